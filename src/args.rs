@@ -1,4 +1,7 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::report::OutputFormat;
+use crate::secret::SecretStore;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -7,13 +10,204 @@ pub struct Args {
     #[arg(
         short = 'c',
         long = "config",
-        default_value = "mastodon-bluesky-sync.toml"
+        default_value = "mastodon-bluesky-sync.toml",
+        global = true
     )]
     pub config: String,
+    /// Directory for caches, sessions and secret files, defaults to the XDG
+    /// state directory
+    #[arg(
+        long = "state-dir",
+        alias = "cache-dir",
+        env = "MBS_STATE_DIR",
+        global = true
+    )]
+    pub state_dir: Option<String>,
+    /// Where new credentials are stored when logging in
+    #[arg(
+        long = "secret-store",
+        value_enum,
+        env = "MBS_SECRET_STORE",
+        default_value_t = SecretStore::Keyring,
+        global = true
+    )]
+    pub secret_store: SecretStore,
     /// Dry run
     #[arg(short = 'n', long = "dry-run")]
     pub dry_run: bool,
     /// Skip all existing posts, use this if you only want to sync future posts
     #[arg(long = "skip-existing-posts")]
     pub skip_existing_posts: bool,
+    /// Output format of the run report
+    #[arg(
+        long = "output",
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        global = true
+    )]
+    pub output: OutputFormat,
+    /// Seconds to wait for another run that uses the same state directory to
+    /// finish, by default this run is skipped
+    #[arg(long = "wait-for-lock", default_value_t = 0, global = true)]
+    pub wait_for_lock: u64,
+    #[command(flatten)]
+    pub http: HttpOptions,
+    /// Number of attachments that are downloaded and uploaded at the same time
+    #[arg(
+        long = "parallel-requests",
+        env = "MBS_PARALLEL_REQUESTS",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(1..)
+    )]
+    pub parallel_requests: u8,
+    /// Keep running and synchronize periodically instead of exiting after one run
+    #[arg(long = "daemon")]
+    pub daemon: bool,
+    /// Seconds to wait between synchronization runs in daemon mode
+    #[arg(long = "interval", default_value_t = 600)]
+    pub interval: u64,
+    /// Address to serve Prometheus metrics and a health check on in daemon
+    /// mode, for example 0.0.0.0:9090
+    #[arg(long = "metrics-listen", requires = "daemon")]
+    pub metrics_listen: Option<String>,
+    /// Seconds since the last successful run after which /healthz fails,
+    /// defaults to three times the interval
+    #[arg(long = "health-max-age", requires = "metrics_listen")]
+    pub health_max_age: Option<u64>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Args {
+    /// The state directory, [`crate::run`] resolves the default before any
+    /// command runs.
+    pub fn state_dir(&self) -> &str {
+        self.state_dir.as_deref().unwrap_or(".")
+    }
+}
+
+/// Settings of the HTTP client for downloads and API requests. Proxies are
+/// taken from the `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and `NO_PROXY`
+/// environment variables.
+#[derive(Debug, clap::Args)]
+pub struct HttpOptions {
+    /// Seconds to wait for a connection to a server
+    #[arg(long = "connect-timeout", default_value_t = 10, global = true)]
+    pub connect_timeout: u64,
+    /// Seconds to wait for a server to send more data before giving up
+    #[arg(long = "http-timeout", default_value_t = 60, global = true)]
+    pub timeout: u64,
+    /// Largest attachment or web page in MiB that is downloaded
+    #[arg(
+        long = "max-download-size",
+        env = "MBS_MAX_DOWNLOAD_SIZE",
+        default_value_t = 100,
+        global = true
+    )]
+    pub max_download_size: u64,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create the config file and log in to both accounts. Without options
+    /// the credentials are asked for interactively
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long = "force")]
+        force: bool,
+        #[command(flatten)]
+        mastodon: MastodonLogin,
+        #[command(flatten)]
+        bluesky: BlueskyLogin,
+    },
+    /// Validate the config, verify the credentials of both accounts and show
+    /// the enabled features without syncing
+    Check,
+    /// Inspect or modify the queue of posts that failed to sync
+    Queue {
+        #[command(subcommand)]
+        action: QueueCommand,
+    },
+    /// Authorize the app again, for example after the access token was revoked
+    Login {
+        #[command(subcommand)]
+        network: LoginCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LoginCommand {
+    /// Log in to Mastodon and store the new access token in the config file
+    Mastodon(MastodonLogin),
+    /// Log in to Bluesky with OAuth in the browser, or with an app password
+    Bluesky(BlueskyLogin),
+}
+
+/// Mastodon credentials for logging in without interaction.
+#[derive(Debug, Default, clap::Args)]
+pub struct MastodonLogin {
+    /// URL of the Mastodon instance, for example https://mastodon.social
+    #[arg(long = "mastodon-url", env = "MBS_MASTODON_URL")]
+    pub url: Option<String>,
+    /// Access token of an application created in the Mastodon settings under
+    /// Development, skips the authorization in the browser
+    #[arg(
+        long = "mastodon-access-token",
+        env = "MBS_MASTODON_ACCESS_TOKEN",
+        hide_env_values = true
+    )]
+    pub access_token: Option<String>,
+    /// Client key of that application, needed to refresh the access token
+    #[arg(
+        long = "mastodon-client-id",
+        env = "MBS_MASTODON_CLIENT_ID",
+        requires = "access_token"
+    )]
+    pub client_id: Option<String>,
+    /// Client secret of that application
+    #[arg(
+        long = "mastodon-client-secret",
+        env = "MBS_MASTODON_CLIENT_SECRET",
+        hide_env_values = true,
+        requires = "access_token"
+    )]
+    pub client_secret: Option<String>,
+}
+
+/// Bluesky credentials for logging in without interaction.
+#[derive(Debug, Default, clap::Args)]
+pub struct BlueskyLogin {
+    /// Bluesky server to log in to, defaults to https://bsky.social
+    #[arg(long = "bluesky-service-url", env = "MBS_BLUESKY_SERVICE_URL")]
+    pub service_url: Option<String>,
+    /// Email address or handle for logging in with an app password
+    #[arg(
+        long = "bluesky-email",
+        env = "MBS_BLUESKY_EMAIL",
+        requires = "app_password"
+    )]
+    pub email: Option<String>,
+    /// App password, skips the OAuth login in the browser
+    #[arg(
+        long = "bluesky-app-password",
+        env = "MBS_BLUESKY_APP_PASSWORD",
+        hide_env_values = true,
+        requires = "email"
+    )]
+    pub app_password: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QueueCommand {
+    /// List queued posts with their attempt counts and last errors
+    List,
+    /// Remove posts from the queue so they are not retried anymore
+    Drop {
+        /// IDs of the queued posts as shown by `queue list`
+        #[arg(required_unless_present = "all")]
+        ids: Vec<String>,
+        /// Remove all queued posts
+        #[arg(long = "all", conflicts_with = "ids")]
+        all: bool,
+    },
 }
@@ -0,0 +1,222 @@
+use anyhow::Context;
+use anyhow::Result;
+use bsky_sdk::api::app::bsky::feed::defs::FeedViewPost;
+use bsky_sdk::api::types::LimitedNonZeroU8;
+use bsky_sdk::api::types::TryFromUnknown;
+use bsky_sdk::api::types::string::{AtIdentifier, Did, Nsid, RecordKey};
+
+use crate::BskyAgent;
+use crate::config::MediaConfig;
+use crate::http::WebClient;
+use crate::network::{DatePage, LikeDeletion, SocialNetwork};
+use crate::post::{BlueskyMedia, bluesky_create_post, bluesky_upload_attachment};
+use crate::sync::{NewMedia, NewStatus};
+
+/// A logged in Bluesky account.
+pub struct BlueskyNetwork {
+    agent: BskyAgent,
+    http: WebClient,
+    did: Did,
+    video_service: String,
+    media: MediaConfig,
+}
+
+impl BlueskyNetwork {
+    pub fn new(
+        agent: BskyAgent,
+        http: WebClient,
+        did: Did,
+        video_service: String,
+        media: MediaConfig,
+    ) -> Self {
+        BlueskyNetwork {
+            agent,
+            http,
+            did,
+            video_service,
+            media,
+        }
+    }
+
+    fn actor(&self) -> AtIdentifier {
+        self.did.clone().into()
+    }
+
+    async fn author_feed(
+        &self,
+        cursor: Option<String>,
+        limit: u8,
+    ) -> Result<bsky_sdk::api::app::bsky::feed::get_author_feed::Output> {
+        Ok(self
+            .agent
+            .api
+            .app
+            .bsky
+            .feed
+            .get_author_feed(
+                bsky_sdk::api::app::bsky::feed::get_author_feed::ParametersData {
+                    actor: self.actor(),
+                    cursor,
+                    filter: None,
+                    include_pins: None,
+                    limit: Some(LimitedNonZeroU8::try_from(limit).unwrap()),
+                }
+                .into(),
+            )
+            .await?)
+    }
+}
+
+impl SocialNetwork for BlueskyNetwork {
+    type Post = FeedViewPost;
+    type Media = BlueskyMedia;
+
+    fn name(&self) -> &'static str {
+        "Bluesky"
+    }
+
+    async fn fetch_timeline(&self) -> Result<Vec<FeedViewPost>> {
+        Ok(self.author_feed(None, 50).await?.feed.clone())
+    }
+
+    async fn upload_media(
+        &self,
+        attachment: &NewMedia,
+        status: &NewStatus,
+    ) -> Result<Option<BlueskyMedia>> {
+        bluesky_upload_attachment(
+            &self.agent,
+            &self.http,
+            &self.video_service,
+            &self.media,
+            attachment,
+            status,
+        )
+        .await
+    }
+
+    async fn create_post(
+        &self,
+        status: &NewStatus,
+        media: Vec<BlueskyMedia>,
+        _reply_to: Option<&str>,
+    ) -> Result<String> {
+        bluesky_create_post(&self.agent, &self.http, status, media).await
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<()> {
+        // No error handling needed here for non existing posts, the Bluesky API
+        // returns success even if the post does not exist.
+        self.agent.delete_record(id).await?;
+        Ok(())
+    }
+
+    async fn list_posts(&self, cursor: Option<String>) -> Result<DatePage> {
+        // Try to fetch as many posts as possible at once, Bluesky API docs say
+        // that is 100.
+        let feed = self.author_feed(cursor, 100).await?;
+        let mut page = DatePage {
+            cursor: feed.cursor.clone(),
+            ..Default::default()
+        };
+        for post in &feed.feed {
+            let record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
+                post.post.record.clone(),
+            )
+            .context("Failed to parse Bluesky post record")?;
+
+            // Reposts are deleted by their repost record.
+            let uri = match post.post.viewer.as_ref().and_then(|v| v.repost.as_ref()) {
+                Some(repost) => repost.to_string(),
+                None => post.post.uri.clone(),
+            };
+            page.dates.insert(uri, (*record.created_at.as_ref()).into());
+        }
+        Ok(page)
+    }
+
+    async fn list_likes(&self, cursor: Option<String>) -> Result<DatePage> {
+        // Use list_records on our repo for the like collection.
+        let response = self
+            .agent
+            .api
+            .com
+            .atproto
+            .repo
+            .list_records(
+                bsky_sdk::api::com::atproto::repo::list_records::ParametersData {
+                    repo: self.actor(),
+                    collection: Nsid::new("app.bsky.feed.like".to_string()).unwrap(),
+                    cursor,
+                    limit: Some(LimitedNonZeroU8::try_from(100).unwrap()),
+                    reverse: None,
+                }
+                .into(),
+            )
+            .await?;
+        let mut page = DatePage {
+            cursor: response.cursor.clone(),
+            ..Default::default()
+        };
+        for rec in &response.records {
+            // Parse like record value to extract its createdAt (time we liked the post).
+            let like_record = bsky_sdk::api::app::bsky::feed::like::RecordData::try_from_unknown(
+                rec.value.clone(),
+            )
+            .context("Failed to parse like record")?;
+            page.dates
+                .insert(rec.uri.clone(), (*like_record.created_at.as_ref()).into());
+        }
+        Ok(page)
+    }
+
+    async fn delete_like(&self, id: &str) -> Result<LikeDeletion> {
+        // Expected like URI format: at://<did>/app.bsky.feed.like/<rkey>
+        let parts = id
+            .strip_prefix("at://")
+            .with_context(|| format!("Invalid At URI prefix {id} when deleting like"))?
+            .splitn(3, '/')
+            .collect::<Vec<_>>();
+        if parts.len() != 3 {
+            eprintln!("Skipping malformed like URI: {id}");
+            return Ok(LikeDeletion::Gone);
+        }
+        if parts[1] != "app.bsky.feed.like" {
+            // Legacy cache entry from old implementation referencing a post URI -> just drop it.
+            eprintln!("Skipping non-like cached entry: {id}");
+            return Ok(LikeDeletion::Gone);
+        }
+        let rkey = match parts[2].parse::<RecordKey>() {
+            Ok(rkey) => rkey,
+            Err(e) => {
+                eprintln!("Invalid like rkey in {id}: {e}");
+                return Ok(LikeDeletion::Gone);
+            }
+        };
+        match self
+            .agent
+            .api
+            .com
+            .atproto
+            .repo
+            .delete_record(
+                bsky_sdk::api::com::atproto::repo::delete_record::InputData {
+                    collection: Nsid::new("app.bsky.feed.like".to_string()).unwrap(),
+                    repo: self.actor(),
+                    rkey,
+                    swap_commit: None,
+                    swap_record: None,
+                }
+                .into(),
+            )
+            .await
+        {
+            Ok(_) => Ok(LikeDeletion::Deleted),
+            Err(e) => {
+                // Forget about the like anyway to avoid trying again forever.
+                eprintln!("Error deleting like {id}: {e:#?}");
+                Ok(LikeDeletion::Gone)
+            }
+        }
+    }
+}
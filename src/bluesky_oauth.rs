@@ -0,0 +1,711 @@
+//! atproto OAuth login with DPoP bound tokens, so that no app password needs
+//! to be stored. See https://atproto.com/specs/oauth for the protocol.
+//!
+//! The program is a public "loopback" client: the authorization redirects to
+//! a local HTTP listener, the tokens are bound to a private key that is stored
+//! together with them in the session file.
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bsky_sdk::api::xrpc::http::HeaderMap;
+use chrono::prelude::*;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::http::WebClient;
+use crate::report::progress;
+use crate::state_dir::write_atomic_private;
+
+/// Permissions requested for the session. `transition:generic` grants the
+/// same access as an app password.
+const SCOPE: &str = "atproto transition:generic";
+
+/// Access tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: i64 = 60;
+
+/// The login in the browser is given up after this long.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Private key that DPoP proofs are signed with. Tokens can only be used
+/// together with proofs of the key they were issued for.
+#[derive(Clone)]
+pub struct DpopKey(SigningKey);
+
+impl DpopKey {
+    pub fn generate() -> Result<Self> {
+        // A random 32 byte string is a valid P-256 key with overwhelming
+        // probability.
+        let key = SigningKey::from_slice(&rand::random::<[u8; 32]>())?;
+        Ok(DpopKey(key))
+    }
+
+    fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .context("Invalid DPoP key encoding")?;
+        Ok(DpopKey(SigningKey::from_slice(&bytes)?))
+    }
+
+    fn to_base64(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.0.to_bytes())
+    }
+
+    /// Public key in JWK format, included in every proof.
+    fn public_jwk(&self) -> Result<Value> {
+        let public_key = p256::PublicKey::from(self.0.verifying_key());
+        Ok(serde_json::to_value(public_key.to_jwk())?)
+    }
+
+    /// Creates a DPoP proof JWT for one request. The access token is hashed
+    /// into the proof for requests to the PDS.
+    pub fn proof(
+        &self,
+        method: &str,
+        url: &str,
+        nonce: Option<&str>,
+        access_token: Option<&str>,
+    ) -> Result<String> {
+        let header = json!({
+            "typ": "dpop+jwt",
+            "alg": "ES256",
+            "jwk": self.public_jwk()?,
+        });
+        let mut claims = json!({
+            "jti": random_string(16),
+            "htm": method,
+            "htu": url,
+            "iat": Utc::now().timestamp(),
+        });
+        if let Some(nonce) = nonce {
+            claims["nonce"] = json!(nonce);
+        }
+        if let Some(access_token) = access_token {
+            claims["ath"] = json!(URL_SAFE_NO_PAD.encode(Sha256::digest(access_token)));
+        }
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature: Signature = self.0.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+/// Tokens and endpoints of a logged in account, stored in the session file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthSession {
+    pub did: String,
+    pub handle: String,
+    /// PDS of the account, all API requests go there.
+    pub pds_url: String,
+    pub issuer: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    dpop_key: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthSession {
+    pub async fn load(session_file: &str) -> Result<Self> {
+        let json = fs::read_to_string(session_file)
+            .await
+            .with_context(|| format!("Failed to read Bluesky OAuth session {session_file}"))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Invalid Bluesky OAuth session {session_file}"))
+    }
+
+    pub async fn save(&self, session_file: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        write_atomic_private(session_file, json.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write Bluesky OAuth session {session_file}"))?;
+        Ok(())
+    }
+
+    fn dpop_key(&self) -> Result<DpopKey> {
+        DpopKey::from_base64(&self.dpop_key)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProtectedResource {
+    authorization_servers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorizationServer {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    pushed_authorization_request_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    scope: Option<String>,
+    sub: String,
+}
+
+/// Shares the DPoP nonce of the authorization server between requests.
+struct TokenClient<'a> {
+    http: &'a WebClient,
+    key: &'a DpopKey,
+    nonce: Mutex<Option<String>>,
+}
+
+impl<'a> TokenClient<'a> {
+    fn new(http: &'a WebClient, key: &'a DpopKey) -> Self {
+        TokenClient {
+            http,
+            key,
+            nonce: Mutex::new(None),
+        }
+    }
+
+    /// Posts a form to an endpoint of the authorization server. The server
+    /// asks for a nonce on the first request, which is then retried.
+    async fn post(&self, url: &str, params: &[(&str, &str)]) -> Result<Value> {
+        let body = serde_urlencoded::to_string(params)?;
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce.lock().unwrap().clone();
+            let proof = self.key.proof("POST", url, nonce.as_deref(), None)?;
+            let response = self
+                .http
+                .client()
+                .post(url)
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    "application/x-www-form-urlencoded",
+                )
+                .header("DPoP", proof)
+                .body(body.clone())
+                .send()
+                .await?;
+            if let Some(nonce) = response
+                .headers()
+                .get("dpop-nonce")
+                .and_then(|value| value.to_str().ok())
+            {
+                *self.nonce.lock().unwrap() = Some(nonce.to_string());
+            }
+            let status = response.status();
+            let json: Value =
+                serde_json::from_str(&self.http.text(response).await?).unwrap_or_default();
+            if status.is_success() {
+                return Ok(json);
+            }
+            if json["error"] == "use_dpop_nonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "{url} failed with {status}: {} {}",
+                json["error"].as_str().unwrap_or_default(),
+                json["error_description"].as_str().unwrap_or_default()
+            );
+        }
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(http: &WebClient, url: &str) -> Result<T> {
+    let response = http.get(url).await?.error_for_status()?;
+    serde_json::from_str(&http.text(response).await?)
+        .with_context(|| format!("Invalid JSON from {url}"))
+}
+
+/// Returns the authorization server metadata for a PDS or entryway.
+async fn authorization_server(http: &WebClient, service_url: &str) -> Result<AuthorizationServer> {
+    let resource: ProtectedResource = get_json(
+        http,
+        &format!(
+            "{}/.well-known/oauth-protected-resource",
+            service_url.trim_end_matches('/')
+        ),
+    )
+    .await?;
+    let issuer = resource
+        .authorization_servers
+        .first()
+        .context("No OAuth authorization server found")?;
+    let server: AuthorizationServer = get_json(
+        http,
+        &format!(
+            "{}/.well-known/oauth-authorization-server",
+            issuer.trim_end_matches('/')
+        ),
+    )
+    .await?;
+    if &server.issuer != issuer {
+        bail!(
+            "OAuth issuer mismatch, expected {issuer} but got {}",
+            server.issuer
+        );
+    }
+    Ok(server)
+}
+
+/// Checks if the server supports OAuth, otherwise an app password is needed.
+pub async fn oauth_supported(http: &WebClient, service_url: &str) -> bool {
+    authorization_server(http, service_url).await.is_ok()
+}
+
+/// Logs in through the browser and returns the new session.
+pub async fn oauth_login(http: &WebClient, service_url: &str) -> Result<OAuthSession> {
+    let server = authorization_server(http, service_url)
+        .await
+        .with_context(|| format!("{service_url} does not support OAuth login"))?;
+    let key = DpopKey::generate()?;
+    let client = TokenClient::new(http, &key);
+
+    // The authorization server redirects the browser to this listener.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = format!(
+        "http://127.0.0.1:{}/callback",
+        listener.local_addr()?.port()
+    );
+    let client_id = format!(
+        "http://localhost?{}",
+        serde_urlencoded::to_string([("redirect_uri", redirect_uri.as_str()), ("scope", SCOPE)])?
+    );
+    let code_verifier = random_string(32);
+    let state = random_string(16);
+
+    let request = client
+        .post(
+            &server.pushed_authorization_request_endpoint,
+            &[
+                ("client_id", &client_id),
+                ("response_type", "code"),
+                ("code_challenge", &pkce_challenge(&code_verifier)),
+                ("code_challenge_method", "S256"),
+                ("state", &state),
+                ("redirect_uri", &redirect_uri),
+                ("scope", SCOPE),
+            ],
+        )
+        .await
+        .context("Failed to start the OAuth authorization")?;
+    let request_uri = request["request_uri"]
+        .as_str()
+        .context("No request_uri in the authorization response")?;
+    println!("Open this link to log in to Bluesky:");
+    println!(
+        "{}?{}",
+        server.authorization_endpoint,
+        serde_urlencoded::to_string([
+            ("client_id", client_id.as_str()),
+            ("request_uri", request_uri)
+        ])?
+    );
+    if std::io::stdin().is_terminal() {
+        println!(
+            "If the browser cannot reach this machine, paste the address of the page it was redirected to here:"
+        );
+    }
+
+    let params = tokio::time::timeout(LOGIN_TIMEOUT, wait_for_callback(listener))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The login was not completed within {} minutes, please try again",
+                LOGIN_TIMEOUT.as_secs() / 60
+            )
+        })??;
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    if let Some(error) = param("error") {
+        bail!(
+            "Authorization failed: {error} {}",
+            param("error_description").unwrap_or_default()
+        );
+    }
+    if param("state") != Some(state.as_str()) {
+        bail!("Authorization state mismatch, please try again");
+    }
+    if param("iss").is_some_and(|iss| iss != server.issuer) {
+        bail!("Authorization response from an unexpected issuer");
+    }
+    let code = param("code").context("No authorization code in the redirect")?;
+
+    let json = client
+        .post(
+            &server.token_endpoint,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &redirect_uri),
+                ("code_verifier", &code_verifier),
+                ("client_id", &client_id),
+            ],
+        )
+        .await
+        .context("Failed to get the OAuth access token")?;
+    let token: TokenResponse = serde_json::from_value(json)?;
+    check_token(&token)?;
+
+    let (handle, pds_url) = resolve_account(http, service_url, &token.sub).await?;
+    // The PDS must trust the server that issued the tokens, otherwise anyone
+    // could claim the DID.
+    let pds_server = authorization_server(http, &pds_url).await?;
+    if pds_server.issuer != server.issuer {
+        bail!(
+            "{} is not the authorization server of {pds_url}",
+            server.issuer
+        );
+    }
+
+    let session = OAuthSession {
+        did: token.sub,
+        handle,
+        pds_url,
+        issuer: server.issuer,
+        token_endpoint: server.token_endpoint,
+        client_id,
+        dpop_key: key.to_base64(),
+        access_token: token.access_token,
+        refresh_token: token
+            .refresh_token
+            .context("No refresh token in the OAuth response")?,
+        expires_at: expires_at(token.expires_in),
+    };
+    progress!("Logged in to Bluesky as {}", session.handle);
+    Ok(session)
+}
+
+/// Waits for the redirect of the browser, or for the user to paste the
+/// redirect URL. Returns the query parameters.
+async fn wait_for_callback(listener: TcpListener) -> Result<Vec<(String, String)>> {
+    let interactive = std::io::stdin().is_terminal();
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let query = tokio::select! {
+        query = accept_redirect(&listener) => query?,
+        line = stdin.next_line(), if interactive => {
+            let target = line?.context("No redirect URL entered")?;
+            redirect_query(&target)
+                .ok_or_else(|| anyhow!("No authorization response in the redirect {target}"))?
+                .to_string()
+        }
+    };
+    Ok(serde_urlencoded::from_str(query.trim())?)
+}
+
+/// Answers requests to the local server until the browser is redirected to
+/// the callback with the authorization response. Other requests, like the
+/// favicon or preconnects of the browser, get a 404. Returns the query.
+async fn accept_redirect(listener: &TcpListener) -> Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        // The request line is all we need.
+        let mut buffer = [0; 4096];
+        let read =
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await {
+                Ok(Ok(read)) => read,
+                // Preconnected sockets might never send anything.
+                _ => continue,
+            };
+        let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+        let target = request.split_whitespace().nth(1).unwrap_or_default();
+        let query = redirect_query(target).map(String::from);
+        let (status, body) = match query {
+            Some(_) => ("200 OK", "Logged in, you can close this window now."),
+            None => ("404 Not Found", "Not found"),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        // The answer is only for the user, the query is what counts.
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+        if let Some(query) = query {
+            return Ok(query);
+        }
+    }
+}
+
+/// The query of a redirect to the callback path that carries an
+/// authorization response, a code and state or an error.
+fn redirect_query(target: &str) -> Option<&str> {
+    let (path, query) = target.split_once('?')?;
+    if !path.ends_with("/callback") {
+        return None;
+    }
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(query.trim()).ok()?;
+    let has = |name: &str| params.iter().any(|(key, _)| key == name);
+    ((has("code") && has("state")) || has("error")).then_some(query)
+}
+
+/// Looks up the handle and the PDS of an account in its DID document.
+async fn resolve_account(
+    http: &WebClient,
+    service_url: &str,
+    did: &str,
+) -> Result<(String, String)> {
+    let repo: Value = get_json(
+        http,
+        &format!(
+            "{}/xrpc/com.atproto.repo.describeRepo?{}",
+            service_url.trim_end_matches('/'),
+            serde_urlencoded::to_string([("repo", did)])?
+        ),
+    )
+    .await
+    .with_context(|| format!("Failed to resolve {did}"))?;
+    let pds_url = repo["didDoc"]["service"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|service| {
+            service["id"]
+                .as_str()
+                .is_some_and(|id| id.ends_with("#atproto_pds"))
+        })
+        .and_then(|service| service["serviceEndpoint"].as_str())
+        .with_context(|| format!("No PDS found in the DID document of {did}"))?;
+    let handle = repo["handle"]
+        .as_str()
+        .with_context(|| format!("No handle found for {did}"))?;
+    Ok((handle.to_string(), pds_url.to_string()))
+}
+
+fn check_token(token: &TokenResponse) -> Result<()> {
+    if !token.token_type.eq_ignore_ascii_case("DPoP") {
+        bail!("Unexpected OAuth token type {}", token.token_type);
+    }
+    if !token
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split(' ')
+        .any(|scope| scope == "atproto")
+    {
+        bail!("The OAuth token was not granted the atproto scope");
+    }
+    Ok(())
+}
+
+fn expires_at(expires_in: Option<i64>) -> DateTime<Utc> {
+    // Without an expiry the token is refreshed when the PDS rejects it.
+    Utc::now() + chrono::Duration::seconds(expires_in.unwrap_or(i64::from(u32::MAX)))
+}
+
+/// PKCE code challenge for the verifier with the S256 method.
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier))
+}
+
+fn random_string(bytes: usize) -> String {
+    let random: Vec<u8> = (0..bytes).map(|_| rand::random::<u8>()).collect();
+    URL_SAFE_NO_PAD.encode(random)
+}
+
+/// The OAuth session used by the Bluesky client. Refreshed tokens are written
+/// back to the session file, because the refresh token can only be used once.
+pub struct OAuthAuthorizer {
+    session: tokio::sync::Mutex<OAuthSession>,
+    key: DpopKey,
+    session_file: String,
+    http: WebClient,
+    // Nonce that the PDS sent last.
+    nonce: Mutex<Option<String>>,
+}
+
+impl OAuthAuthorizer {
+    pub async fn load(session_file: &str, http: &WebClient) -> Result<Self> {
+        let session = OAuthSession::load(session_file).await?;
+        Ok(OAuthAuthorizer {
+            key: session.dpop_key()?,
+            session: tokio::sync::Mutex::new(session),
+            session_file: session_file.to_string(),
+            http: http.clone(),
+            nonce: Mutex::new(None),
+        })
+    }
+
+    pub async fn session(&self) -> OAuthSession {
+        self.session.lock().await.clone()
+    }
+
+    /// Returns a valid access token, refreshing it if it is about to expire.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut session = self.session.lock().await;
+        if session.expires_at - chrono::Duration::seconds(REFRESH_MARGIN) < Utc::now() {
+            self.refresh_session(&mut session).await?;
+        }
+        Ok(session.access_token.clone())
+    }
+
+    /// Refreshes the access token after it was rejected. Does nothing if
+    /// another request refreshed it already in the meantime.
+    pub async fn refresh(&self, rejected_token: &str) -> Result<String> {
+        let mut session = self.session.lock().await;
+        if session.access_token == rejected_token {
+            self.refresh_session(&mut session).await?;
+        }
+        Ok(session.access_token.clone())
+    }
+
+    async fn refresh_session(&self, session: &mut OAuthSession) -> Result<()> {
+        progress!("Refreshing the Bluesky OAuth access token");
+        let json = TokenClient::new(&self.http, &self.key)
+            .post(
+                &session.token_endpoint,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &session.refresh_token),
+                    ("client_id", &session.client_id),
+                ],
+            )
+            .await
+            .context(
+                "Refreshing the Bluesky OAuth session failed, run `mastodon-bluesky-sync login bluesky` to log in again",
+            )?;
+        let token: TokenResponse = serde_json::from_value(json)?;
+        check_token(&token)?;
+        if token.sub != session.did {
+            bail!("The refreshed OAuth token belongs to another account");
+        }
+        session.access_token = token.access_token;
+        if let Some(refresh_token) = token.refresh_token {
+            session.refresh_token = refresh_token;
+        }
+        session.expires_at = expires_at(token.expires_in);
+        session.save(&self.session_file).await
+    }
+
+    /// DPoP proof for a request to the PDS.
+    pub fn proof(&self, method: &str, url: &str, access_token: &str) -> Result<String> {
+        let nonce = self.nonce.lock().unwrap().clone();
+        self.key
+            .proof(method, url, nonce.as_deref(), Some(access_token))
+    }
+
+    /// Remembers the nonce of a PDS response. Returns true if it changed, then
+    /// a request rejected with `use_dpop_nonce` can be retried.
+    pub fn update_nonce(&self, headers: &HeaderMap) -> bool {
+        let Some(nonce) = headers
+            .get("dpop-nonce")
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let mut current = self.nonce.lock().unwrap();
+        let changed = current.as_deref() != Some(nonce);
+        *current = Some(nonce.to_string());
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::VerifyingKey;
+    use p256::ecdsa::signature::Verifier;
+
+    #[tokio::test]
+    async fn only_the_redirect_to_the_callback_is_accepted() {
+        use tokio::net::TcpStream;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let redirect = tokio::spawn(async move { accept_redirect(&listener).await.unwrap() });
+
+        for target in ["/favicon.ico", "/callback", "/callback?state=abc"] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(format!("GET {target} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        }
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /callback?code=xyz&state=abc&iss=x HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(redirect.await.unwrap(), "code=xyz&state=abc&iss=x");
+
+        assert_eq!(
+            redirect_query("http://127.0.0.1:1234/callback?error=access_denied"),
+            Some("error=access_denied")
+        );
+    }
+
+    #[test]
+    fn pkce_challenge_matches_rfc_example() {
+        // Example from RFC 7636 appendix B.
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn dpop_proof_is_signed_with_the_public_key_in_the_header() {
+        let key = DpopKey::generate().unwrap();
+        let key = DpopKey::from_base64(&key.to_base64()).unwrap();
+        let proof = key
+            .proof(
+                "GET",
+                "https://pds.example/xrpc/app.bsky.feed.getAuthorFeed",
+                Some("nonce-1"),
+                Some("token"),
+            )
+            .unwrap();
+
+        let parts: Vec<&str> = proof.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let decode = |part: &str| -> Value {
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+        };
+        let header = decode(parts[0]);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        let claims = decode(parts[1]);
+        assert_eq!(claims["htm"], "GET");
+        assert_eq!(
+            claims["htu"],
+            "https://pds.example/xrpc/app.bsky.feed.getAuthorFeed"
+        );
+        assert_eq!(claims["nonce"], "nonce-1");
+        assert_eq!(
+            claims["ath"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest("token"))
+        );
+
+        let jwk: p256::elliptic_curve::JwkEcKey =
+            serde_json::from_value(header["jwk"].clone()).unwrap();
+        let verifying_key = VerifyingKey::from(jwk.to_public_key::<p256::NistP256>().unwrap());
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(parts[2]).unwrap()).unwrap();
+        verifying_key
+            .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+            .unwrap();
+    }
+}
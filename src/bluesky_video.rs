@@ -1,152 +1,234 @@
 use crate::BskyAgent;
-use anyhow::{Result, bail};
-use atrium_xrpc_client::reqwest::ReqwestClient;
+use crate::http::WebClient;
+use crate::report::progress;
+use crate::retry::RetryClient;
+use anyhow::{Context, Result, anyhow, bail};
 use bsky_sdk::api::{
+    app::bsky::video::defs::JobStatusData,
     client::AtpServiceClient,
     types::{BlobRef, string::Did},
     xrpc::{
-        HttpClient, XrpcClient,
-        http::{Request, Response, uri::Builder},
+        HttpClient, InputDataOrBytes, OutputDataOrBytes, XrpcClient, XrpcRequest,
+        http::{
+            Method, Request, Response,
+            header::{AUTHORIZATION, CONTENT_TYPE},
+        },
         types::AuthorizationToken,
     },
 };
 use serde::Serialize;
-use std::result::Result::Ok;
 use std::time::Duration;
-use tokio::time;
+use tokio::time::{self, Instant};
 use url::Url;
 
-const VIDEO_SERVICE: &str = "https://video.bsky.app";
 const UPLOAD_VIDEO_PATH: &str = "/xrpc/app.bsky.video.uploadVideo";
 
+/// How long Bluesky may take to process an uploaded video.
+const JOB_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Longest wait between two job status requests.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Serialize)]
 struct UploadParams {
     did: Did,
     name: String,
 }
 
-struct VideoClient {
+/// Result of uploading a video to the Bluesky video service.
+#[derive(Debug)]
+pub enum VideoUpload {
+    Uploaded(BlobRef),
+    /// The daily upload limit of the account is reached, with the reason
+    /// from the video service.
+    LimitReached(String),
+}
+
+/// XRPC client for the video service or our PDS, authenticated with a
+/// service token.
+struct ServiceClient {
+    service: String,
     token: String,
-    params: Option<UploadParams>,
-    inner: ReqwestClient,
+    inner: RetryClient,
 }
 
-impl VideoClient {
-    fn new(token: String, params: Option<UploadParams>) -> Self {
+impl ServiceClient {
+    fn new(service: &str, http: &WebClient, token: String) -> Self {
         Self {
+            service: service.to_string(),
             token,
-            params,
-            inner: ReqwestClient::new(
-                // Actually, `base_uri` returns `VIDEO_SERVICE`, so there is no need to specify this.
-                "https://dummy.example.com",
-            ),
+            inner: RetryClient::new(service, http),
         }
     }
 }
 
-impl HttpClient for VideoClient {
+impl HttpClient for ServiceClient {
     async fn send_http(
         &self,
-        mut request: Request<Vec<u8>>,
+        request: Request<Vec<u8>>,
     ) -> Result<Response<Vec<u8>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let is_upload_video = request.uri().path() == UPLOAD_VIDEO_PATH;
-        // Hack: Append query parameters
-        if is_upload_video && let Some(params) = &self.params {
-            *request.uri_mut() = Builder::from(request.uri().clone())
-                .path_and_query(format!(
-                    "{UPLOAD_VIDEO_PATH}?{}",
-                    serde_urlencoded::to_string(params)?
-                ))
-                .build()?;
-        }
-        let mut response = self.inner.send_http(request).await;
-        // Hack: Formatting an incorrect response body
-        if is_upload_video && let Ok(res) = response.as_mut() {
-            *res.body_mut() = [
-                b"{\"jobStatus\":".to_vec(),
-                res.body().to_vec(),
-                b"}".to_vec(),
-            ]
-            .concat();
-        }
-        response
+        self.inner.send_http(request).await
     }
 }
 
-impl XrpcClient for VideoClient {
+impl XrpcClient for ServiceClient {
     fn base_uri(&self) -> String {
-        VIDEO_SERVICE.to_string()
+        self.service.clone()
     }
     async fn authorization_token(&self, _: bool) -> Option<AuthorizationToken> {
         Some(AuthorizationToken::Bearer(self.token.clone()))
     }
 }
 
-// Upload a video to Bluesky and wait for it to be processed.
-// Code copied from
-// https://github.com/sugyan/atrium/blob/main/examples/video/src/main.rs
+/// Requests a token from our PDS for a call to another service.
+async fn service_auth(bsky_agent: &BskyAgent, service_url: &str, method: &str) -> Result<String> {
+    let host = Url::parse(service_url)?
+        .host_str()
+        .context(format!("No host in {service_url}"))?
+        .to_string();
+    let output = bsky_agent
+        .api
+        .com
+        .atproto
+        .server
+        .get_service_auth(
+            bsky_sdk::api::com::atproto::server::get_service_auth::ParametersData {
+                aud: format!("did:web:{host}")
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid service DID for {host}: {e}"))?,
+                exp: None,
+                lxm: method.parse().ok(),
+            }
+            .into(),
+        )
+        .await
+        .context(format!("Failed getting a service token for {method}"))?;
+    Ok(output.data.token)
+}
+
+/// Uploads a WebVTT caption file to our PDS. The agent uploads every blob as
+/// `*/*`, which Bluesky does not accept for captions, so the upload is sent
+/// with a service token and the WebVTT content type instead.
+pub async fn bluesky_upload_caption_blob(
+    bsky_agent: &BskyAgent,
+    http: &WebClient,
+    caption_bytes: Vec<u8>,
+) -> Result<BlobRef> {
+    use bsky_sdk::api::com::atproto::repo::upload_blob;
+
+    let endpoint = bsky_agent.get_endpoint().await;
+    let token = service_auth(bsky_agent, &endpoint, upload_blob::NSID).await?;
+    let client = ServiceClient::new(&endpoint, http, token);
+    let response = client
+        .send_xrpc::<(), Vec<u8>, upload_blob::Output, upload_blob::Error>(&XrpcRequest {
+            method: Method::POST,
+            nsid: upload_blob::NSID.into(),
+            parameters: None,
+            input: Some(InputDataOrBytes::Bytes(caption_bytes)),
+            encoding: Some("text/vtt".to_string()),
+        })
+        .await?;
+    match response {
+        OutputDataOrBytes::Data(output) => Ok(output.data.blob),
+        OutputDataOrBytes::Bytes(_) => bail!("Unexpected response to the caption upload"),
+    }
+}
+
+/// Uploads a video to Bluesky and waits until it is processed. The upload
+/// limits of the account are checked first, so that a video over the daily
+/// limit is not uploaded in vain.
 pub async fn bluesky_upload_video(
     bsky_agent: &BskyAgent,
+    http: &WebClient,
+    video_service: &str,
     url: &str,
     video_bytes: Vec<u8>,
-) -> Result<BlobRef> {
-    println!("Uploading video {url} to Bluesky...");
-    let session = bsky_agent.get_session().await.unwrap();
-    let output = {
-        let service_auth = bsky_agent
-            .api
-            .com
-            .atproto
-            .server
-            .get_service_auth(
-                bsky_sdk::api::com::atproto::server::get_service_auth::ParametersData {
-                    aud: format!(
-                        "did:web:{}",
-                        bsky_agent
-                            .get_endpoint()
-                            .await
-                            .strip_prefix("https://")
-                            .unwrap()
-                    )
-                    .parse()
-                    .expect("invalid DID"),
-                    exp: None,
-                    lxm: bsky_sdk::api::com::atproto::repo::upload_blob::NSID
-                        .parse()
-                        .ok(),
-                }
-                .into(),
-            )
-            .await?;
-
-        let video_url = Url::parse(url)?;
-        let filename = video_url
-            .path_segments()
-            .unwrap()
-            .next_back()
-            .filter(|&s| !s.is_empty())
-            .map(|s| s.to_string())
-            .unwrap_or("video.mp4".to_string());
-        let client = AtpServiceClient::new(VideoClient::new(
-            service_auth.data.token,
-            Some(UploadParams {
-                did: session.did.clone(),
-                name: filename,
-            }),
-        ));
-        client
-            .service
-            .app
-            .bsky
-            .video
-            .upload_video(video_bytes)
-            .await?
-    };
+) -> Result<VideoUpload> {
+    let limits_token = service_auth(
+        bsky_agent,
+        video_service,
+        bsky_sdk::api::app::bsky::video::get_upload_limits::NSID,
+    )
+    .await?;
+    let client = AtpServiceClient::new(ServiceClient::new(video_service, http, limits_token));
+    let limits = client
+        .service
+        .app
+        .bsky
+        .video
+        .get_upload_limits()
+        .await
+        .context("Failed getting the video upload limits from Bluesky")?
+        .data;
+    if let Some(reason) = limit_reached(&limits, video_bytes.len()) {
+        return Ok(VideoUpload::LimitReached(reason));
+    }
+
+    progress!("Uploading video {url} to Bluesky...");
+    let session = bsky_agent
+        .get_session()
+        .await
+        .context("Not logged in to Bluesky")?;
+    // The video service stores the video in our repository, so it
+    // authenticates uploads with a token for our PDS.
+    let upload_token = service_auth(
+        bsky_agent,
+        &bsky_agent.get_endpoint().await,
+        bsky_sdk::api::com::atproto::repo::upload_blob::NSID,
+    )
+    .await?;
+    let filename = Url::parse(url)?
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| !segment.is_empty())
+        .unwrap_or("video.mp4")
+        .to_string();
+    let params = serde_urlencoded::to_string(UploadParams {
+        did: session.did.clone(),
+        name: filename,
+    })?;
+    // The video service returns the job status without the wrapper object of
+    // the lexicon, so the upload is sent without the XRPC client.
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "{}{UPLOAD_VIDEO_PATH}?{params}",
+            video_service.trim_end_matches('/')
+        ))
+        .header(AUTHORIZATION, format!("Bearer {upload_token}"))
+        .header(CONTENT_TYPE, "video/mp4")
+        .body(video_bytes)?;
+    let response = RetryClient::new(video_service, http)
+        .send_http(request)
+        .await
+        .map_err(|e| anyhow!("Failed uploading video {url} to Bluesky: {e}"))?;
+    let mut status = parse_upload_response(response.status().as_u16(), response.body())
+        .context(format!("Failed uploading video {url} to Bluesky"))?;
 
-    // Wait for the video to be uploaded
-    let client = AtpServiceClient::new(ReqwestClient::new(VIDEO_SERVICE));
-    let mut status = output.data.job_status.data;
+    let deadline = Instant::now() + JOB_TIMEOUT;
+    let mut interval = Duration::from_secs(1);
+    let mut last_state = String::new();
     loop {
+        if let Some(blob) = job_result(&status).context(format!(
+            "Bluesky failed processing video {url} in job {}",
+            status.job_id
+        ))? {
+            progress!("Video {url} uploaded to Bluesky");
+            return Ok(VideoUpload::Uploaded(blob));
+        }
+        if status.state != last_state {
+            progress!("Video status: {}", status.state);
+            last_state = status.state.clone();
+        }
+        if Instant::now() >= deadline {
+            bail!(
+                "Bluesky did not finish processing video {url} in job {} within {} seconds",
+                status.job_id,
+                JOB_TIMEOUT.as_secs()
+            );
+        }
+        time::sleep(interval).await;
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
         status = client
             .service
             .app
@@ -158,23 +240,149 @@ pub async fn bluesky_upload_video(
                 }
                 .into(),
             )
-            .await?
+            .await
+            .context(format!(
+                "Failed getting the status of video job {}",
+                status.job_id
+            ))?
             .data
             .job_status
             .data;
-        let state = &status.state;
-        println!("Video status: {state}");
-        if status.blob.is_some()
-            || status.state == "JOB_STATE_COMPLETED"
-            || status.state == "JOB_STATE_FAILED"
-        {
-            break;
+    }
+}
+
+/// Returns why the account cannot upload a video of the given size today.
+fn limit_reached(
+    limits: &bsky_sdk::api::app::bsky::video::get_upload_limits::OutputData,
+    size: usize,
+) -> Option<String> {
+    if !limits.can_upload
+        || limits
+            .remaining_daily_videos
+            .is_some_and(|videos| videos < 1)
+    {
+        return Some(
+            limits
+                .message
+                .clone()
+                .or(limits.error.clone())
+                .unwrap_or("the daily video upload limit is reached".to_string()),
+        );
+    }
+    if limits
+        .remaining_daily_bytes
+        .is_some_and(|bytes| bytes < size as i64)
+    {
+        return Some(format!(
+            "the video is larger than the {} bytes left to upload today",
+            limits.remaining_daily_bytes.unwrap_or_default()
+        ));
+    }
+    None
+}
+
+/// Reads the job status of an upload. The video service answers a video
+/// that it already knows with a conflict and the existing job.
+fn parse_upload_response(status: u16, body: &[u8]) -> Result<JobStatusData> {
+    match serde_json::from_slice::<JobStatusData>(body) {
+        Ok(job) if (200..300).contains(&status) || status == 409 => Ok(job),
+        _ => {
+            let error: serde_json::Value = serde_json::from_slice(body).unwrap_or_default();
+            let message = [&error["error"], &error["message"]]
+                .into_iter()
+                .filter_map(|value| value.as_str())
+                .collect::<Vec<_>>()
+                .join(": ");
+            if message.is_empty() {
+                bail!("The video service responded with status {status}");
+            }
+            bail!("The video service responded with status {status}: {message}");
+        }
+    }
+}
+
+/// The blob of a finished job, None while the job is running, or the error
+/// of a failed job.
+fn job_result(status: &JobStatusData) -> Result<Option<BlobRef>> {
+    if status.state == "JOB_STATE_FAILED" {
+        let reason = [&status.error, &status.message]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(": ");
+        if reason.is_empty() {
+            bail!("The job failed without an error message");
         }
-        time::sleep(Duration::from_secs(1)).await;
+        bail!("{reason}");
+    }
+    match &status.blob {
+        Some(blob) => Ok(Some(blob.clone())),
+        None if status.state == "JOB_STATE_COMPLETED" => {
+            bail!("The job completed without a video blob")
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn job(state: &str) -> serde_json::Value {
+        json!({ "jobId": "job-1", "did": "did:plc:alice", "state": state })
+    }
+
+    #[test]
+    fn job_errors_are_surfaced() {
+        let mut failed = job("JOB_STATE_FAILED");
+        failed["error"] = json!("Video too long");
+        failed["message"] = json!("Videos may be at most 3 minutes");
+        let status = serde_json::from_value(failed).unwrap();
+        assert_eq!(
+            job_result(&status).unwrap_err().to_string(),
+            "Video too long: Videos may be at most 3 minutes"
+        );
+
+        let status = serde_json::from_value(job("JOB_STATE_ENCODING")).unwrap();
+        assert!(job_result(&status).unwrap().is_none());
+
+        let error = parse_upload_response(
+            400,
+            br#"{"error":"InvalidRequest","message":"Unsupported video format"}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The video service responded with status 400: InvalidRequest: Unsupported video format"
+        );
+        // A video that was uploaded before is not an error.
+        let known = serde_json::to_vec(&job("JOB_STATE_COMPLETED")).unwrap();
+        assert_eq!(parse_upload_response(409, &known).unwrap().job_id, "job-1");
+    }
+
+    #[test]
+    fn upload_limits_are_checked() {
+        let limits = |value| serde_json::from_value(value).unwrap();
+        assert_eq!(
+            limit_reached(&limits(json!({ "canUpload": true })), 1000),
+            None
+        );
+        assert_eq!(
+            limit_reached(
+                &limits(json!({ "canUpload": false, "message": "Daily limit reached" })),
+                1000
+            )
+            .as_deref(),
+            Some("Daily limit reached")
+        );
+        assert!(
+            limit_reached(
+                &limits(json!({ "canUpload": true, "remainingDailyBytes": 500 })),
+                1000
+            )
+            .is_some()
+        );
     }
-    let Some(video) = status.blob else {
-        bail!("Failed to get video blob: {status:?}");
-    };
-    println!("Video {url} uploaded to Bluesky");
-    Ok(video)
 }
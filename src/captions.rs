@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::hls::{hls_attribute, hls_segments};
+use crate::http::WebClient;
+
+/// Mastodon accepts media descriptions up to this many characters.
+pub const MASTODON_DESCRIPTION_LIMIT: usize = 1_500;
+
+/// Bluesky accepts caption files up to this many bytes.
+pub const BLUESKY_CAPTION_LIMIT: usize = 20_000;
+
+/// A subtitle track of an HLS playlist.
+#[derive(Debug, PartialEq)]
+struct SubtitleTrack {
+    language: Option<String>,
+    url: Url,
+}
+
+/// Downloads the captions of a Bluesky video stream as one WebVTT file.
+/// Prefers the track in the given language. Returns None if the video has no
+/// captions.
+pub async fn fetch_stream_captions(
+    http: &WebClient,
+    stream_url: &str,
+    language: &str,
+) -> Result<Option<String>> {
+    let base = Url::parse(stream_url)?;
+    let playlist = fetch_text(http, base.as_str()).await?;
+    let tracks = hls_subtitle_tracks(&playlist, &base);
+    let Some(track) = tracks
+        .iter()
+        .find(|track| track.language.as_deref() == Some(language))
+        .or(tracks.first())
+    else {
+        return Ok(None);
+    };
+    let subtitles = fetch_text(http, track.url.as_str()).await?;
+    let mut segments = Vec::new();
+    for segment in hls_segments(&subtitles, &track.url) {
+        segments.push(fetch_text(http, segment.as_str()).await?);
+    }
+    Ok(Some(merge_vtt_segments(&segments)))
+}
+
+async fn fetch_text(http: &WebClient, url: &str) -> Result<String> {
+    let response = http
+        .download(url)
+        .await
+        .context(format!("Failed downloading captions {url}"))?;
+    http.text(response).await
+}
+
+/// The subtitle tracks of an HLS master playlist.
+fn hls_subtitle_tracks(playlist: &str, base: &Url) -> Vec<SubtitleTrack> {
+    playlist
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXT-X-MEDIA:"))
+        .filter(|attributes| hls_attribute(attributes, "TYPE").as_deref() == Some("SUBTITLES"))
+        .filter_map(|attributes| {
+            Some(SubtitleTrack {
+                language: hls_attribute(attributes, "LANGUAGE"),
+                url: base.join(&hls_attribute(attributes, "URI")?).ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Joins the WebVTT segments of a subtitle track to one file. Only the
+/// header of the first segment is kept.
+fn merge_vtt_segments(segments: &[String]) -> String {
+    let mut merged = String::from("WEBVTT\n");
+    for segment in segments {
+        // The header ends at the first empty line.
+        let body = segment
+            .replace("\r\n", "\n")
+            .split_once("\n\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        if !body.trim().is_empty() {
+            merged.push('\n');
+            merged.push_str(body.trim());
+            merged.push('\n');
+        }
+    }
+    merged
+}
+
+/// The spoken text of a WebVTT file, without timings and markup. Cues that
+/// repeat at segment boundaries are only included once.
+pub fn vtt_text(vtt: &str) -> String {
+    let tags = regex::Regex::new(r"<[^>]*>").unwrap();
+    let mut lines: Vec<String> = Vec::new();
+    for block in vtt.replace("\r\n", "\n").split("\n\n").skip(1) {
+        let mut block_lines = block.lines().map(str::trim);
+        let first = block_lines.clone().next().unwrap_or_default();
+        if first.starts_with("NOTE") || first.starts_with("STYLE") || first.starts_with("REGION") {
+            continue;
+        }
+        // Skip the optional cue identifier and the timing line.
+        if !block_lines.by_ref().any(|line| line.contains("-->")) {
+            continue;
+        }
+        for line in block_lines {
+            let text = html_escape::decode_html_entities(&tags.replace_all(line, "")).to_string();
+            if !text.is_empty() && lines.last() != Some(&text) {
+                lines.push(text);
+            }
+        }
+    }
+    lines.join(" ")
+}
+
+/// Shortens a text to at most `max_chars` characters, ending with an
+/// ellipsis if it was cut.
+pub fn truncate_text(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    format!("{}…", cut.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtitle_tracks_are_read_from_the_playlist() {
+        let base = Url::parse("https://video.bsky.app/watch/did/cid/playlist.m3u8").unwrap();
+        let playlist = "#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",LANGUAGE=\"en\",URI=\"subtitles/en.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"Deutsch, Österreich\",LANGUAGE=\"de\",URI=\"subtitles/de.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,RESOLUTION=640x360,SUBTITLES=\"subs\"
+360p/video.m3u8
+";
+        assert_eq!(
+            hls_subtitle_tracks(playlist, &base),
+            vec![
+                SubtitleTrack {
+                    language: Some("en".to_string()),
+                    url: base.join("subtitles/en.m3u8").unwrap(),
+                },
+                SubtitleTrack {
+                    language: Some("de".to_string()),
+                    url: base.join("subtitles/de.m3u8").unwrap(),
+                },
+            ]
+        );
+
+        let media = "#EXTM3U\n#EXTINF:10.0,\nen_0.vtt\n#EXTINF:5.0,\nen_1.vtt\n#EXT-X-ENDLIST\n";
+        let track = base.join("subtitles/en.m3u8").unwrap();
+        assert_eq!(
+            hls_segments(media, &track),
+            vec![
+                base.join("subtitles/en_0.vtt").unwrap(),
+                base.join("subtitles/en_1.vtt").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn vtt_segments_become_one_transcript() {
+        let segments = [
+            "WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:00.000,MPEGTS:0\n\n1\n00:00:00.000 --> 00:00:02.000\nHello <b>world</b>!\n\n00:00:09.000 --> 00:00:11.000\nThis cue &amp; more\n".to_string(),
+            "WEBVTT\n\nNOTE repeated cue\n\n00:00:09.000 --> 00:00:11.000\nThis cue &amp; more\n\n00:00:11.000 --> 00:00:12.000\nBye.\n".to_string(),
+        ];
+        let vtt = merge_vtt_segments(&segments);
+        assert!(vtt.starts_with("WEBVTT\n\n1\n00:00:00.000"));
+        assert_eq!(vtt.matches("WEBVTT").count(), 1);
+        assert_eq!(vtt_text(&vtt), "Hello world! This cue & more Bye.");
+    }
+
+    #[test]
+    fn long_texts_are_truncated() {
+        assert_eq!(truncate_text("short", 10), "short");
+        assert_eq!(truncate_text("one two three", 8), "one two…");
+        assert_eq!(truncate_text("one two three", 8).chars().count(), 8);
+    }
+}
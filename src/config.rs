@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow, bail};
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_with::NoneAsEmptyString;
@@ -6,57 +6,386 @@ use serde_with::serde_as;
 use std::collections::BTreeMap;
 use tokio::fs;
 use tokio::fs::remove_file;
+use url::Url;
+
+use crate::secret::{Secret, SecretStore};
+use crate::state_dir::write_atomic;
 
 pub type DatePostList = BTreeMap<String, DateTime<Utc>>;
 
-#[inline]
+/// Keys that older versions of the README documented, with the section they
+/// are in and their current name. They still work.
+const RENAMED_KEYS: [(&str, &str, &str); 3] = [
+    ("mastodon", "delete_older_favs", "delete_old_favs"),
+    ("bluesky", "delete_older_posts", "delete_old_posts"),
+    ("bluesky", "delete_older_favs", "delete_old_favs"),
+];
+
+/// Parses the config. Unknown keys are rejected with a suggestion for the
+/// closest known key, so that typos do not silently disable a feature.
+/// Hashtags to sync are accepted with or without the leading '#'.
 pub fn config_load(config: &str) -> Result<Config> {
-    toml::from_str(config).map_err(anyhow::Error::from)
+    let mut config: Config =
+        toml::from_str(config).map_err(|e| match unknown_key_suggestion(e.message()) {
+            Some(suggestion) => anyhow!("{e}Did you mean `{suggestion}`?"),
+            None => anyhow::Error::from(e),
+        })?;
+    for hashtag in [
+        &mut config.mastodon.sync_hashtag,
+        &mut config.bluesky.sync_hashtag,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !hashtag.starts_with('#') {
+            hashtag.insert(0, '#');
+        }
+    }
+    Ok(config)
+}
+
+/// Warnings about keys of the config that were renamed.
+pub fn config_deprecations(config: &str) -> Vec<String> {
+    let Ok(table) = toml::from_str::<toml::Table>(config) else {
+        return Vec::new();
+    };
+    RENAMED_KEYS
+        .iter()
+        .filter(|(section, old, _)| {
+            table
+                .get(*section)
+                .and_then(|section| section.get(*old))
+                .is_some()
+        })
+        .map(|(section, old, new)| {
+            format!("{section}.{old} in the config is deprecated, rename it to {new}")
+        })
+        .collect()
+}
+
+/// Finds the expected key that is closest to the unknown key of a serde
+/// error message like "unknown field `a`, expected one of `b`, `c`".
+fn unknown_key_suggestion(message: &str) -> Option<String> {
+    let (unknown, expected) = message
+        .strip_prefix("unknown field `")?
+        .split_once("`, expected ")?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|key| (strsim::levenshtein(unknown, key), key))
+        .filter(|(distance, key)| *distance <= 3.max(key.len() / 3))
+        .min()
+        .map(|(_, key)| key.to_string())
+}
+
+/// Checks the values of the config that are not checked by their types.
+/// All problems are reported at once.
+pub fn config_validate(config: &Config) -> Result<()> {
+    let mut problems = Vec::new();
+    for (key, value) in [
+        ("mastodon.base_url", &config.mastodon.base_url),
+        ("bluesky.service_url", &config.bluesky.service_url),
+        (
+            "bluesky.video_service_url",
+            &config.bluesky.video_service_url,
+        ),
+    ] {
+        if let Err(e) = validate_url(value) {
+            problems.push(format!("{key} \"{value}\" {e}"));
+        }
+    }
+    for (key, value) in [
+        ("mastodon.sync_hashtag", &config.mastodon.sync_hashtag),
+        ("bluesky.sync_hashtag", &config.bluesky.sync_hashtag),
+    ] {
+        if let Some(hashtag) = value
+            && !is_hashtag(hashtag)
+        {
+            problems.push(format!(
+                "{key} \"{hashtag}\" must be a hashtag like #crosspost, or empty to sync all posts"
+            ));
+        }
+    }
+    if config.bluesky.auth == BlueskyAuth::AppPassword
+        && (config.bluesky.email.is_empty() || config.bluesky.app_password.is_empty())
+    {
+        problems.push(
+            "bluesky.email and bluesky.app_password are required unless auth is \"oauth\""
+                .to_string(),
+        );
+    }
+    for (key, domains) in [
+        (
+            "fetch.link_preview_allowed_domains",
+            &config.fetch.link_preview_allowed_domains,
+        ),
+        (
+            "fetch.link_preview_denied_domains",
+            &config.fetch.link_preview_denied_domains,
+        ),
+    ] {
+        for domain in domains.iter().filter(|domain| !is_domain(domain)) {
+            problems.push(format!(
+                "{key} \"{domain}\" must be a domain like example.com, without scheme or path"
+            ));
+        }
+    }
+    for (key, value) in [
+        ("media.max_video_duration", config.media.max_video_duration),
+        ("media.max_video_size", config.media.max_video_size),
+    ] {
+        if value == 0 {
+            problems.push(format!("{key} must be greater than 0"));
+        }
+    }
+    if !problems.is_empty() {
+        bail!("Invalid config values:\n  {}", problems.join("\n  "));
+    }
+    Ok(())
+}
+
+fn validate_url(value: &str) -> Result<()> {
+    let url = Url::parse(value).context("is not a valid URL")?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        bail!("must be an http or https URL");
+    }
+    Ok(())
+}
+
+fn is_domain(value: &str) -> bool {
+    Url::parse(&format!("https://{value}/"))
+        .is_ok_and(|url| url.host_str() == Some(&value.to_ascii_lowercase()))
+}
+
+fn is_hashtag(value: &str) -> bool {
+    value
+        .strip_prefix('#')
+        .is_some_and(|tag| !tag.is_empty() && tag.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// The state directory of the config file. Only this key is read so that
+/// commands like `queue` work with incomplete configs.
+pub fn config_state_dir(config: &str) -> Option<String> {
+    let table: toml::Table = toml::from_str(config).ok()?;
+    table.get("state_dir")?.as_str().map(String::from)
+}
+
+/// Reads the credentials that the config references from the environment,
+/// files or the keyring.
+pub async fn config_resolve_secrets(config: &mut Config) -> Result<()> {
+    for secret in config.secrets_mut() {
+        secret.resolve().await?;
+    }
+    Ok(())
+}
+
+/// Moves credentials that are written directly in the config to the store,
+/// so that saving the config does not write them to the file.
+pub async fn config_store_secrets(
+    config: &mut Config,
+    store: SecretStore,
+    secrets_dir: &str,
+) -> Result<()> {
+    for (name, secret) in [
+        "mastodon-client-secret",
+        "mastodon-access-token",
+        "mastodon-refresh-token",
+        "bluesky-app-password",
+    ]
+    .into_iter()
+    .zip(config.secrets_mut())
+    {
+        secret.store(store, name, secrets_dir).await?;
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Directory for caches and sessions, see [`crate::args::Args::state_dir`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<String>,
     pub mastodon: MastodonConfig,
     pub bluesky: BlueskyConfig,
+    #[serde(default, skip_serializing_if = "FetchConfig::is_default")]
+    pub fetch: FetchConfig,
+    #[serde(default, skip_serializing_if = "MediaConfig::is_default")]
+    pub media: MediaConfig,
+}
+
+impl Config {
+    fn secrets_mut(&mut self) -> [&mut Secret; 4] {
+        [
+            &mut self.mastodon.client_secret,
+            &mut self.mastodon.access_token,
+            &mut self.mastodon.refresh_token,
+            &mut self.bluesky.app_password,
+        ]
+    }
 }
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct MastodonConfig {
     pub base_url: String,
     pub client_id: String,
-    pub client_secret: String,
-    pub access_token: String,
-    pub refresh_token: String,
+    pub client_secret: Secret,
+    pub access_token: Secret,
+    pub refresh_token: Secret,
     #[serde(default = "config_true_default")]
     pub sync_reblogs: bool,
     #[serde_as(as = "NoneAsEmptyString")]
     #[serde(default = "config_none_default")]
     pub sync_hashtag: Option<String>,
-    #[serde(default = "config_false_default")]
+    #[serde(default = "config_false_default", alias = "delete_older_favs")]
     pub delete_old_favs: bool,
 }
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BlueskyConfig {
+    #[serde(default)]
+    pub auth: BlueskyAuth,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub email: String,
-    pub app_password: String,
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    pub app_password: Secret,
+    /// Server to log in to, the PDS of the account or the bsky.social entryway.
+    #[serde(default = "config_bluesky_service_default")]
+    pub service_url: String,
+    #[serde(default = "config_bluesky_video_service_default")]
+    pub video_service_url: String,
     #[serde(default = "config_true_default")]
     pub sync_reposts: bool,
     #[serde_as(as = "NoneAsEmptyString")]
     #[serde(default = "config_none_default")]
     pub sync_hashtag: Option<String>,
-    #[serde(default = "config_false_default")]
+    #[serde(default = "config_false_default", alias = "delete_older_posts")]
     pub delete_old_posts: bool,
-    #[serde(default = "config_false_default")]
+    #[serde(default = "config_false_default", alias = "delete_older_favs")]
     pub delete_old_favs: bool,
 }
 
+/// Which attachments, link previews and web pages may be downloaded. The URLs
+/// come from posts, so by default only public addresses are allowed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// Also download from loopback, private and link-local addresses, for
+    /// example from a Mastodon instance in the local network.
+    #[serde(default)]
+    pub allow_private_networks: bool,
+    /// If not empty, link previews are only fetched from these domains and
+    /// their subdomains.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_preview_allowed_domains: Vec<String>,
+    /// Link previews are never fetched from these domains and their
+    /// subdomains.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_preview_denied_domains: Vec<String>,
+}
+
+impl FetchConfig {
+    fn is_default(&self) -> bool {
+        *self == FetchConfig::default()
+    }
+}
+
+/// How attachments are converted for the other network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    /// What Bluesky gets for a Mastodon audio attachment.
+    pub audio: AudioMode,
+    /// Longest video in seconds that is uploaded to Bluesky.
+    pub max_video_duration: u64,
+    /// Largest video in MiB that is uploaded to Bluesky.
+    pub max_video_size: u64,
+    /// What Bluesky gets for a video over these limits.
+    pub oversized_videos: OversizedVideos,
+    /// How the captions of Bluesky videos are kept on Mastodon.
+    pub captions: CaptionMode,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            audio: AudioMode::default(),
+            max_video_duration: 180,
+            max_video_size: 100,
+            oversized_videos: OversizedVideos::default(),
+            captions: CaptionMode::default(),
+        }
+    }
+}
+
+impl MediaConfig {
+    fn is_default(&self) -> bool {
+        *self == MediaConfig::default()
+    }
+}
+
+/// Bluesky has no audio attachments.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioMode {
+    /// A video of the audio with the cover image, made with ffmpeg.
+    #[default]
+    Video,
+    /// A link preview of the original post.
+    Link,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedVideos {
+    /// A link preview of the original post.
+    #[default]
+    Link,
+    /// The video is cut to the maximum duration and encoded again to fit the
+    /// maximum size with ffmpeg. Cut videos get a link to the full video.
+    Transcode,
+}
+
+/// Mastodon videos have no caption tracks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptionMode {
+    /// The text of the captions becomes the description of the video.
+    #[default]
+    Description,
+    /// The captions are rendered into the video with ffmpeg.
+    BurnIn,
+}
+
+/// How to log in to Bluesky.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlueskyAuth {
+    /// Email and app password from the config file.
+    #[default]
+    #[serde(rename = "app_password")]
+    AppPassword,
+    /// OAuth tokens from the session file, created with `login bluesky`.
+    #[serde(rename = "oauth")]
+    OAuth,
+}
+
 fn config_true_default() -> bool {
     true
 }
 
+pub fn config_bluesky_service_default() -> String {
+    "https://bsky.social".to_string()
+}
+
+pub fn config_bluesky_video_service_default() -> String {
+    "https://video.bsky.app".to_string()
+}
+
 fn config_none_default<T>() -> Option<T> {
     None
 }
@@ -93,6 +422,114 @@ pub async fn save_dates_to_cache(cache_file: &str, dates: &DatePostList) -> Resu
         return Ok(());
     }
     let json = serde_json::to_string_pretty(&dates)?;
-    fs::write(cache_file, json.as_bytes()).await?;
+    write_atomic(cache_file, json.as_bytes()).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [mastodon]
+        base_url = "https://mastodon.example"
+        client_id = "id"
+        client_secret = "secret"
+        access_token = "token"
+        refresh_token = "none"
+
+        [bluesky]
+        email = "alice@example.com"
+        app_password = "password"
+    "#;
+
+    #[test]
+    fn unknown_keys_are_rejected_with_a_suggestion() {
+        let config = CONFIG.replace("[bluesky]", "[bluesky]\ndelete_old_post = true");
+        let error = config_load(&config).unwrap_err().to_string();
+        assert!(error.contains("unknown field `delete_old_post`"), "{error}");
+        assert!(
+            error.ends_with("Did you mean `delete_old_posts`?"),
+            "{error}"
+        );
+
+        let config = CONFIG.replace("[mastodon]", "[mastodon]\nsomething = true");
+        let error = config_load(&config).unwrap_err().to_string();
+        assert!(!error.contains("Did you mean"), "{error}");
+
+        let config = CONFIG.replace("[bluesky]", "[bluesky]\nsync_repost = false");
+        let error = config_load(&config).unwrap_err().to_string();
+        assert!(error.ends_with("Did you mean `sync_reposts`?"), "{error}");
+    }
+
+    #[test]
+    fn renamed_keys_still_work() {
+        let config = CONFIG
+            .replace("[mastodon]", "[mastodon]\ndelete_older_favs = true")
+            .replace("[bluesky]", "[bluesky]\ndelete_older_posts = true");
+        let loaded = config_load(&config).unwrap();
+        assert!(loaded.mastodon.delete_old_favs);
+        assert!(loaded.bluesky.delete_old_posts);
+        assert!(!loaded.bluesky.delete_old_favs);
+        assert_eq!(
+            config_deprecations(&config),
+            vec![
+                "mastodon.delete_older_favs in the config is deprecated, rename it to delete_old_favs",
+                "bluesky.delete_older_posts in the config is deprecated, rename it to delete_old_posts",
+            ]
+        );
+        assert!(config_deprecations(CONFIG).is_empty());
+    }
+
+    #[test]
+    fn hashtags_without_hash_are_accepted() {
+        let config = CONFIG.replace("[bluesky]", "sync_hashtag = \"crosspost\"\n[bluesky]");
+        let config = config_load(&config).unwrap();
+        assert_eq!(config.mastodon.sync_hashtag.as_deref(), Some("#crosspost"));
+        assert_eq!(config.bluesky.sync_hashtag, None);
+        config_validate(&config).unwrap();
+    }
+
+    #[test]
+    fn invalid_values_are_reported_together() {
+        config_validate(&config_load(CONFIG).unwrap()).unwrap();
+
+        let config = CONFIG
+            .replace("https://mastodon.example", "mastodon.example")
+            .replace(
+                "[bluesky]",
+                "sync_hashtag = \"cross post\"\n[bluesky]\nsync_hashtag = \"#bsky\"",
+            )
+            .replace("app_password = \"password\"", "")
+            + "[fetch]\nlink_preview_denied_domains = [\"tracker.example\", \"https://ads.example/\"]";
+        let error = config_validate(&config_load(&config).unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("mastodon.base_url \"mastodon.example\" is not a valid URL"));
+        assert!(error.contains("mastodon.sync_hashtag \"#cross post\" must be a hashtag"));
+        assert!(!error.contains("bluesky.sync_hashtag"));
+        assert!(error.contains("bluesky.app_password are required"));
+        assert!(
+            error.contains("link_preview_denied_domains \"https://ads.example/\" must be a domain")
+        );
+        assert!(!error.contains("tracker.example"));
+    }
+
+    #[test]
+    fn media_section_is_optional() {
+        let config = config_load(CONFIG).unwrap();
+        assert_eq!(config.media.audio, AudioMode::Video);
+        assert!(!toml::to_string(&config).unwrap().contains("[media]"));
+
+        let config = config_load(&format!(
+            "{CONFIG}\n[media]\naudio = \"link\"\nmax_video_duration = 0\ncaptions = \"burn_in\""
+        ))
+        .unwrap();
+        assert_eq!(config.media.audio, AudioMode::Link);
+        assert_eq!(config.media.max_video_size, 100);
+        assert_eq!(config.media.oversized_videos, OversizedVideos::Link);
+        assert_eq!(config.media.captions, CaptionMode::BurnIn);
+        let error = config_validate(&config).unwrap_err().to_string();
+        assert!(error.contains("media.max_video_duration must be greater than 0"));
+    }
+}
@@ -1,104 +1,104 @@
 use anyhow::Result;
-use bsky_sdk::api::types::LimitedNonZeroU8;
-use bsky_sdk::api::types::TryFromUnknown;
 use chrono::Duration;
 use chrono::prelude::*;
 use std::collections::BTreeMap;
 
-use crate::BskyAgent;
 use crate::DatePostList;
-use crate::cache_file;
 use crate::load_dates_from_cache;
+use crate::network::SocialNetwork;
 use crate::remove_date_from_cache;
+use crate::report::DeletionCount;
+use crate::report::progress;
 use crate::save_dates_to_cache;
 
 // Delete old posts of this account that are older than 90 days.
-pub async fn bluesky_delete_older_posts(bsky_agent: &BskyAgent, dry_run: bool) -> Result<()> {
+pub async fn delete_older_posts<N: SocialNetwork>(
+    network: &N,
+    cache_file: &str,
+    dry_run: bool,
+) -> Result<DeletionCount> {
     // In order not to fetch old posts every time keep them in a cache file
     // keyed by their dates.
-    let cache_file = &cache_file("bluesky_cache.json");
-    let dates = bluesky_load_post_dates(bsky_agent, cache_file).await?;
+    let dates = load_post_dates(network, cache_file).await?;
     let three_months_ago = Utc::now() - Duration::days(90);
-    for (post_uri, date) in dates.iter().filter(|(_, date)| date < &&three_months_ago) {
-        println!("Deleting Bluesky post from {date}: {post_uri}");
+    let mut count = DeletionCount::default();
+    for (post_id, date) in dates.iter().filter(|(_, date)| date < &&three_months_ago) {
+        count.expired += 1;
+        progress!("Deleting {} post from {date}: {post_id}", network.name());
         // Do nothing on a dry run, just print what would be done.
         if dry_run {
             continue;
         }
-        // No error handling needed here for non existing posts, the Bluesky API
-        // returns success even if the post does not exist.
-        bsky_agent.delete_record(post_uri).await?;
-        remove_date_from_cache(post_uri, cache_file).await?;
+        network.delete_post(post_id).await?;
+        remove_date_from_cache(post_id, cache_file).await?;
+        count.deleted += 1;
     }
-    Ok(())
+    Ok(count)
 }
 
-async fn bluesky_load_post_dates(bsky_agent: &BskyAgent, cache_file: &str) -> Result<DatePostList> {
+async fn load_post_dates<N: SocialNetwork>(network: &N, cache_file: &str) -> Result<DatePostList> {
     match load_dates_from_cache(cache_file).await? {
         Some(dates) => Ok(dates),
-        None => bluesky_fetch_post_dates(bsky_agent, cache_file).await,
+        None => fetch_post_dates(network, cache_file).await,
     }
 }
 
-async fn bluesky_fetch_post_dates(
-    bsky_agent: &BskyAgent,
-    cache_file: &str,
-) -> Result<DatePostList> {
+async fn fetch_post_dates<N: SocialNetwork>(network: &N, cache_file: &str) -> Result<DatePostList> {
     let mut dates = BTreeMap::new();
     let mut cursor = None;
     loop {
-        println!(
-            "Fetching Bluesky posts older than {}",
+        progress!(
+            "Fetching {} posts older than {}",
+            network.name(),
             cursor.as_ref().unwrap_or(&"now".to_string())
         );
-        // Try to fetch as many posts as possible at once, Bluesky API docs say
-        // that is 100.
-        let feed = match bsky_agent
-            .api
-            .app
-            .bsky
-            .feed
-            .get_author_feed(
-                bsky_sdk::api::app::bsky::feed::get_author_feed::ParametersData {
-                    actor: bsky_agent.get_session().await.unwrap().did.clone().into(),
-                    cursor,
-                    filter: None,
-                    include_pins: None,
-                    limit: Some(LimitedNonZeroU8::try_from(100).unwrap()),
-                }
-                .into(),
-            )
-            .await
-        {
-            Ok(posts) => posts,
+        let page = match network.list_posts(cursor).await {
+            Ok(page) => page,
             Err(e) => {
-                eprintln!("Error fetching posts from Bluesky: {e:#?}");
+                eprintln!("Error fetching posts from {}: {e:#?}", network.name());
                 break;
             }
         };
-
-        for post in &feed.feed {
-            let record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
-                post.post.record.clone(),
-            )
-            .expect("Failed to parse Bluesky post record");
-
-            // Check if this post is a repost.
-            if let Some(viewer) = &post.post.viewer
-                && let Some(repost) = &viewer.repost
-            {
-                dates.insert(repost.to_string(), (*record.created_at.as_ref()).into());
-                continue;
-            }
-            dates.insert(post.post.uri.clone(), (*record.created_at.as_ref()).into());
-        }
-        if feed.cursor.is_none() {
+        dates.extend(page.dates);
+        if page.cursor.is_none() {
             break;
         }
-        cursor = feed.cursor.clone();
+        cursor = page.cursor;
     }
 
     save_dates_to_cache(cache_file, &dates).await?;
 
     Ok(dates)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_network::FakeNetwork;
+    use crate::sync::NewStatus;
+
+    #[tokio::test]
+    async fn deletes_only_posts_older_than_90_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("posts.json");
+        let cache_file = cache_file.to_str().unwrap();
+        let network = FakeNetwork::<NewStatus>::new(Vec::new());
+        network.add_post("old", Utc::now() - Duration::days(100));
+        network.add_post("older", Utc::now() - Duration::days(200));
+        network.add_post("new", Utc::now() - Duration::days(10));
+
+        let count = delete_older_posts(&network, cache_file, true)
+            .await
+            .unwrap();
+        assert_eq!((count.expired, count.deleted), (2, 0));
+        assert_eq!(network.post_ids(), vec!["new", "old", "older"]);
+
+        let count = delete_older_posts(&network, cache_file, false)
+            .await
+            .unwrap();
+        assert_eq!((count.expired, count.deleted), (2, 2));
+        assert_eq!(network.post_ids(), vec!["new"]);
+        let cached = load_dates_from_cache(cache_file).await.unwrap().unwrap();
+        assert_eq!(cached.keys().collect::<Vec<_>>(), vec!["new"]);
+    }
+}
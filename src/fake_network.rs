@@ -0,0 +1,192 @@
+use anyhow::{Result, bail};
+use chrono::prelude::*;
+use std::sync::Mutex;
+
+use crate::DatePostList;
+use crate::network::{DatePage, LikeDeletion, SocialNetwork};
+use crate::sync::{NewMedia, NewStatus};
+
+/// Number of items per page returned by the list functions, small to
+/// exercise pagination.
+const PAGE_SIZE: usize = 2;
+
+/// A post created on a [`FakeNetwork`].
+#[derive(Debug, Clone)]
+pub struct CreatedPost {
+    pub id: String,
+    pub text: String,
+    /// URLs of the uploaded attachments.
+    pub media: Vec<String>,
+    pub reply_to: Option<String>,
+}
+
+/// In-memory social network for tests.
+pub struct FakeNetwork<P> {
+    timeline: Vec<P>,
+    created: Mutex<Vec<CreatedPost>>,
+    posts: Mutex<DatePostList>,
+    likes: Mutex<DatePostList>,
+    fail_posts: Mutex<bool>,
+    // Creating a post with this text fails.
+    fail_text: Mutex<Option<String>>,
+    // Number of likes that can be deleted before the rate limit hits.
+    like_deletions_left: Mutex<usize>,
+    // Uploads running right now, the most that ran at the same time and the
+    // number of started uploads.
+    uploads_in_flight: Mutex<(usize, usize, usize)>,
+}
+
+impl<P> FakeNetwork<P> {
+    pub fn new(timeline: Vec<P>) -> Self {
+        FakeNetwork {
+            timeline,
+            created: Mutex::new(Vec::new()),
+            posts: Mutex::new(DatePostList::new()),
+            likes: Mutex::new(DatePostList::new()),
+            fail_posts: Mutex::new(false),
+            fail_text: Mutex::new(None),
+            like_deletions_left: Mutex::new(usize::MAX),
+            uploads_in_flight: Mutex::new((0, 0, 0)),
+        }
+    }
+
+    pub fn created(&self) -> Vec<CreatedPost> {
+        self.created.lock().unwrap().clone()
+    }
+
+    /// Makes creating posts fail until it is switched off again.
+    pub fn fail_posts(&self, fail: bool) {
+        *self.fail_posts.lock().unwrap() = fail;
+    }
+
+    /// Makes creating posts with the given text fail, or none with None.
+    pub fn fail_text(&self, text: Option<&str>) {
+        *self.fail_text.lock().unwrap() = text.map(String::from);
+    }
+
+    pub fn add_post(&self, id: &str, date: DateTime<Utc>) {
+        self.posts.lock().unwrap().insert(id.to_string(), date);
+    }
+
+    pub fn post_ids(&self) -> Vec<String> {
+        self.posts.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn add_like(&self, id: &str, date: DateTime<Utc>) {
+        self.likes.lock().unwrap().insert(id.to_string(), date);
+    }
+
+    pub fn like_ids(&self) -> Vec<String> {
+        self.likes.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn rate_limit_likes_after(&self, deletions: usize) {
+        *self.like_deletions_left.lock().unwrap() = deletions;
+    }
+
+    /// The highest number of uploads that ran at the same time.
+    pub fn max_uploads_in_flight(&self) -> usize {
+        self.uploads_in_flight.lock().unwrap().1
+    }
+}
+
+/// Returns the page of entries starting at the offset in the cursor.
+fn page(entries: &DatePostList, cursor: Option<String>) -> Result<DatePage> {
+    let offset = match cursor {
+        Some(cursor) => cursor.parse()?,
+        None => 0,
+    };
+    let dates: DatePostList = entries
+        .iter()
+        .skip(offset)
+        .take(PAGE_SIZE)
+        .map(|(id, date)| (id.clone(), *date))
+        .collect();
+    let next = offset + dates.len();
+    Ok(DatePage {
+        dates,
+        cursor: (next < entries.len()).then(|| next.to_string()),
+    })
+}
+
+impl<P: Clone> SocialNetwork for FakeNetwork<P> {
+    type Post = P;
+    type Media = String;
+
+    fn name(&self) -> &'static str {
+        "Fake"
+    }
+
+    async fn fetch_timeline(&self) -> Result<Vec<P>> {
+        Ok(self.timeline.clone())
+    }
+
+    async fn upload_media(
+        &self,
+        attachment: &NewMedia,
+        _status: &NewStatus,
+    ) -> Result<Option<String>> {
+        let started = {
+            let mut in_flight = self.uploads_in_flight.lock().unwrap();
+            in_flight.0 += 1;
+            in_flight.1 = in_flight.1.max(in_flight.0);
+            in_flight.2 += 1;
+            in_flight.2
+        };
+        // Give other uploads the chance to start, later uploads finish first.
+        for _ in started..10 {
+            tokio::task::yield_now().await;
+        }
+        self.uploads_in_flight.lock().unwrap().0 -= 1;
+        Ok(Some(attachment.attachment_url.clone()))
+    }
+
+    async fn create_post(
+        &self,
+        status: &NewStatus,
+        media: Vec<String>,
+        reply_to: Option<&str>,
+    ) -> Result<String> {
+        if *self.fail_posts.lock().unwrap() {
+            bail!("Fake network is down");
+        }
+        if self.fail_text.lock().unwrap().as_deref() == Some(status.text.as_str()) {
+            bail!("Fake network rejects the post");
+        }
+        let mut created = self.created.lock().unwrap();
+        let id = format!("fake-{}", created.len() + 1);
+        created.push(CreatedPost {
+            id: id.clone(),
+            text: status.text.clone(),
+            media,
+            reply_to: reply_to.map(String::from),
+        });
+        self.add_post(&id, Utc::now());
+        Ok(id)
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<()> {
+        self.posts.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn list_posts(&self, cursor: Option<String>) -> Result<DatePage> {
+        page(&self.posts.lock().unwrap(), cursor)
+    }
+
+    async fn list_likes(&self, cursor: Option<String>) -> Result<DatePage> {
+        page(&self.likes.lock().unwrap(), cursor)
+    }
+
+    async fn delete_like(&self, id: &str) -> Result<LikeDeletion> {
+        let mut left = self.like_deletions_left.lock().unwrap();
+        if *left == 0 {
+            return Ok(LikeDeletion::RateLimited);
+        }
+        *left -= 1;
+        Ok(match self.likes.lock().unwrap().remove(id) {
+            Some(_) => LikeDeletion::Deleted,
+            None => LikeDeletion::Gone,
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{Context, Result, bail};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use url::{Host, Url};
+
+use crate::config::FetchConfig;
+
+/// Resolves host names to their public addresses only. Connecting to the
+/// checked addresses instead of resolving again prevents DNS rebinding.
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|address| is_public_address(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// False for loopback, private, link-local and other addresses that are not
+/// reachable on the internet.
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network" and the shared address space of carrier NAT.
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            // Addresses that carry an IPv4 address are only as public as that.
+            let embedded = match ip.segments() {
+                // 6to4.
+                [0x2002, high, low, ..] => Some(ipv4_from_segments(high, low)),
+                // NAT64 with the well-known prefix.
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(ipv4_from_segments(high, low)),
+                // IPv4-mapped and IPv4-compatible, including :: and ::1.
+                _ => ip.to_ipv4(),
+            };
+            match embedded {
+                Some(ip) => is_public_address(ip.into()),
+                None => {
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_unique_local()
+                        || ip.is_unicast_link_local()
+                        || ip.is_multicast())
+                }
+            }
+        }
+    }
+}
+
+fn ipv4_from_segments(high: u16, low: u16) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(high) << 16 | u32::from(low))
+}
+
+/// Checks all addresses of the host of a URL that is requested through a
+/// proxy. The proxy resolves the host itself, so [`PublicResolver`] never sees
+/// the addresses it connects to.
+pub async fn check_proxied_host(url: &Url) -> Result<()> {
+    let Some(Host::Domain(host)) = url.host() else {
+        return Ok(());
+    };
+    let port = url.port_or_known_default().unwrap_or(0);
+    let addresses = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("Failed resolving {host} to check its addresses"))?;
+    for address in addresses {
+        if !is_public_address(address.ip()) {
+            bail!(
+                "Not downloading {url}, {host} resolves to {} which is not a public address",
+                address.ip()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks a URL before it is requested. Host names are checked again by
+/// [`PublicResolver`] after they are resolved. The domain lists only apply to
+/// link previews.
+pub fn check_url(url: &Url, config: &FetchConfig, link_preview: bool) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Not downloading {url}, only http and https URLs are allowed");
+    }
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        Some(Host::Domain(_)) => None,
+        None => bail!("Not downloading {url} without a host"),
+    };
+    if let Some(ip) = ip
+        && !config.allow_private_networks
+        && !is_public_address(ip)
+    {
+        bail!("Not downloading {url}, {ip} is not a public address");
+    }
+    if !link_preview {
+        return Ok(());
+    }
+    let host = url.host_str().unwrap_or_default();
+    if config
+        .link_preview_denied_domains
+        .iter()
+        .any(|domain| domain_matches(host, domain))
+    {
+        bail!("Not fetching a link preview of {url}, the domain is denied in the config");
+    }
+    if !config.link_preview_allowed_domains.is_empty()
+        && !config
+            .link_preview_allowed_domains
+            .iter()
+            .any(|domain| domain_matches(host, domain))
+    {
+        bail!("Not fetching a link preview of {url}, the domain is not allowed in the config");
+    }
+    Ok(())
+}
+
+/// True for the domain itself and its subdomains.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let host = host.trim_end_matches('.');
+    host == domain
+        || host
+            .strip_suffix(&domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+            "::10.0.0.5",
+            "64:ff9b::a00:5",
+            "64:ff9b::127.0.0.1",
+            "2002:a00:5::1",
+            "2002:c0a8:101::",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2a01:4f8::1",
+            "::ffff:8.8.8.8",
+            "64:ff9b::8.8.8.8",
+            "2002:808:808::1",
+        ] {
+            assert!(is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn urls_are_checked_against_the_config() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let mut config = FetchConfig {
+            link_preview_denied_domains: vec!["tracker.example".to_string()],
+            ..Default::default()
+        };
+        assert!(check_url(&url("https://example.com/a"), &config, true).is_ok());
+        assert!(check_url(&url("http://192.168.1.1/"), &config, false).is_err());
+        assert!(check_url(&url("http://[::1]:8080/"), &config, false).is_err());
+        assert!(check_url(&url("file:///etc/passwd"), &config, false).is_err());
+        assert!(check_url(&url("https://ads.tracker.example/"), &config, true).is_err());
+        // Only link previews are limited by the domain lists.
+        assert!(check_url(&url("https://tracker.example/"), &config, false).is_ok());
+        assert!(check_url(&url("https://nottracker.example/"), &config, true).is_ok());
+
+        config.allow_private_networks = true;
+        config.link_preview_allowed_domains = vec!["news.example".to_string()];
+        assert!(check_url(&url("http://192.168.1.1/"), &config, false).is_ok());
+        assert!(check_url(&url("https://www.news.example/"), &config, true).is_ok());
+        assert!(check_url(&url("https://example.com/"), &config, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn proxied_hosts_are_resolved_and_checked() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let error = check_proxied_host(&url("http://localhost/admin"))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("is not a public address"),
+            "{error}"
+        );
+        // Addresses in the URL are checked by check_url().
+        assert!(check_proxied_host(&url("http://10.0.0.5/")).await.is_ok());
+    }
+}
@@ -0,0 +1,1012 @@
+//! Downloads the HLS streams of Bluesky videos and remuxes them to MP4 without
+//! ffmpeg. Only H.264 video with AAC audio in MPEG-TS segments is supported,
+//! which is what Bluesky serves.
+
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use url::Url;
+
+use crate::http::WebClient;
+use crate::media::Unconvertible;
+use crate::mp4::{self, AudioTrack, Sample, VIDEO_TIMESCALE, VideoTrack};
+use crate::report::progress;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Stream types of the program map table.
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_AAC: u8 = 0x0f;
+const OTHER_VIDEO_TYPES: [u8; 4] = [0x01, 0x02, 0x10, 0x24];
+const OTHER_AUDIO_TYPES: [u8; 6] = [0x03, 0x04, 0x11, 0x81, 0x87, 0x8a];
+
+/// Sample rates of the ADTS header by index.
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96_000, 88_200, 64_000, 48_000, 44_100, 32_000, 24_000, 22_050, 16_000, 12_000, 11_025, 8_000,
+    7_350,
+];
+
+/// Samples in an AAC frame.
+const AAC_FRAME_SAMPLES: u32 = 1024;
+
+/// Downloads the best variant of an HLS stream as one MP4 file.
+pub async fn download_stream_mp4(http: &WebClient, stream_url: &str) -> Result<Vec<u8>> {
+    let base = Url::parse(stream_url)?;
+    let master = fetch_text(http, &base).await?;
+    let (playlist_url, playlist) = match best_variant(&master, &base) {
+        Some(variant) => {
+            let playlist = fetch_text(http, &variant).await?;
+            (variant, playlist)
+        }
+        // The stream is a media playlist already.
+        None => (base, master),
+    };
+    if playlist.lines().any(|line| {
+        line.strip_prefix("#EXT-X-KEY:")
+            .is_some_and(|key| hls_attribute(key, "METHOD").as_deref() != Some("NONE"))
+    }) {
+        bail!(Unconvertible("The video stream is encrypted".to_string()));
+    }
+    // Joined fragmented MP4 segments are no MP4 file that can be checked
+    // against the limits or played everywhere.
+    if playlist.lines().any(|line| line.starts_with("#EXT-X-MAP:")) {
+        bail!(Unconvertible(
+            "Video streams with fragmented MP4 segments cannot be converted without ffmpeg"
+                .to_string()
+        ));
+    }
+
+    progress!("Remuxing video stream {stream_url} to MP4...");
+    let mut data = Vec::new();
+    for segment in hls_segments(&playlist, &playlist_url) {
+        data.extend(fetch_bytes(http, &segment).await?);
+        // Every segment is limited on its own, the whole video as well.
+        if data.len() as u64 > http.max_download_size() {
+            bail!(Unconvertible(format!(
+                "The video stream is larger than the maximum download size of {} MiB",
+                http.max_download_size() / 1024 / 1024
+            )));
+        }
+    }
+    tokio::task::spawn_blocking(move || remux_ts(&data)).await?
+}
+
+async fn fetch_text(http: &WebClient, url: &Url) -> Result<String> {
+    let response = http
+        .download(url.as_str())
+        .await
+        .context(format!("Failed downloading playlist {url}"))?;
+    http.text(response).await
+}
+
+async fn fetch_bytes(http: &WebClient, url: &Url) -> Result<Vec<u8>> {
+    let response = http
+        .download(url.as_str())
+        .await
+        .context(format!("Failed downloading video segment {url}"))?;
+    http.bytes(response).await
+}
+
+/// The media playlist with the highest bandwidth of a master playlist.
+fn best_variant(playlist: &str, base: &Url) -> Option<Url> {
+    let mut lines = playlist.lines().map(str::trim);
+    let mut best: Option<(u64, Url)> = None;
+    while let Some(line) = lines.next() {
+        let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") else {
+            continue;
+        };
+        let bandwidth = hls_attribute(attributes, "BANDWIDTH")
+            .and_then(|bandwidth| bandwidth.parse().ok())
+            .unwrap_or(0);
+        let Some(url) = lines
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|uri| base.join(uri).ok())
+        else {
+            continue;
+        };
+        if best.as_ref().is_none_or(|(best, _)| bandwidth > *best) {
+            best = Some((bandwidth, url));
+        }
+    }
+    best.map(|(_, url)| url)
+}
+
+/// Reads an attribute like `LANGUAGE="en"` of an HLS tag.
+pub fn hls_attribute(attributes: &str, name: &str) -> Option<String> {
+    let mut rest = attributes;
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, remaining) = quoted.split_once('"')?;
+                (value, remaining.strip_prefix(',').unwrap_or(remaining))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        if key.trim() == name {
+            return Some(value.to_string());
+        }
+        rest = remaining;
+    }
+    None
+}
+
+/// The segment URLs of an HLS media playlist.
+pub fn hls_segments(playlist: &str, base: &Url) -> Vec<Url> {
+    playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| base.join(line).ok())
+        .collect()
+}
+
+/// Converts an MPEG-TS stream with H.264 video and AAC audio to MP4.
+pub fn remux_ts(data: &[u8]) -> Result<Vec<u8>> {
+    let streams = demux_ts(data)?;
+    let (mut video, video_start) = video_track(&streams.video)?;
+    let mut audio = audio_track(&streams.audio)?;
+    // The track that starts later is delayed by an edit.
+    let start = audio.as_ref().map_or(video_start, |(_, audio_start)| {
+        video_start.min(*audio_start)
+    });
+    video.delay = (video_start - start) as f64 / VIDEO_TIMESCALE as f64;
+    if let Some((audio, audio_start)) = &mut audio {
+        audio.delay = (*audio_start - start) as f64 / VIDEO_TIMESCALE as f64;
+    }
+    mp4::write(&video, audio.as_ref().map(|(audio, _)| audio))
+}
+
+/// A PES packet of an elementary stream with its timestamps.
+#[derive(Debug)]
+struct Pes {
+    pts: Option<u64>,
+    dts: Option<u64>,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Streams {
+    video: Vec<Pes>,
+    audio: Vec<Pes>,
+}
+
+/// Splits an MPEG-TS stream into the PES packets of the first H.264 and the
+/// first AAC stream of the first program.
+fn demux_ts(data: &[u8]) -> Result<Streams> {
+    let mut pmt_pid = None;
+    let mut video_pid = None;
+    let mut audio_pid = None;
+    let mut other_video = None;
+    let mut other_audio = None;
+    let mut buffers: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut streams = Streams::default();
+
+    let mut position = 0;
+    while position + TS_PACKET_SIZE <= data.len() {
+        if data[position] != TS_SYNC_BYTE {
+            position += 1;
+            continue;
+        }
+        let packet = &data[position..position + TS_PACKET_SIZE];
+        position += TS_PACKET_SIZE;
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = (packet[3] >> 4) & 0x03;
+        let mut offset = 4;
+        if adaptation & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation & 0x01 == 0 || offset >= TS_PACKET_SIZE {
+            continue;
+        }
+        let payload = &packet[offset..];
+
+        if pid == 0 && unit_start {
+            pmt_pid = pmt_pid.or(pat_pmt_pid(payload));
+        } else if Some(pid) == pmt_pid && unit_start && video_pid.is_none() {
+            for stream in pmt_streams(payload).unwrap_or_default() {
+                match stream.stream_type {
+                    STREAM_TYPE_H264 if video_pid.is_none() => video_pid = Some(stream.pid),
+                    STREAM_TYPE_AAC if audio_pid.is_none() => audio_pid = Some(stream.pid),
+                    stream_type if OTHER_VIDEO_TYPES.contains(&stream_type) => {
+                        other_video = other_video.or(Some(stream_type));
+                    }
+                    stream_type if OTHER_AUDIO_TYPES.contains(&stream_type) => {
+                        other_audio = other_audio.or(Some(stream_type));
+                    }
+                    _ => {}
+                }
+            }
+        } else if Some(pid) == video_pid || Some(pid) == audio_pid {
+            if unit_start {
+                if let Some(buffer) = buffers.insert(pid, payload.to_vec()) {
+                    let pes = parse_pes(&buffer)?;
+                    if Some(pid) == video_pid {
+                        streams.video.push(pes);
+                    } else {
+                        streams.audio.push(pes);
+                    }
+                }
+            } else if let Some(buffer) = buffers.get_mut(&pid) {
+                buffer.extend_from_slice(payload);
+            }
+        }
+    }
+    for pid in [video_pid, audio_pid].into_iter().flatten() {
+        if let Some(buffer) = buffers.remove(&pid) {
+            let pes = parse_pes(&buffer)?;
+            if Some(pid) == video_pid {
+                streams.video.push(pes);
+            } else {
+                streams.audio.push(pes);
+            }
+        }
+    }
+
+    match (video_pid, other_video) {
+        (Some(_), _) => {}
+        (None, Some(stream_type)) => bail!(Unconvertible(format!(
+            "Video of stream type {stream_type:#04x} cannot be converted without ffmpeg, only H.264"
+        ))),
+        (None, None) => bail!(Unconvertible(
+            "The video stream has no H.264 video".to_string()
+        )),
+    }
+    if let (None, Some(stream_type)) = (audio_pid, other_audio) {
+        bail!(Unconvertible(format!(
+            "Audio of stream type {stream_type:#04x} cannot be converted without ffmpeg, only AAC"
+        )));
+    }
+    Ok(streams)
+}
+
+/// The content of a PSI section after the pointer field, from the first
+/// byte after the section length to the CRC.
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    let length = u16::from_be_bytes([section.get(1)? & 0x0f, *section.get(2)?]) as usize;
+    section.get(3..3 + length.checked_sub(4)?)
+}
+
+/// The PID of the program map table of the first program.
+fn pat_pmt_pid(payload: &[u8]) -> Option<u16> {
+    psi_section(payload)?
+        .get(5..)?
+        .chunks_exact(4)
+        .find(|program| program[0] != 0 || program[1] != 0)
+        .map(|program| u16::from_be_bytes([program[2] & 0x1f, program[3]]))
+}
+
+#[derive(Debug, PartialEq)]
+struct ElementaryStream {
+    stream_type: u8,
+    pid: u16,
+}
+
+fn pmt_streams(payload: &[u8]) -> Option<Vec<ElementaryStream>> {
+    let section = psi_section(payload)?;
+    let info_length = u16::from_be_bytes([section.get(7)? & 0x0f, *section.get(8)?]) as usize;
+    let mut entries = section.get(9 + info_length..)?;
+    let mut streams = Vec::new();
+    while entries.len() >= 5 {
+        streams.push(ElementaryStream {
+            stream_type: entries[0],
+            pid: u16::from_be_bytes([entries[1] & 0x1f, entries[2]]),
+        });
+        let info_length = u16::from_be_bytes([entries[3] & 0x0f, entries[4]]) as usize;
+        entries = entries.get(5 + info_length..).unwrap_or_default();
+    }
+    Some(streams)
+}
+
+fn parse_pes(data: &[u8]) -> Result<Pes> {
+    if data.len() < 9 || data[..3] != [0, 0, 1] {
+        bail!("Invalid PES packet in the video stream");
+    }
+    let flags = data[7] >> 6;
+    let pts = if flags & 0x02 != 0 {
+        data.get(9..14).map(timestamp)
+    } else {
+        None
+    };
+    let dts = if flags == 0x03 {
+        data.get(14..19).map(timestamp)
+    } else {
+        pts
+    };
+    let payload = data
+        .get(9 + data[8] as usize..)
+        .context("Invalid PES header in the video stream")?;
+    Ok(Pes {
+        pts,
+        dts,
+        data: payload.to_vec(),
+    })
+}
+
+/// A 33 bit timestamp of a PES header.
+fn timestamp(bytes: &[u8]) -> u64 {
+    ((bytes[0] as u64 >> 1) & 0x07) << 30
+        | (bytes[1] as u64) << 22
+        | (bytes[2] as u64 >> 1) << 15
+        | (bytes[3] as u64) << 7
+        | bytes[4] as u64 >> 1
+}
+
+const TIMESTAMP_WRAP: u64 = 1 << 33;
+
+/// Difference of two 33 bit timestamps that may have wrapped around.
+fn timestamp_diff(later: u64, earlier: u64) -> u64 {
+    later.wrapping_sub(earlier) % TIMESTAMP_WRAP
+}
+
+/// The frames of an H.264 stream with one access unit per PES packet, and
+/// the presentation time of the first frame. Frames before the first
+/// keyframe cannot be decoded and are left out.
+fn video_track(packets: &[Pes]) -> Result<(VideoTrack, u64)> {
+    let mut sps: Option<Vec<u8>> = None;
+    let mut pps: Option<Vec<u8>> = None;
+    // Decoding time relative to the first frame, composition offset and
+    // sample.
+    let mut frames: Vec<(u64, u32, Sample)> = Vec::new();
+    let mut first: Option<(u64, u64)> = None;
+    for pes in packets {
+        let mut data = Vec::new();
+        let mut sync = false;
+        let mut picture = false;
+        for unit in nal_units(&pes.data) {
+            match unit[0] & 0x1f {
+                7 => {
+                    sps.get_or_insert_with(|| unit.to_vec());
+                    continue;
+                }
+                8 => {
+                    pps.get_or_insert_with(|| unit.to_vec());
+                    continue;
+                }
+                // Access unit delimiter.
+                9 => continue,
+                5 => {
+                    sync = true;
+                    picture = true;
+                }
+                1..=4 => picture = true,
+                _ => {}
+            }
+            data.extend_from_slice(&(unit.len() as u32).to_be_bytes());
+            data.extend_from_slice(unit);
+        }
+        match (pes.dts, pes.pts) {
+            (Some(dts), Some(pts)) if picture => {
+                let (first_dts, _) = *first.get_or_insert((dts, pts));
+                frames.push((
+                    timestamp_diff(dts, first_dts),
+                    timestamp_diff(pts, dts) as u32,
+                    Sample {
+                        data,
+                        duration: 0,
+                        composition_offset: 0,
+                        sync,
+                    },
+                ));
+            }
+            // An access unit that continues in the next PES packet.
+            _ => {
+                if let Some((_, _, sample)) = frames.last_mut() {
+                    sample.data.extend(data);
+                    sample.sync |= sync;
+                }
+            }
+        }
+    }
+    let keyframe = frames
+        .iter()
+        .position(|(_, _, sample)| sample.sync)
+        .ok_or_else(|| Unconvertible("The video stream has no H.264 keyframe".to_string()))?;
+    frames.drain(..keyframe);
+    let (sps, pps) = sps
+        .zip(pps)
+        .ok_or_else(|| Unconvertible("The video stream has no H.264 parameter sets".to_string()))?;
+    let (width, height) = sps_dimensions(&sps)?;
+
+    let start = first.map(|(dts, _)| dts).unwrap_or_default() + frames[0].0 + frames[0].1 as u64;
+    let decode_times: Vec<u64> = frames.iter().map(|(dts, _, _)| *dts).collect();
+    let samples = frames
+        .into_iter()
+        .enumerate()
+        .map(|(i, (dts, offset, sample))| {
+            // The last frame is shown as long as the one before.
+            let duration = match decode_times.get(i + 1) {
+                Some(next) => next.saturating_sub(dts),
+                None if i > 0 => dts.saturating_sub(decode_times[i - 1]),
+                None => VIDEO_TIMESCALE as u64 / 30,
+            };
+            Sample {
+                duration: duration as u32,
+                composition_offset: offset,
+                ..sample
+            }
+        })
+        .collect();
+    Ok((
+        VideoTrack {
+            width,
+            height,
+            sps,
+            pps,
+            samples,
+            delay: 0.,
+        },
+        start,
+    ))
+}
+
+/// The NAL units of an H.264 byte stream without their start codes.
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            if let Some(start) = start {
+                units.push(trim_zeros(&data[start..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(trim_zeros(&data[start..]));
+    }
+    units.retain(|unit| !unit.is_empty());
+    units
+}
+
+/// Removes the zero bytes in front of the next start code.
+fn trim_zeros(mut unit: &[u8]) -> &[u8] {
+    while let [rest @ .., 0] = unit {
+        unit = rest;
+    }
+    unit
+}
+
+/// Width and height of the pictures of an H.264 sequence parameter set.
+fn sps_dimensions(sps: &[u8]) -> Result<(u16, u16)> {
+    // Remove the emulation prevention bytes after two zero bytes.
+    let mut rbsp = Vec::with_capacity(sps.len());
+    let mut zeros = 0;
+    for &byte in sps.get(1..).unwrap_or_default() {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    let mut bits = BitReader::new(&rbsp);
+    let profile = bits.bits(8)?;
+    // Constraint flags, level and ID.
+    bits.bits(16)?;
+    bits.ue()?;
+    let mut chroma_format = 1;
+    let mut separate_planes = false;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+        chroma_format = bits.ue()?;
+        if chroma_format == 3 {
+            separate_planes = bits.bit()?;
+        }
+        // Bit depths and transform bypass.
+        bits.ue()?;
+        bits.ue()?;
+        bits.bit()?;
+        if bits.bit()? {
+            for list in 0..if chroma_format == 3 { 12 } else { 8 } {
+                if bits.bit()? {
+                    skip_scaling_list(&mut bits, if list < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    bits.ue()?;
+    match bits.ue()? {
+        0 => {
+            bits.ue()?;
+        }
+        1 => {
+            bits.bit()?;
+            bits.se()?;
+            bits.se()?;
+            for _ in 0..bits.ue()? {
+                bits.se()?;
+            }
+        }
+        _ => {}
+    }
+    // Reference frames and gaps.
+    bits.ue()?;
+    bits.bit()?;
+    let width_in_macroblocks = bits.ue()? as u64 + 1;
+    let height_in_map_units = bits.ue()? as u64 + 1;
+    let frame_macroblocks_only = bits.bit()?;
+    if !frame_macroblocks_only {
+        bits.bit()?;
+    }
+    bits.bit()?;
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if bits.bit()? {
+        crop_x = bits.ue()? as u64 + bits.ue()? as u64;
+        crop_y = bits.ue()? as u64 + bits.ue()? as u64;
+    }
+
+    let field_factor = if frame_macroblocks_only { 1 } else { 2 };
+    let (crop_unit_x, crop_unit_y) = match (separate_planes, chroma_format) {
+        (false, 1) => (2, 2 * field_factor),
+        (false, 2) => (2, field_factor),
+        _ => (1, field_factor),
+    };
+    let width = (width_in_macroblocks * 16).checked_sub(crop_unit_x * crop_x);
+    let height = (field_factor * height_in_map_units * 16).checked_sub(crop_unit_y * crop_y);
+    match (width, height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => Ok((
+            u16::try_from(width).context("The video is too wide")?,
+            u16::try_from(height).context("The video is too high")?,
+        )),
+        _ => bail!("Invalid picture size in the H.264 sequence parameter set"),
+    }
+}
+
+fn skip_scaling_list(bits: &mut BitReader, size: usize) -> Result<()> {
+    let (mut last, mut next) = (8i64, 8i64);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + bits.se()? as i64 + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the bits and Exp-Golomb codes of an H.264 parameter set.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn bit(&mut self) -> Result<bool> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .context("Unexpected end of the H.264 sequence parameter set")?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while !self.bit()? {
+            zeros += 1;
+            if zeros > 31 {
+                bail!("Invalid Exp-Golomb code in the H.264 sequence parameter set");
+            }
+        }
+        Ok((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Result<i32> {
+        let value = self.ue()? as i64;
+        Ok(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -(value / 2)
+        } as i32)
+    }
+}
+
+/// The AAC frames of ADTS packets and the presentation time of the first
+/// frame. The audio is left out if the stream has none.
+fn audio_track(packets: &[Pes]) -> Result<Option<(AudioTrack, u64)>> {
+    let Some(start) = packets.iter().find_map(|pes| pes.pts) else {
+        return Ok(None);
+    };
+    let mut track: Option<AudioTrack> = None;
+    for pes in packets {
+        let mut rest = pes.data.as_slice();
+        while rest.len() >= 7 {
+            if rest[0] != 0xff || rest[1] & 0xf0 != 0xf0 {
+                bail!(Unconvertible(
+                    "The audio of the video stream is not in ADTS frames".to_string()
+                ));
+            }
+            let header_length = if rest[1] & 0x01 == 1 { 7 } else { 9 };
+            let object_type = (rest[2] >> 6) + 1;
+            let rate_index = (rest[2] >> 2) & 0x0f;
+            let channels = (rest[2] & 0x01) << 2 | rest[3] >> 6;
+            let length =
+                (rest[3] as usize & 0x03) << 11 | (rest[4] as usize) << 3 | rest[5] as usize >> 5;
+            if length < header_length {
+                bail!("Invalid ADTS frame in the video stream");
+            }
+            // The last frame of a cut stream may be incomplete.
+            let Some(frame) = rest.get(header_length..length) else {
+                break;
+            };
+            if rest[6] & 0x03 != 0 {
+                bail!(Unconvertible(
+                    "ADTS frames with several AAC blocks cannot be converted without ffmpeg"
+                        .to_string()
+                ));
+            }
+            let sample_rate = *AAC_SAMPLE_RATES
+                .get(rate_index as usize)
+                .context("Invalid AAC sample rate in the video stream")?;
+            let track = track.get_or_insert_with(|| AudioTrack {
+                config: vec![
+                    object_type << 3 | rate_index >> 1,
+                    (rate_index & 0x01) << 7 | channels << 3,
+                ],
+                sample_rate,
+                channels: channels as u16,
+                samples: Vec::new(),
+                delay: 0.,
+            });
+            track.samples.push(Sample {
+                data: frame.to_vec(),
+                duration: AAC_FRAME_SAMPLES,
+                composition_offset: 0,
+                sync: true,
+            });
+            rest = &rest[length..];
+        }
+    }
+    Ok(track.map(|track| (track, start)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the fields of an H.264 parameter set.
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u32) {
+            for bit in (0..count).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |=
+                    ((value >> bit & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn ue(&mut self, value: u32) {
+            let code = value + 1;
+            let length = 32 - code.leading_zeros();
+            self.bits(0, length - 1);
+            self.bits(code, length);
+        }
+    }
+
+    /// A baseline SPS for 640x360 pixels, coded as 640x368 with cropping.
+    fn sps() -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.bits(0x67, 8);
+        bits.bits(66, 8);
+        bits.bits(0xc0, 8);
+        bits.bits(30, 8);
+        bits.ue(0);
+        bits.ue(0);
+        // Picture order count type 2.
+        bits.ue(2);
+        bits.ue(1);
+        bits.bits(0, 1);
+        bits.ue(39);
+        bits.ue(22);
+        bits.bits(1, 1);
+        bits.bits(1, 1);
+        // Crop 8 lines at the bottom.
+        bits.bits(1, 1);
+        bits.ue(0);
+        bits.ue(0);
+        bits.ue(0);
+        bits.ue(4);
+        bits.bits(0, 1);
+        bits.bits(1, 1);
+        bits.bytes
+    }
+
+    fn pes(stream_id: u8, pts: u64, dts: Option<u64>, payload: &[u8]) -> Vec<u8> {
+        let encode = |prefix: u8, value: u64| {
+            [
+                prefix << 4 | ((value >> 29) as u8 & 0x0e) | 1,
+                (value >> 22) as u8,
+                ((value >> 14) as u8 & 0xfe) | 1,
+                (value >> 7) as u8,
+                ((value << 1) as u8) | 1,
+            ]
+        };
+        let mut header = vec![0x80];
+        match dts {
+            Some(dts) => {
+                header.extend([0xc0, 10]);
+                header.extend(encode(3, pts));
+                header.extend(encode(1, dts));
+            }
+            None => {
+                header.extend([0x80, 5]);
+                header.extend(encode(2, pts));
+            }
+        }
+        let mut pes = vec![0, 0, 1, stream_id, 0, 0];
+        pes.extend(header);
+        pes.extend(payload);
+        pes
+    }
+
+    /// Splits a PES packet into transport stream packets, padded with an
+    /// adaptation field.
+    fn ts_packets(pid: u16, data: &[u8]) -> Vec<u8> {
+        let mut packets = Vec::new();
+        for (index, chunk) in data.chunks(184).enumerate() {
+            let start = if index == 0 { 0x40 } else { 0 };
+            packets.extend([TS_SYNC_BYTE, start | (pid >> 8) as u8, pid as u8]);
+            if chunk.len() < 184 {
+                let stuffing = 184 - chunk.len();
+                packets.push(0x30);
+                packets.push(stuffing as u8 - 1);
+                if stuffing > 1 {
+                    packets.push(0);
+                    packets.extend(vec![0xff; stuffing - 2]);
+                }
+            } else {
+                packets.push(0x10);
+            }
+            packets.extend(chunk);
+        }
+        packets
+    }
+
+    fn psi(table_id: u8, content: &[u8]) -> Vec<u8> {
+        let length = content.len() + 4;
+        let mut section = vec![0, table_id, 0xb0 | (length >> 8) as u8, length as u8];
+        section.extend(content);
+        // The CRC is not checked.
+        section.extend([0; 4]);
+        section
+    }
+
+    /// A transport stream with two seconds of video at 25 frames per second
+    /// and AAC audio that starts a tenth of a second later.
+    fn transport_stream() -> Vec<u8> {
+        let mut stream = ts_packets(0, &psi(0, &[0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00]));
+        stream.extend(ts_packets(
+            0x1000,
+            &psi(
+                2,
+                &[
+                    0,
+                    1,
+                    0xc1,
+                    0,
+                    0,
+                    0xe1,
+                    0x00,
+                    0xf0,
+                    0,
+                    STREAM_TYPE_H264,
+                    0xe1,
+                    0x00,
+                    0xf0,
+                    0,
+                    STREAM_TYPE_AAC,
+                    0xe1,
+                    0x01,
+                    0xf0,
+                    0,
+                ],
+            ),
+        ));
+        let start = 126_000;
+        for frame in 0..50u64 {
+            let mut access_unit = vec![0, 0, 0, 1, 0x09, 0xf0];
+            if frame % 25 == 0 {
+                access_unit.extend([0, 0, 0, 1]);
+                access_unit.extend(sps());
+                access_unit.extend([0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80]);
+                access_unit.extend([0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x33]);
+            } else {
+                access_unit.extend([0, 0, 1, 0x41, 0x9a, 0x02, 0x00, 0x03, 0x01]);
+            }
+            let dts = start + frame * 3_600;
+            stream.extend(ts_packets(
+                0x100,
+                &pes(0xe0, dts + 7_200, Some(dts), &access_unit),
+            ));
+        }
+        for packet in 0..10u64 {
+            let mut frames = Vec::new();
+            for _ in 0..9 {
+                let length = 7 + 20;
+                // AAC LC, 44.1 kHz, stereo.
+                frames.extend([
+                    0xff,
+                    0xf1,
+                    0x50,
+                    0x80 | (length >> 11) as u8,
+                    (length >> 3) as u8,
+                    ((length as u8) << 5) | 0x1f,
+                    0xfc,
+                ]);
+                frames.extend([0x21; 20]);
+            }
+            let pts = start + 9_000 + packet * 9 * 1024 * 90_000 / 44_100;
+            stream.extend(ts_packets(0x101, &pes(0xc0, pts, None, &frames)));
+        }
+        stream
+    }
+
+    #[test]
+    fn transport_streams_are_remuxed_to_mp4() {
+        let streams = demux_ts(&transport_stream()).unwrap();
+        assert_eq!(streams.video.len(), 50);
+        assert_eq!(streams.audio.len(), 10);
+
+        let (video, start) = video_track(&streams.video).unwrap();
+        assert_eq!((video.width, video.height), (640, 360));
+        assert_eq!(start, 126_000 + 7_200);
+        assert_eq!(video.samples.len(), 50);
+        assert!(video.samples[0].sync && !video.samples[1].sync);
+        assert_eq!(video.samples[1].duration, 3_600);
+        assert_eq!(video.samples[1].composition_offset, 7_200);
+        // Parameter sets and delimiters are not part of the frames.
+        assert_eq!(
+            video.samples[0].data,
+            [0, 0, 0, 5, 0x65, 0x88, 0x84, 0x00, 0x33]
+        );
+
+        let (audio, audio_start) = audio_track(&streams.audio).unwrap().unwrap();
+        assert_eq!(audio_start, 126_000 + 9_000);
+        assert_eq!(audio.config, [0x12, 0x10]);
+        assert_eq!((audio.sample_rate, audio.channels), (44_100, 2));
+        assert_eq!(audio.samples.len(), 90);
+
+        let file = remux_ts(&transport_stream()).unwrap();
+        let probe = mp4::probe(&file).unwrap();
+        assert_eq!(probe.dimensions, Some((640, 360)));
+        assert!((probe.duration - 2.1).abs() < 0.05, "{}", probe.duration);
+    }
+
+    #[test]
+    fn other_codecs_cannot_be_remuxed() {
+        let mut stream = ts_packets(0, &psi(0, &[0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00]));
+        // HEVC video.
+        stream.extend(ts_packets(
+            0x1000,
+            &psi(
+                2,
+                &[
+                    0, 1, 0xc1, 0, 0, 0xe1, 0x00, 0xf0, 0, 0x24, 0xe1, 0x00, 0xf0, 0,
+                ],
+            ),
+        ));
+        let error = remux_ts(&stream).unwrap_err();
+        assert!(error.is::<Unconvertible>());
+        assert!(error.to_string().contains("0x24"), "{error}");
+
+        let error = remux_ts(b"not a video").unwrap_err();
+        assert!(error.is::<Unconvertible>());
+    }
+
+    #[test]
+    fn the_best_variant_is_chosen() {
+        let base = Url::parse("https://video.bsky.app/watch/did/cid/playlist.m3u8").unwrap();
+        let playlist = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=500000,RESOLUTION=640x360,CODECS=\"avc1.64001e,mp4a.40.2\"
+360p/video.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1500000,RESOLUTION=1280x720,CODECS=\"avc1.64001f,mp4a.40.2\"
+720p/video.m3u8
+";
+        assert_eq!(
+            best_variant(playlist, &base),
+            Some(base.join("720p/video.m3u8").unwrap())
+        );
+        assert_eq!(
+            best_variant("#EXTM3U\n#EXTINF:4.0,\nvideo0.ts\n", &base),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_over_the_download_size_are_not_converted() {
+        use clap::Parser;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/video.m3u8"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "#EXTM3U\n#EXTINF:4.0,\nvideo0.ts\n#EXTINF:4.0,\nvideo1.ts\n#EXTINF:4.0,\nvideo2.ts\n",
+            ))
+            .mount(&server)
+            .await;
+        // Each segment is below the limit of 1 MiB, all of them together are not.
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 600 * 1024]))
+            .mount(&server)
+            .await;
+        let args =
+            crate::args::Args::parse_from(["mastodon-bluesky-sync", "--max-download-size", "1"]);
+        let policy = crate::config::FetchConfig {
+            allow_private_networks: true,
+            ..Default::default()
+        };
+        let http = WebClient::new(&args.http, &policy).unwrap();
+
+        let error = download_stream_mp4(&http, &format!("{}/video.m3u8", server.uri()))
+            .await
+            .unwrap_err();
+        assert!(error.is::<Unconvertible>());
+        assert!(
+            error.to_string().contains("maximum download size of 1 MiB"),
+            "{error}"
+        );
+        let segments = server.received_requests().await.unwrap();
+        assert_eq!(segments.len(), 3, "the last segment is not downloaded");
+    }
+
+    #[tokio::test]
+    async fn fragmented_mp4_streams_are_not_converted() {
+        use clap::Parser;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/video.m3u8"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:4.0,\nvideo0.m4s\n",
+            ))
+            .mount(&server)
+            .await;
+        let policy = crate::config::FetchConfig {
+            allow_private_networks: true,
+            ..Default::default()
+        };
+        let args = crate::args::Args::parse_from(["mastodon-bluesky-sync"]);
+        let http = WebClient::new(&args.http, &policy).unwrap();
+
+        let error = download_stream_mp4(&http, &format!("{}/video.m3u8", server.uri()))
+            .await
+            .unwrap_err();
+        assert!(error.is::<Unconvertible>());
+        assert!(error.to_string().contains("fragmented MP4"), "{error}");
+        // No segments are downloaded.
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result, bail};
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, IntoUrl, Response};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+use crate::args::HttpOptions;
+use crate::config::FetchConfig;
+use crate::fetch_policy::{PublicResolver, check_proxied_host, check_url};
+
+/// Environment variables that reqwest takes a proxy from.
+const PROXY_VARIABLES: [&str; 6] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+];
+
+/// Redirects that are followed when downloading from URLs of posts.
+const MAX_REDIRECTS: usize = 5;
+
+/// Sent with every request so that server admins can tell where the traffic
+/// comes from.
+pub const USER_AGENT: &str = concat!(
+    "mastodon-bluesky-sync/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/klausi/mastodon-bluesky-sync)"
+);
+
+/// The HTTP client shared by all downloads and Bluesky API requests of a run,
+/// so that connections are reused and every request has the same timeouts.
+/// URLs from posts are downloaded with a second client that follows the
+/// fetch policy of the config.
+#[derive(Clone, Debug)]
+pub struct WebClient {
+    client: Client,
+    content: Client,
+    policy: Arc<FetchConfig>,
+    /// Hosts are resolved and checked before the request because the proxy
+    /// resolves them itself.
+    check_proxied_hosts: bool,
+    max_download_size: u64,
+}
+
+impl WebClient {
+    pub fn new(options: &HttpOptions, policy: &FetchConfig) -> Result<Self> {
+        let builder = || {
+            Client::builder()
+                .user_agent(USER_AGENT)
+                .connect_timeout(Duration::from_secs(options.connect_timeout))
+                .read_timeout(Duration::from_secs(options.timeout))
+        };
+        let client = builder()
+            .build()
+            .context("Failed to create the HTTP client")?;
+        // Redirects are followed by download() to check every URL.
+        let mut content: ClientBuilder = builder().redirect(Policy::none());
+        let proxied = PROXY_VARIABLES
+            .iter()
+            .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()));
+        // The proxy itself may be in a private network, so it is resolved as
+        // usual when there is one.
+        if !policy.allow_private_networks && !proxied {
+            content = content.dns_resolver(Arc::new(PublicResolver));
+        }
+        let content = content
+            .build()
+            .context("Failed to create the HTTP client")?;
+        Ok(WebClient {
+            client,
+            content,
+            policy: Arc::new(policy.clone()),
+            check_proxied_hosts: proxied && !policy.allow_private_networks,
+            max_download_size: options.max_download_size * 1024 * 1024,
+        })
+    }
+
+    /// The underlying client, for API clients that send their own requests.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Largest download in bytes.
+    pub fn max_download_size(&self) -> u64 {
+        self.max_download_size
+    }
+
+    /// Sends a GET request. The status is not checked.
+    pub async fn get(&self, url: impl IntoUrl) -> reqwest::Result<Response> {
+        self.client.get(url).send().await
+    }
+
+    /// Sends a GET request to a URL from a post, for example an attachment.
+    /// Addresses in private networks are refused unless the config allows
+    /// them. The status is not checked.
+    pub async fn download(&self, url: &str) -> Result<Response> {
+        self.fetch(url, false).await
+    }
+
+    /// Like [`WebClient::download`], for the page of a link preview, which is
+    /// also checked against the domain lists of the config.
+    pub async fn download_link_preview(&self, url: &str) -> Result<Response> {
+        self.fetch(url, true).await
+    }
+
+    async fn fetch(&self, url: &str, link_preview: bool) -> Result<Response> {
+        let mut url = Url::parse(url).with_context(|| format!("Invalid URL {url}"))?;
+        for _ in 0..=MAX_REDIRECTS {
+            check_url(&url, &self.policy, link_preview)?;
+            if self.check_proxied_hosts {
+                check_proxied_host(&url).await?;
+            }
+            let response = self
+                .content
+                .get(url.clone())
+                .send()
+                .await
+                .with_context(|| format!("Failed requesting {url}"))?;
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if response.status().is_redirection() => {
+                    url = url
+                        .join(location)
+                        .with_context(|| format!("Invalid redirect from {url} to {location}"))?;
+                }
+                _ => return Ok(response),
+            }
+        }
+        bail!("Not following more than {MAX_REDIRECTS} redirects to {url}")
+    }
+
+    /// Reads the body of a response, or fails if it is larger than the
+    /// maximum download size.
+    pub async fn bytes(&self, mut response: Response) -> Result<Vec<u8>> {
+        let url = response.url().clone();
+        let too_large = || {
+            format!(
+                "{url} is larger than the maximum download size of {} MiB",
+                self.max_download_size / 1024 / 1024
+            )
+        };
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_download_size)
+        {
+            bail!(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed reading {url}"))?
+        {
+            if (body.len() + chunk.len()) as u64 > self.max_download_size {
+                bail!(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Reads the body of a response as text, see [`WebClient::bytes`].
+    pub async fn text(&self, response: Response) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes(response).await?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(policy: &FetchConfig) -> WebClient {
+        let args = crate::args::Args::parse_from(["mastodon-bluesky-sync"]);
+        WebClient::new(&args.http, policy).unwrap()
+    }
+
+    #[tokio::test]
+    async fn downloads_are_limited_and_identified() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("user-agent", USER_AGENT))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 1024 * 1024 + 1]))
+            .mount(&server)
+            .await;
+        let mut http = client(&FetchConfig::default());
+
+        let response = http.get(server.uri()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(http.bytes(response).await.unwrap().len(), 1024 * 1024 + 1);
+
+        http.max_download_size = 1024 * 1024;
+        let response = http.get(server.uri()).await.unwrap();
+        let error = http.bytes(response).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("larger than the maximum download size of 1 MiB")
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_from_posts_follow_the_fetch_policy() {
+        let server = MockServer::start().await;
+        let port = server.address().port();
+        Mock::given(path("/redirect"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", format!("http://localhost:{port}/page")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/page"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("page"))
+            .mount(&server)
+            .await;
+
+        let http = client(&FetchConfig::default());
+        let error = http.download(&server.uri()).await.unwrap_err();
+        assert!(format!("{error:#}").contains("127.0.0.1 is not a public address"));
+        let error = http
+            .download(&format!("http://localhost:{port}/page"))
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("localhost does not resolve to a public address"));
+
+        let http = client(&FetchConfig {
+            allow_private_networks: true,
+            link_preview_denied_domains: vec!["localhost".to_string()],
+            ..Default::default()
+        });
+        let redirect = format!("{}/redirect", server.uri());
+        let response = http.download(&redirect).await.unwrap();
+        assert_eq!(http.text(response).await.unwrap(), "page");
+        // The redirect target of a link preview is checked as well.
+        let error = http.download_link_preview(&redirect).await.unwrap_err();
+        assert!(error.to_string().contains("the domain is denied"));
+    }
+}
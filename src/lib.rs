@@ -1,131 +1,466 @@
 use anyhow::Context;
 use anyhow::Result;
-use atrium_xrpc_client::reqwest::ReqwestClient;
+use anyhow::anyhow;
 use bsky_sdk::agent::BskyAtpAgentBuilder;
 use bsky_sdk::agent::config::FileStore;
-use bsky_sdk::api::types::LimitedNonZeroU8;
-use delete_posts::bluesky_delete_older_posts;
+use bsky_sdk::api::app::bsky::feed::defs::FeedViewPost;
 use log::debug;
+use megalodon::Megalodon;
+use megalodon::entities::{Account, Status};
 use megalodon::generator;
-use megalodon::megalodon::GetAccountStatusesInputOptions;
-use std::process;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
 
 use crate::args::*;
+use crate::bluesky_network::BlueskyNetwork;
+use crate::bluesky_oauth::OAuthAuthorizer;
 use crate::config::*;
+use crate::delete_favs::delete_older_likes;
+use crate::delete_posts::delete_older_posts;
+use crate::http::{USER_AGENT, WebClient};
+use crate::lock::{Lock, StateLock};
+use crate::mastodon_network::{MastodonNetwork, http_status};
+use crate::media::{media_tools, warn_about_missing_tools};
+use crate::metrics::*;
+use crate::network::SocialNetwork;
 use crate::post::*;
-use crate::registration::bluesky_register;
-use crate::registration::mastodon_register;
+use crate::post_cache::PostCache;
+use crate::queue::*;
+use crate::registration::*;
+use crate::report::*;
+use crate::retry::*;
+use crate::secret::Secret;
+use crate::state_dir::{state_dir_prepare, write_atomic, write_atomic_private};
 use crate::sync::*;
 
 pub mod args;
+mod bluesky_network;
+mod bluesky_oauth;
 mod bluesky_richtext;
 mod bluesky_video;
+mod captions;
 mod config;
 mod delete_favs;
 mod delete_posts;
+#[cfg(test)]
+mod fake_network;
+mod fetch_policy;
+mod hls;
+mod http;
+mod lock;
 mod mastodon_html;
+mod mastodon_network;
+mod media;
+pub mod metrics;
+mod mp4;
+mod network;
 mod post;
+mod post_cache;
+mod queue;
 mod registration;
+pub mod report;
+mod retry;
+pub mod secret;
+mod state_dir;
 mod sync;
 
-type BskyAgent = bsky_sdk::BskyAgent<atrium_xrpc_client::reqwest::ReqwestClient>;
+type BskyAgent = bsky_sdk::BskyAgent<RetryClient>;
 
-pub async fn run(args: Args) -> Result<()> {
-    debug!("running with args {:?}", args);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mastodon,
+    Bluesky,
+}
 
-    let config = match fs::read_to_string(&args.config).await {
-        Ok(config) => config_load(&config)?,
-        Err(_) => {
-            let mastodon_config = mastodon_register()
-                .await
-                .context("Failed to setup mastodon account")?;
-            let bluesky_config = bluesky_register()
-                .await
-                .context("Failed to setup Bluesky account")?;
-            let config = Config {
-                mastodon: mastodon_config,
-                bluesky: bluesky_config,
-            };
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mastodon => write!(f, "Mastodon"),
+            Network::Bluesky => write!(f, "Bluesky"),
+        }
+    }
+}
 
-            // Save config for using on the next run.
-            let toml = toml::to_string(&config)?;
-            let mut file = File::create(&args.config)
-                .await
-                .context("Failed to create config file")?;
-            file.write_all(toml.as_bytes()).await?;
+/// Errors returned by [`run`]. Each variant maps to a documented exit code,
+/// see [`Error::exit_code`].
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is missing, invalid or could not be written.
+    Config(anyhow::Error),
+    /// Logging in or verifying the credentials failed.
+    Auth(Network, anyhow::Error),
+    /// Fetching the timeline failed.
+    Fetch(Network, anyhow::Error),
+    /// Some posts could not be synced, they are queued for the next run.
+    Post(anyhow::Error),
+    /// Deleting old posts or favourites failed.
+    Delete(anyhow::Error),
+    /// A cache file is corrupted or could not be written.
+    Cache(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// Exit code of the program for this error.
+    ///
+    /// - 1: other errors
+    /// - 2: fetching toots from Mastodon failed
+    /// - 3: fetching posts from Bluesky failed
+    /// - 4: authentication failed
+    /// - 5: posting failed
+    /// - 6: deleting old data failed
+    /// - 7: configuration error
+    /// - 8: cache error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Fetch(Network::Mastodon, _) => 2,
+            Error::Fetch(Network::Bluesky, _) => 3,
+            Error::Auth(_, _) => 4,
+            Error::Post(_) => 5,
+            Error::Delete(_) => 6,
+            Error::Config(_) => 7,
+            Error::Cache(_) => 8,
+        }
+    }
+
+    /// Short name of the error kind, used as metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Auth(Network::Mastodon, _) => "auth_mastodon",
+            Error::Auth(Network::Bluesky, _) => "auth_bluesky",
+            Error::Fetch(Network::Mastodon, _) => "fetch_mastodon",
+            Error::Fetch(Network::Bluesky, _) => "fetch_bluesky",
+            Error::Post(_) => "post",
+            Error::Delete(_) => "delete",
+            Error::Cache(_) => "cache",
+            Error::Other(_) => "other",
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            Error::Config(e)
+            | Error::Auth(_, e)
+            | Error::Fetch(_, e)
+            | Error::Post(e)
+            | Error::Delete(e)
+            | Error::Cache(e)
+            | Error::Other(e) => e,
+        }
+    }
+}
 
-            config
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(_) => write!(f, "Configuration error"),
+            Error::Auth(network, _) => write!(f, "Authentication with {network} failed"),
+            Error::Fetch(network, _) => write!(f, "Fetching posts from {network} failed"),
+            Error::Post(_) => write!(f, "Posting failed"),
+            Error::Delete(_) => write!(f, "Deleting old data failed"),
+            Error::Cache(_) => write!(f, "Cache error"),
+            Error::Other(e) => write!(f, "{e}"),
         }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // The message of other errors is already displayed directly.
+            Error::Other(e) => e.source(),
+            _ => Some(self.inner().as_ref()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        Error::Other(error)
+    }
+}
+
+pub async fn run(mut args: Args) -> Result<(), Error> {
+    debug!("running with args {:?}", args);
+    set_output_format(args.output);
+
+    let config_state_dir = match fs::read_to_string(&args.config).await {
+        Ok(config) => config_state_dir(&config),
+        Err(_) => None,
     };
+    let state_dir = state_dir_prepare(args.state_dir.as_deref(), config_state_dir.as_deref())
+        .await
+        .map_err(Error::Cache)?;
+    args.state_dir = Some(state_dir);
+    // Commands without a config do not download anything from posts.
+    let http = WebClient::new(&args.http, &FetchConfig::default()).map_err(Error::Config)?;
 
-    let mastodon = generator(
-        megalodon::SNS::Mastodon,
-        config.mastodon.base_url.clone(),
-        Some(config.mastodon.access_token.clone()),
-        None,
-    )?;
-    let account = match mastodon.verify_account_credentials().await {
-        Ok(account) => account,
+    if let Some(Command::Queue { action }) = &args.command {
+        // Only changes to the queue need to wait for a running sync.
+        let _lock = match action {
+            QueueCommand::List => None,
+            QueueCommand::Drop { .. } => match lock_state(&args).await? {
+                Some(lock) => Some(lock),
+                None => return Ok(()),
+            },
+        };
+        return queue_command(action, args.state_dir(), args.output).await;
+    }
+
+    if let Some(Command::Login { network }) = &args.command {
+        return login(network, &args, &http).await;
+    }
+
+    if let Some(Command::Init {
+        force,
+        mastodon,
+        bluesky,
+    }) = &args.command
+    {
+        return init(*force, mastodon, bluesky, &args, &http).await;
+    }
+
+    let mut config = match fs::read_to_string(&args.config).await {
+        Ok(config) => config_parse(&config, &args.config).await?,
         Err(e) => {
-            eprintln!("Error connecting to Mastodon: {e:#?}");
-            process::exit(1);
+            return Err(Error::Config(anyhow::Error::from(e).context(format!(
+                "Failed to read config file {}, run `mastodon-bluesky-sync init` to create it",
+                args.config
+            ))));
         }
     };
-    // Get most recent 50 toots, exclude replies for now.
-    let mastodon_statuses = match mastodon
-        .get_account_statuses(
-            account.json.id,
-            Some(&GetAccountStatusesInputOptions {
-                limit: Some(50),
-                pinned: Some(false),
-                exclude_replies: Some(true),
-                exclude_reblogs: Some(!config.mastodon.sync_reblogs),
-                only_public: Some(true),
-                ..Default::default()
-            }),
-        )
+
+    let http = WebClient::new(&args.http, &config.fetch).map_err(Error::Config)?;
+
+    if let Some(Command::Check) = &args.command {
+        return check(&args, &mut config, &http).await;
+    }
+
+    warn_about_missing_tools().await;
+    if args.daemon {
+        return run_daemon(&args, &mut config, &http).await;
+    }
+
+    let Some(_lock) = lock_state(&args).await? else {
+        return Ok(());
+    };
+    let report = sync_once(&args, &mut config, &Metrics::new(), &http).await?;
+    report.print(args.output)?;
+    let failed = report.failed_count();
+    if failed > 0 {
+        return Err(Error::Post(anyhow!(
+            "{failed} posts failed to sync, they are queued for the next run"
+        )));
+    }
+    Ok(())
+}
+
+/// Locks the state directory for one run. Returns None if another run still
+/// holds the lock, this run is skipped then.
+async fn lock_state(args: &Args) -> Result<Option<StateLock>, Error> {
+    let wait = Duration::from_secs(args.wait_for_lock);
+    match StateLock::acquire(args.state_dir(), wait)
         .await
+        .map_err(Error::Cache)?
     {
-        Ok(statuses) => statuses.json,
-        Err(e) => {
-            eprintln!("Error fetching toots from Mastodon: {e:#?}");
-            process::exit(2);
+        Lock::Acquired(lock) => Ok(Some(lock)),
+        Lock::Busy(owner) => {
+            eprintln!(
+                "{owner} is still using the state directory {}, skipping this run",
+                args.state_dir()
+            );
+            Ok(None)
         }
+    }
+}
+
+/// Parses the config file and reads the referenced credentials.
+async fn config_parse(config: &str, config_file: &str) -> Result<Config, Error> {
+    for deprecation in config_deprecations(config) {
+        eprintln!("Warning: {deprecation}");
+    }
+    let mut config = config_load(config)
+        .context(format!("Failed to load config file {config_file}"))
+        .map_err(Error::Config)?;
+    config_validate(&config)
+        .context(format!("Failed to load config file {config_file}"))
+        .map_err(Error::Config)?;
+    config_resolve_secrets(&mut config)
+        .await
+        .context(format!("Failed to read the credentials of {config_file}"))
+        .map_err(Error::Config)?;
+    Ok(config)
+}
+
+/// Moves new credentials to the secret store chosen on the command line.
+async fn store_secrets(config: &mut Config, args: &Args) -> Result<(), Error> {
+    config_store_secrets(
+        config,
+        args.secret_store,
+        &state_file(args.state_dir(), "secrets"),
+    )
+    .await
+    .context("Failed to store the credentials")
+    .map_err(Error::Config)
+}
+
+/// Replaces a credential where it is stored. If that is not possible, for
+/// example for environment variables, it is moved to the secret store. New
+/// credentials go to the secret store as well.
+async fn update_secret(
+    secret: &mut Secret,
+    name: &str,
+    value: String,
+    args: &Args,
+) -> Result<(), Error> {
+    let update = match secret.is_empty() {
+        true => Err(None),
+        false => secret.update(value.clone()).await.map_err(Some),
     };
+    if let Err(e) = update {
+        if let Some(e) = e {
+            eprintln!("Warning: {e:#}");
+        }
+        *secret = Secret::inline(value);
+        secret
+            .store(
+                args.secret_store,
+                name,
+                &state_file(args.state_dir(), "secrets"),
+            )
+            .await
+            .context("Failed to store the credentials")
+            .map_err(Error::Config)?;
+    }
+    Ok(())
+}
 
-    // First try to login with a cached access token.
-    let bsky_agent =
-        match bsky_sdk::agent::config::Config::load(&FileStore::new("bluesky-auth-cache.json"))
+async fn config_save(config: &Config, config_file: &str) -> Result<()> {
+    let toml = toml::to_string(config)?;
+    write_atomic(config_file, toml.as_bytes())
+        .await
+        .context("Failed to write config file")
+}
+
+/// Keeps synchronizing in an endless loop, optionally serving Prometheus
+/// metrics and a health check endpoint.
+async fn run_daemon(args: &Args, config: &mut Config, http: &WebClient) -> Result<(), Error> {
+    let metrics = Arc::new(Metrics::new());
+    let interval = Duration::from_secs(args.interval);
+    if let Some(address) = &args.metrics_listen {
+        let listener = TcpListener::bind(address)
             .await
-        {
-            Ok(bsky_config) => {
-                match BskyAtpAgentBuilder::new(ReqwestClient::new("https://bsky.social"))
-                    .config(bsky_config)
-                    .build()
-                    .await
-                {
-                    Ok(agent) => {
-                        // Save the session in case it was refreshed.
-                        agent
-                            .to_config()
-                            .await
-                            .save(&FileStore::new("bluesky-auth-cache.json"))
-                            .await?;
-                        agent
-                    }
-                    Err(_) => {
-                        get_new_bluesky_agent(&config.bluesky.email, &config.bluesky.app_password)
-                            .await?
-                    }
-                }
+            .context(format!("Failed to listen on {address} for metrics"))?;
+        // Allow a few failed runs before reporting the sync as unhealthy.
+        let max_age = Duration::from_secs(args.health_max_age.unwrap_or(args.interval * 3));
+        progress!("Serving metrics and health check on http://{address}");
+        tokio::spawn(serve_metrics(listener, metrics.clone(), max_age));
+    }
+
+    loop {
+        let Some(lock) = lock_state(args).await? else {
+            tokio::time::sleep(interval).await;
+            continue;
+        };
+        let result = sync_once(args, config, &metrics, http).await;
+        drop(lock);
+        match result {
+            Ok(report) => {
+                metrics.record_run(&report);
+                report.print(args.output)?;
             }
-            Err(_) => {
-                get_new_bluesky_agent(&config.bluesky.email, &config.bluesky.app_password).await?
+            Err(err) => {
+                metrics.record_failure(err.kind());
+                eprintln!("Error: {err}: {:#}", err.inner());
             }
-        };
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Runs one synchronization of both accounts. The config is updated and saved
+/// when the Mastodon access token had to be refreshed.
+async fn sync_once(
+    args: &Args,
+    config: &mut Config,
+    metrics: &Metrics,
+    http: &WebClient,
+) -> Result<RunReport, Error> {
+    let (client, account) = mastodon_connect(args, config, metrics, http).await?;
+    let mastodon = MastodonNetwork::new(
+        client,
+        http.clone(),
+        account.id,
+        config.mastodon.sync_reblogs,
+        config.media.clone(),
+    );
+
+    let (bsky_agent, bsky_session) = bluesky_connect(config, args.state_dir(), http).await?;
+    let bluesky = BlueskyNetwork::new(
+        bsky_agent,
+        http.clone(),
+        bsky_session.did.clone(),
+        config.bluesky.video_service_url.clone(),
+        config.media.clone(),
+    );
+
+    sync_networks(args, config, args.state_dir(), &mastodon, &bluesky, metrics).await
+}
+
+/// Verifies the credentials of both accounts and prints which features the
+/// config enables, without syncing.
+async fn check(args: &Args, config: &mut Config, http: &WebClient) -> Result<(), Error> {
+    let (_, account) = mastodon_connect(args, config, &Metrics::new(), http).await?;
+    let (_, session) = bluesky_connect(config, args.state_dir(), http).await?;
+    let bluesky_auth = match config.bluesky.auth {
+        BlueskyAuth::AppPassword => "app password",
+        BlueskyAuth::OAuth => "OAuth",
+    };
+    let tools = media_tools().await;
+    let mut features = CheckReport::features(config);
+    features.push(CheckFeature {
+        name: "Convert media with ffmpeg".to_string(),
+        enabled: tools.ffmpeg,
+    });
+    features.push(CheckFeature {
+        name: "Read videos with ffprobe".to_string(),
+        enabled: tools.ffprobe,
+    });
+    let report = CheckReport {
+        config_file: args.config.clone(),
+        state_dir: args.state_dir().to_string(),
+        mastodon_account: format!("@{} on {}", account.acct, config.mastodon.base_url),
+        bluesky_account: format!(
+            "{} ({}) with {bluesky_auth}",
+            session.handle.as_str(),
+            session.did.as_str()
+        ),
+        features,
+    };
+    report.print(args.output)?;
+    Ok(())
+}
+
+/// Logs in to Bluesky and checks the session.
+async fn bluesky_connect(
+    config: &Config,
+    state_dir: &str,
+    http: &WebClient,
+) -> Result<
+    (
+        BskyAgent,
+        bsky_sdk::api::com::atproto::server::get_session::Output,
+    ),
+    Error,
+> {
+    let bsky_agent = bluesky_login(config, state_dir, http)
+        .await
+        .map_err(|e| Error::Auth(Network::Bluesky, e))?;
     let bsky_session = bsky_agent
         .api
         .com
@@ -133,121 +468,931 @@ pub async fn run(args: Args) -> Result<()> {
         .server
         .get_session()
         .await
-        .context("Error getting Bluesky session")?;
-    let bsky_statuses = match bsky_agent
-        .api
-        .app
-        .bsky
-        .feed
-        .get_author_feed(
-            bsky_sdk::api::app::bsky::feed::get_author_feed::ParametersData {
-                actor: bsky_session.did.clone().into(),
-                cursor: None,
-                filter: None,
-                include_pins: None,
-                limit: Some(LimitedNonZeroU8::try_from(50).unwrap()),
-            }
-            .into(),
+        .context("Error getting Bluesky session")
+        .map_err(|e| Error::Auth(Network::Bluesky, e))?;
+    Ok((bsky_agent, bsky_session))
+}
+
+/// Creates a Mastodon client and verifies its credentials. If the access token
+/// was revoked or expired it is refreshed with the refresh token.
+async fn mastodon_connect(
+    args: &Args,
+    config: &mut Config,
+    metrics: &Metrics,
+    http: &WebClient,
+) -> Result<(Box<dyn Megalodon + Send + Sync>, Account), Error> {
+    let (client, result) = mastodon_verify(&config.mastodon, metrics).await?;
+    let error = match result {
+        Ok(account) => return Ok((client, account)),
+        Err(error) if http_status(&error) == Some(401) => error,
+        Err(error) => {
+            return Err(Error::Auth(
+                Network::Mastodon,
+                anyhow::Error::from(error).context("Error connecting to Mastodon"),
+            ));
+        }
+    };
+
+    let login_hint = "run `mastodon-bluesky-sync login mastodon` to authorize again";
+    if !has_refresh_token(&config.mastodon) {
+        return Err(Error::Auth(
+            Network::Mastodon,
+            anyhow::Error::from(error).context(format!(
+                "The Mastodon access token was rejected and there is no refresh token, {login_hint}"
+            )),
+        ));
+    }
+    progress!("The Mastodon access token was rejected, refreshing it");
+    let token = mastodon_refresh_token(&config.mastodon, http)
+        .await
+        .with_context(|| format!("Refreshing the Mastodon access token failed, {login_hint}"))
+        .map_err(|e| Error::Auth(Network::Mastodon, e))?;
+    update_secret(
+        &mut config.mastodon.access_token,
+        "mastodon-access-token",
+        token.access_token,
+        args,
+    )
+    .await?;
+    // Some servers issue a new refresh token with every refresh.
+    if let Some(refresh_token) = token.refresh_token {
+        update_secret(
+            &mut config.mastodon.refresh_token,
+            "mastodon-refresh-token",
+            refresh_token,
+            args,
         )
+        .await?;
+    }
+    config_save(config, &args.config)
         .await
-    {
-        Ok(statuses) => statuses.feed.clone(),
+        .context("Failed to save the refreshed Mastodon access token")
+        .map_err(Error::Config)?;
+
+    let (client, result) = mastodon_verify(&config.mastodon, metrics).await?;
+    let account = result
+        .with_context(|| {
+            format!("Error connecting to Mastodon after refreshing the access token, {login_hint}")
+        })
+        .map_err(|e| Error::Auth(Network::Mastodon, e))?;
+    Ok((client, account))
+}
+
+/// Verifies the credentials of the Mastodon config and returns the account
+/// on success.
+async fn mastodon_verify(
+    config: &MastodonConfig,
+    metrics: &Metrics,
+) -> Result<
+    (
+        Box<dyn Megalodon + Send + Sync>,
+        Result<Account, megalodon::error::Error>,
+    ),
+    Error,
+> {
+    let client = generator(
+        megalodon::SNS::Mastodon,
+        config.base_url.clone(),
+        Some(config.access_token.expose().to_string()),
+        Some(USER_AGENT.to_string()),
+    )
+    .context("Invalid Mastodon base URL")
+    .map_err(Error::Config)?;
+    let result = metrics
+        .time_api(
+            "mastodon",
+            "verify_credentials",
+            mastodon_retry("verify credentials", true, || {
+                client.verify_account_credentials()
+            }),
+        )
+        .await
+        .map(|account| account.json);
+    Ok((client, result))
+}
+
+/// Creates the config file by logging in to both networks. Credentials
+/// given on the command line are used as they are, the rest is asked for.
+async fn init(
+    force: bool,
+    mastodon: &MastodonLogin,
+    bluesky: &BlueskyLogin,
+    args: &Args,
+    http: &WebClient,
+) -> Result<(), Error> {
+    if !force && fs::try_exists(&args.config).await.unwrap_or(false) {
+        return Err(Error::Config(anyhow!(
+            "Config file {} already exists, use `login` to log in again or --force to overwrite it",
+            args.config
+        )));
+    }
+    let mastodon_config = mastodon_register(mastodon, None)
+        .await
+        .context("Failed to setup mastodon account")
+        .map_err(|e| Error::Auth(Network::Mastodon, e))?;
+    let bluesky_config = bluesky_register(bluesky, None, args.state_dir(), http)
+        .await
+        .context("Failed to setup Bluesky account")
+        .map_err(|e| Error::Auth(Network::Bluesky, e))?;
+    let mut config = Config {
+        state_dir: None,
+        mastodon: mastodon_config,
+        bluesky: bluesky_config,
+        fetch: FetchConfig::default(),
+        media: MediaConfig::default(),
+    };
+
+    store_secrets(&mut config, args).await?;
+    config_save(&config, &args.config)
+        .await
+        .map_err(Error::Config)?;
+    progress!("Created config file {}", args.config);
+    Ok(())
+}
+
+/// Logs in to one of the networks again and stores the new credentials in
+/// the existing config file.
+async fn login(network: &LoginCommand, args: &Args, http: &WebClient) -> Result<(), Error> {
+    let config_file = &args.config;
+    let mut config = match fs::read_to_string(config_file).await {
+        Ok(config) => config_parse(&config, config_file).await?,
         Err(e) => {
-            eprintln!("Error fetching posts from Bluesky: {e:#?}");
-            process::exit(3);
+            return Err(Error::Config(anyhow::Error::from(e).context(format!(
+                "Failed to read config file {config_file}, run `mastodon-bluesky-sync init` to create it"
+            ))));
         }
     };
+    match network {
+        LoginCommand::Mastodon(login) => {
+            let authorized = mastodon_register(login, Some(&config.mastodon.base_url))
+                .await
+                .context("Failed to authorize on Mastodon")
+                .map_err(|e| Error::Auth(Network::Mastodon, e))?;
+            config.mastodon.base_url = authorized.base_url;
+            config.mastodon.client_id = authorized.client_id;
+            let mastodon = &mut config.mastodon;
+            for (secret, name, new) in [
+                (
+                    &mut mastodon.client_secret,
+                    "mastodon-client-secret",
+                    authorized.client_secret,
+                ),
+                (
+                    &mut mastodon.access_token,
+                    "mastodon-access-token",
+                    authorized.access_token,
+                ),
+                (
+                    &mut mastodon.refresh_token,
+                    "mastodon-refresh-token",
+                    authorized.refresh_token,
+                ),
+            ] {
+                update_secret(secret, name, new.expose().to_string(), args).await?;
+            }
+        }
+        LoginCommand::Bluesky(login) => {
+            let registered = bluesky_register(
+                login,
+                Some(&config.bluesky.service_url),
+                args.state_dir(),
+                http,
+            )
+            .await
+            .context("Failed to log in to Bluesky")
+            .map_err(|e| Error::Auth(Network::Bluesky, e))?;
+            config.bluesky.auth = registered.auth;
+            config.bluesky.service_url = registered.service_url;
+            config.bluesky.email = registered.email;
+            match registered.app_password.is_empty() {
+                // The app password is not needed anymore with OAuth.
+                true => config.bluesky.app_password = Secret::default(),
+                false => {
+                    update_secret(
+                        &mut config.bluesky.app_password,
+                        "bluesky-app-password",
+                        registered.app_password.expose().to_string(),
+                        args,
+                    )
+                    .await?
+                }
+            }
+        }
+    }
+    config_save(&config, config_file)
+        .await
+        .map_err(Error::Config)?;
+    progress!("Saved the new credentials to {config_file}");
+    Ok(())
+}
+
+/// Synchronizes posts between the two networks and deletes old data. Cache
+/// files are kept in the given directory.
+async fn sync_networks<M, B>(
+    args: &Args,
+    config: &Config,
+    state_dir: &str,
+    mastodon: &M,
+    bluesky: &B,
+    metrics: &Metrics,
+) -> Result<RunReport, Error>
+where
+    M: SocialNetwork<Post = Status>,
+    B: SocialNetwork<Post = FeedViewPost>,
+{
+    let mut report = RunReport::new(args.dry_run);
+
+    let (mastodon_statuses, bsky_statuses) = tokio::join!(
+        metrics.time_api("mastodon", "fetch_timeline", mastodon.fetch_timeline()),
+        metrics.time_api("bluesky", "fetch_timeline", bluesky.fetch_timeline()),
+    );
+    let mastodon_statuses = mastodon_statuses
+        .context("Error fetching toots from Mastodon")
+        .map_err(|e| Error::Fetch(Network::Mastodon, e))?;
+    let bsky_statuses = bsky_statuses
+        .context("Error fetching posts from Bluesky")
+        .map_err(|e| Error::Fetch(Network::Bluesky, e))?;
 
     let options = SyncOptions {
         sync_reblogs: config.mastodon.sync_reblogs,
         sync_reposts: config.bluesky.sync_reposts,
-        sync_hashtag_mastodon: config.mastodon.sync_hashtag,
-        sync_hashtag_bluesky: config.bluesky.sync_hashtag,
+        sync_hashtag_mastodon: config.mastodon.sync_hashtag.clone(),
+        sync_hashtag_bluesky: config.bluesky.sync_hashtag.clone(),
     };
 
     let mut posts = determine_posts(&mastodon_statuses, &bsky_statuses, &options);
 
     // Prevent double posting with a post cache that records each new status
     // message.
-    let post_cache_file = &cache_file("post_cache.json");
-    let mut post_cache = read_post_cache(post_cache_file);
-    let mut cache_changed = false;
+    let post_cache_file = &state_file(state_dir, "post_cache.json");
+    let mut post_cache = PostCache::load(post_cache_file).map_err(Error::Cache)?;
+    let mut cache_changed = post_cache.prune(&mastodon_statuses, &bsky_statuses);
+    for toot in posts.toots.iter().filter(|t| post_cache.contains(&t.text)) {
+        report.skipped(Direction::BlueskyToMastodon, toot, "already in post cache");
+    }
+    for post in posts
+        .bsky_posts
+        .iter()
+        .filter(|p| post_cache.contains(&p.text))
+    {
+        report.skipped(Direction::MastodonToBluesky, post, "already in post cache");
+    }
     posts = filter_posted_before(posts, &post_cache)?;
 
-    for toot in posts.toots {
-        if !args.skip_existing_posts
-            && let Err(e) = post_to_mastodon(&*mastodon, &toot, args.dry_run).await
-        {
-            eprintln!("Error posting toot to Mastodon: {e:#?}");
+    // Posts that failed on previous runs are tried again first.
+    let queue_file = &state_file(state_dir, "post_queue.json");
+    let queued = read_post_queue(queue_file).map_err(Error::Cache)?;
+    let queue_changed = !queued.is_empty();
+    let mut queue = Vec::new();
+    // Directions and texts of queued posts that were sent now, they might
+    // still be in the fetched timeline.
+    let mut retried = Vec::new();
+    for mut item in queued {
+        if post_cache.contains(&item.status.text) {
             continue;
         }
-        // Posting API call was successful: store text in cache to prevent any
-        // double posting next time.
-        if !args.dry_run {
-            post_cache.insert(toot.text);
-            cache_changed = true;
+        if item.given_up {
+            queue.push(item);
+            continue;
+        }
+        // The post might have been stored although sending it failed, for
+        // example on a timeout. Its replies are continued then.
+        let on_target = match item.direction {
+            Direction::BlueskyToMastodon => find_toot(&mastodon_statuses, &item.status.text),
+            Direction::MastodonToBluesky => find_bsky_post(&bsky_statuses, &item.status.text),
+        };
+        if let Some(target_id) = on_target {
+            item.posted.entry(String::new()).or_insert(target_id);
+            if item.status.replies.is_empty() {
+                report.skipped(item.direction, &item.status, "already posted");
+                if !args.dry_run {
+                    post_cache.insert(item.status.text);
+                    cache_changed = true;
+                }
+                continue;
+            }
+        }
+        let mut posted = item.posted.clone();
+        match post_status(
+            item.direction,
+            &item.status,
+            mastodon,
+            bluesky,
+            args,
+            metrics,
+            &mut posted,
+        )
+        .await
+        {
+            Ok(target_id) => {
+                report.posted(item.direction, &item.status, target_id);
+                retried.push((item.direction, item.status.text.clone()));
+                if !args.dry_run {
+                    post_cache.insert(item.status.text);
+                    cache_changed = true;
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Error posting queued post {} to {} (attempt {}): {e:#?}",
+                    item.id,
+                    item.direction.target(),
+                    item.attempts + 1
+                );
+                report.failed(item.direction, &item.status, &e);
+                if !args.dry_run {
+                    item.posted = posted;
+                    item.failed_again(&e);
+                    if item.given_up {
+                        eprintln!(
+                            "Giving up on queued post {} after {} attempts, drop it with `queue drop {}`",
+                            item.id, item.attempts, item.id
+                        );
+                    }
+                }
+                queue.push(item);
+            }
         }
     }
 
-    for post in posts.bsky_posts {
-        if !args.skip_existing_posts
-            && let Err(e) = post_to_bluesky(&bsky_agent, &post, args.dry_run).await
-        {
-            eprintln!("Error posting to Bluesky: {e:#?}");
+    let new_posts = posts
+        .toots
+        .into_iter()
+        .map(|status| (Direction::BlueskyToMastodon, status))
+        .chain(
+            posts
+                .bsky_posts
+                .into_iter()
+                .map(|status| (Direction::MastodonToBluesky, status)),
+        );
+    for (direction, status) in new_posts {
+        if retried.contains(&(direction, status.text.clone())) {
+            continue;
+        }
+        if args.skip_existing_posts {
+            report.skipped(direction, &status, "skip existing posts");
+        } else if let Some(queued) = find_queued(&queue, direction, &status) {
+            let reason = match queued.given_up {
+                true => "given up after failed attempts",
+                false => "queued for retry",
+            };
+            report.skipped(direction, &status, reason);
             continue;
+        } else {
+            let mut posted = ThreadProgress::new();
+            match post_status(
+                direction,
+                &status,
+                mastodon,
+                bluesky,
+                args,
+                metrics,
+                &mut posted,
+            )
+            .await
+            {
+                Ok(target_id) => report.posted(direction, &status, target_id),
+                Err(e) => {
+                    eprintln!("Error posting to {}: {e:#?}", direction.target());
+                    report.failed(direction, &status, &e);
+                    // Keep the post in the outbox so that it is not lost if it
+                    // drops out of the fetched timeline until the next run.
+                    if !args.dry_run {
+                        let mut item = QueuedPost::new(direction, status, &e);
+                        item.posted = posted;
+                        queue.push(item);
+                    }
+                    continue;
+                }
+            }
         }
         // Posting API call was successful: store text in cache to prevent any
         // double posting next time.
         if !args.dry_run {
-            post_cache.insert(post.text);
+            post_cache.insert(status.text);
             cache_changed = true;
         }
     }
 
+    if !args.dry_run && (queue_changed || !queue.is_empty()) {
+        save_post_queue(queue_file, &queue)
+            .await
+            .map_err(Error::Cache)?;
+    }
+
     // Write out the cache file if necessary.
     if !args.dry_run && cache_changed {
-        let json = serde_json::to_string_pretty(&post_cache)?;
-        fs::write(post_cache_file, json.as_bytes()).await?;
+        post_cache
+            .save(post_cache_file)
+            .await
+            .map_err(Error::Cache)?;
     }
 
     if config.bluesky.delete_old_posts {
-        bluesky_delete_older_posts(&bsky_agent, args.dry_run)
+        report.deletions.bluesky_posts = Some(
+            delete_older_posts(
+                bluesky,
+                &state_file(state_dir, "bluesky_cache.json"),
+                args.dry_run,
+            )
             .await
-            .context("Failed to delete old Bluesky posts")?;
+            .context("Failed to delete old Bluesky posts")
+            .map_err(delete_error)?,
+        );
     }
 
     if config.mastodon.delete_old_favs {
-        delete_favs::mastodon_delete_older_favs(&*mastodon, args.dry_run)
+        report.deletions.mastodon_favs = Some(
+            delete_older_likes(
+                mastodon,
+                &state_file(state_dir, "mastodon_fav_cache.json"),
+                &state_file(state_dir, "mastodon_fav_cursor_cache.json"),
+                args.dry_run,
+            )
             .await
-            .context("Failed to delete old Mastodon favourites")?;
+            .context("Failed to delete old Mastodon favourites")
+            .map_err(delete_error)?,
+        );
     }
 
     if config.bluesky.delete_old_favs {
-        delete_favs::bluesky_delete_older_favs(&bsky_agent, args.dry_run)
+        report.deletions.bluesky_favs = Some(
+            delete_older_likes(
+                bluesky,
+                &state_file(state_dir, "bluesky_like_cache.json"),
+                &state_file(state_dir, "bluesky_like_cursor_cache.json"),
+                args.dry_run,
+            )
             .await
-            .context("Failed to delete old Bluesky favourites")?;
+            .context("Failed to delete old Bluesky favourites")
+            .map_err(delete_error)?,
+        );
     }
 
+    report.finish();
+
+    Ok(report)
+}
+
+/// Deletion errors caused by unreadable date cache files are cache errors.
+fn delete_error(error: anyhow::Error) -> Error {
+    if error.downcast_ref::<serde_json::Error>().is_some() {
+        Error::Cache(error)
+    } else {
+        Error::Delete(error)
+    }
+}
+
+/// Lists or drops entries of the post queue.
+async fn queue_command(
+    action: &QueueCommand,
+    state_dir: &str,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let queue_file = &state_file(state_dir, "post_queue.json");
+    let mut queue = read_post_queue(queue_file).map_err(Error::Cache)?;
+    match action {
+        QueueCommand::List => match output {
+            OutputFormat::Text => println!("{}", format_queue(&queue)),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&queue).map_err(|e| Error::Other(e.into()))?
+            ),
+        },
+        QueueCommand::Drop { ids, all } => {
+            let before = queue.len();
+            let ids = if *all { Vec::new() } else { ids.clone() };
+            let (dropped, missing) = drop_from_queue(&mut queue, &ids);
+            if !missing.is_empty() {
+                return Err(Error::Other(anyhow!(
+                    "Posts not found in the queue: {}",
+                    missing.join(", ")
+                )));
+            }
+            // Dropped posts that are still in the source timeline would be
+            // synced again on the next run, the post cache prevents that.
+            if !dropped.is_empty() {
+                let post_cache_file = &state_file(state_dir, "post_cache.json");
+                let mut post_cache = PostCache::load(post_cache_file).map_err(Error::Cache)?;
+                for queued in dropped {
+                    post_cache.insert(queued.status.text);
+                }
+                post_cache
+                    .save(post_cache_file)
+                    .await
+                    .map_err(Error::Cache)?;
+            }
+            save_post_queue(queue_file, &queue)
+                .await
+                .map_err(Error::Cache)?;
+            println!("Dropped {} posts from the queue.", before - queue.len());
+        }
+    }
     Ok(())
 }
 
-/// Returns the full path for a cache file name.
-fn cache_file(name: &str) -> String {
-    if let Ok(cache_dir) = std::env::var("MBS_CACHE_DIR") {
-        return format!("{cache_dir}/{name}");
+/// Posts a new status with its replies to the network of the given direction.
+/// The parts of the thread that were posted are recorded in `posted`.
+async fn post_status<M: SocialNetwork, B: SocialNetwork>(
+    direction: Direction,
+    status: &NewStatus,
+    mastodon: &M,
+    bluesky: &B,
+    args: &Args,
+    metrics: &Metrics,
+    posted: &mut ThreadProgress,
+) -> Result<Option<String>> {
+    let (dry_run, parallel) = (args.dry_run, args.parallel_requests.into());
+    match direction {
+        Direction::BlueskyToMastodon => {
+            let post = post_thread(mastodon, status, dry_run, parallel, posted);
+            metrics.time_api("mastodon", "post", post).await
+        }
+        Direction::MastodonToBluesky => {
+            let post = post_thread(bluesky, status, dry_run, parallel, posted);
+            metrics.time_api("bluesky", "post", post).await
+        }
+    }
+}
+
+/// Returns the full path for a file in the state directory.
+fn state_file(state_dir: &str, name: &str) -> String {
+    format!("{state_dir}/{name}")
+}
+
+/// Logs in to Bluesky, preferably with the cached session.
+async fn bluesky_login(config: &Config, state_dir: &str, http: &WebClient) -> Result<BskyAgent> {
+    if config.bluesky.auth == BlueskyAuth::OAuth {
+        return bluesky_oauth_agent(&bluesky_oauth_session_file(state_dir), http).await;
+    }
+    let session_file = state_file(state_dir, "bluesky-auth-cache.json");
+    // First try to login with a cached access token.
+    if let Ok(bsky_config) =
+        bsky_sdk::agent::config::Config::load(&FileStore::new(&session_file)).await
+        && let Ok(agent) =
+            BskyAtpAgentBuilder::new(RetryClient::new(&config.bluesky.service_url, http))
+                .config(bsky_config)
+                .build()
+                .await
+    {
+        // Save the session in case it was refreshed.
+        bluesky_session_save(&agent, &session_file).await?;
+        return Ok(agent);
     }
-    name.into()
+    get_new_bluesky_agent(&config.bluesky, &session_file, http).await
 }
 
-async fn get_new_bluesky_agent(email: &str, app_password: &str) -> Result<BskyAgent> {
-    let agent = BskyAtpAgentBuilder::new(ReqwestClient::new("https://bsky.social"))
+/// Creates an agent that authorizes its requests with the OAuth session.
+async fn bluesky_oauth_agent(session_file: &str, http: &WebClient) -> Result<BskyAgent> {
+    let oauth = Arc::new(OAuthAuthorizer::load(session_file, http).await.context(
+        "No Bluesky OAuth session found, run `mastodon-bluesky-sync login bluesky` to log in",
+    )?);
+    let session = oauth.session().await;
+    // The agent sends the access token as bearer token, the client replaces
+    // it with the current DPoP bound token.
+    let atp_session = bsky_sdk::api::com::atproto::server::create_session::OutputData {
+        access_jwt: session.access_token.clone(),
+        active: None,
+        did: session
+            .did
+            .parse()
+            .map_err(|e| anyhow!("Invalid DID: {e}"))?,
+        did_doc: None,
+        email: None,
+        email_auth_factor: None,
+        email_confirmed: None,
+        handle: session
+            .handle
+            .parse()
+            .map_err(|e| anyhow!("Invalid handle: {e}"))?,
+        refresh_jwt: String::new(),
+        status: None,
+    };
+    let agent = BskyAtpAgentBuilder::new(RetryClient::with_oauth(&session.pds_url, http, oauth))
+        .config(bsky_sdk::agent::config::Config {
+            endpoint: session.pds_url.clone(),
+            session: Some(atp_session.into()),
+            ..Default::default()
+        })
         .build()
         .await?;
-    let _session = agent.login(email, app_password).await?;
-    agent
-        .to_config()
-        .await
-        .save(&FileStore::new("bluesky-auth-cache.json"))
+    Ok(agent)
+}
+
+/// The OAuth tokens are kept next to the other cache files.
+fn bluesky_oauth_session_file(state_dir: &str) -> String {
+    state_file(state_dir, "bluesky-oauth-session.json")
+}
+
+async fn get_new_bluesky_agent(
+    config: &BlueskyConfig,
+    session_file: &str,
+    http: &WebClient,
+) -> Result<BskyAgent> {
+    let agent = BskyAtpAgentBuilder::new(RetryClient::new(&config.service_url, http))
+        .config(bsky_sdk::agent::config::Config {
+            endpoint: config.service_url.clone(),
+            ..Default::default()
+        })
+        .build()
+        .await?;
+    let _session = agent
+        .login(&config.email, config.app_password.expose())
         .await?;
+    bluesky_session_save(&agent, session_file).await?;
     Ok(agent)
 }
+
+/// Caches the tokens of an app password login. Written like
+/// [`FileStore`] does, but atomically and only readable by the user.
+async fn bluesky_session_save(agent: &BskyAgent, session_file: &str) -> Result<()> {
+    let json = serde_json::to_string_pretty(&agent.to_config().await)?;
+    write_atomic_private(session_file, json.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_network::FakeNetwork;
+    use clap::Parser;
+
+    fn read_fixture<T: serde::de::DeserializeOwned>(file_name: &str) -> T {
+        serde_json::from_str(&std::fs::read_to_string(file_name).unwrap()).unwrap()
+    }
+
+    fn test_config() -> Config {
+        config_load(
+            r#"
+            [mastodon]
+            base_url = "https://mastodon.example"
+            client_id = ""
+            client_secret = ""
+            access_token = ""
+            refresh_token = ""
+
+            [bluesky]
+            email = ""
+            app_password = ""
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sync_run_posts_new_posts_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["mastodon-bluesky-sync"]);
+        let config = test_config();
+        let mastodon =
+            FakeNetwork::new(vec![read_fixture::<Status>("tests/mastodon_long_url.json")]);
+        let bluesky = FakeNetwork::new(vec![read_fixture::<FeedViewPost>(
+            "tests/bsky_long_url.json",
+        )]);
+
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 0);
+        assert_eq!(mastodon.created().len(), 1);
+        assert_eq!(bluesky.created().len(), 1);
+        assert_eq!(
+            report.count(Direction::MastodonToBluesky, ActionStatus::Posted),
+            1
+        );
+
+        // The fake timelines don't change, the post cache prevents posting
+        // the same posts again.
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(mastodon.created().len(), 1);
+        assert_eq!(bluesky.created().len(), 1);
+        assert_eq!(
+            report.count(Direction::MastodonToBluesky, ActionStatus::Skipped),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_posts_are_queued_and_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["mastodon-bluesky-sync"]);
+        let config = test_config();
+        let mastodon =
+            FakeNetwork::new(vec![read_fixture::<Status>("tests/mastodon_long_url.json")]);
+        let bluesky = FakeNetwork::<FeedViewPost>::new(Vec::new());
+        bluesky.fail_posts(true);
+
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 1);
+        let queue = read_post_queue(&state_file(state_dir, "post_queue.json")).unwrap();
+        assert_eq!(queue.len(), 1);
+
+        bluesky.fail_posts(false);
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 0);
+        assert_eq!(bluesky.created().len(), 1);
+        assert!(
+            read_post_queue(&state_file(state_dir, "post_queue.json"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// A Bluesky post with the given text.
+    fn bsky_post_with_text(text: &str) -> FeedViewPost {
+        use bsky_sdk::api::types::TryFromUnknown;
+
+        let mut post = read_fixture::<FeedViewPost>("tests/bsky_long_url.json");
+        let mut record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
+            post.post.record.clone(),
+        )
+        .unwrap();
+        record.text = text.to_string();
+        record.facets = None;
+        post.post.record = serde_json::from_value(serde_json::to_value(record).unwrap()).unwrap();
+        post
+    }
+
+    #[tokio::test]
+    async fn queued_post_on_the_target_timeline_is_not_posted_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["mastodon-bluesky-sync"]);
+        let config = test_config();
+        let mastodon =
+            FakeNetwork::new(vec![read_fixture::<Status>("tests/mastodon_long_url.json")]);
+        let bluesky = FakeNetwork::<FeedViewPost>::new(Vec::new());
+        bluesky.fail_posts(true);
+        sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        let queue = read_post_queue(&state_file(state_dir, "post_queue.json")).unwrap();
+
+        // Bluesky stored the post although the request failed.
+        let bluesky = FakeNetwork::new(vec![bsky_post_with_text(&queue[0].status.text)]);
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 0);
+        assert!(bluesky.created().is_empty());
+        assert!(
+            read_post_queue(&state_file(state_dir, "post_queue.json"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_post_is_given_up_after_max_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["mastodon-bluesky-sync"]);
+        let config = test_config();
+        let mastodon =
+            FakeNetwork::new(vec![read_fixture::<Status>("tests/mastodon_long_url.json")]);
+        let bluesky = FakeNetwork::<FeedViewPost>::new(Vec::new());
+        bluesky.fail_posts(true);
+        for _ in 0..MAX_ATTEMPTS {
+            let report = sync_networks(
+                &args,
+                &config,
+                state_dir,
+                &mastodon,
+                &bluesky,
+                &Metrics::new(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(report.failed_count(), 1);
+        }
+        let queue = read_post_queue(&state_file(state_dir, "post_queue.json")).unwrap();
+        assert!(queue[0].given_up);
+
+        bluesky.fail_posts(false);
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 0);
+        assert!(bluesky.created().is_empty());
+        let queue = read_post_queue(&state_file(state_dir, "post_queue.json")).unwrap();
+        assert_eq!(queue[0].attempts, MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn dropped_posts_are_not_synced_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["mastodon-bluesky-sync"]);
+        let config = test_config();
+        let mastodon =
+            FakeNetwork::new(vec![read_fixture::<Status>("tests/mastodon_long_url.json")]);
+        let bluesky = FakeNetwork::<FeedViewPost>::new(Vec::new());
+        bluesky.fail_posts(true);
+        sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+
+        let drop = QueueCommand::Drop {
+            ids: Vec::new(),
+            all: true,
+        };
+        queue_command(&drop, state_dir, OutputFormat::Text)
+            .await
+            .unwrap();
+
+        bluesky.fail_posts(false);
+        let report = sync_networks(
+            &args,
+            &config,
+            state_dir,
+            &mastodon,
+            &bluesky,
+            &Metrics::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed_count(), 0);
+        assert!(bluesky.created().is_empty());
+    }
+
+    #[test]
+    fn error_exit_codes_and_causes() {
+        let error = Error::Fetch(
+            Network::Bluesky,
+            anyhow!("connection refused").context("Error fetching posts from Bluesky"),
+        );
+        assert_eq!(error.exit_code(), 3);
+        assert_eq!(error.to_string(), "Fetching posts from Bluesky failed");
+        let cause = std::error::Error::source(&error).unwrap();
+        assert_eq!(cause.to_string(), "Error fetching posts from Bluesky");
+        assert_eq!(cause.source().unwrap().to_string(), "connection refused");
+
+        let error = Error::Other(anyhow!("something else"));
+        assert_eq!(error.exit_code(), 1);
+        assert_eq!(error.to_string(), "something else");
+        assert!(std::error::Error::source(&error).is_none());
+    }
+}
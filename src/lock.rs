@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Name of the lock file in the state directory.
+const LOCK_FILE: &str = "mastodon-bluesky-sync.lock";
+
+/// Written into the lock file to tell other runs who holds the lock.
+#[derive(Debug, Serialize, Deserialize)]
+struct LockOwner {
+    pid: u32,
+    started_at: DateTime<Utc>,
+}
+
+/// Advisory lock on the state directory, so that overlapping runs, for example
+/// from cron, do not read the same caches and post twice.
+///
+/// The operating system releases the lock when the process ends. A lock file
+/// that still names an owner but is not locked was left by a crashed run and
+/// is taken over.
+pub struct StateLock {
+    file: File,
+}
+
+pub enum Lock {
+    Acquired(StateLock),
+    /// Another run holds the lock, described for the user.
+    Busy(String),
+}
+
+impl StateLock {
+    /// Locks the state directory, waiting up to the given time for another
+    /// run to finish.
+    pub async fn acquire(state_dir: &str, wait: Duration) -> Result<Lock> {
+        let path = Path::new(state_dir).join(LOCK_FILE);
+        let deadline = Instant::now() + wait;
+        loop {
+            if let Some(lock) = Self::try_acquire(&path)? {
+                return Ok(Lock::Acquired(lock));
+            }
+            if Instant::now() >= deadline {
+                return Ok(Lock::Busy(describe_owner(&path)));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    fn try_acquire(path: &Path) -> Result<Option<StateLock>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+
+        let mut previous = String::new();
+        file.read_to_string(&mut previous)?;
+        if let Ok(owner) = serde_json::from_str::<LockOwner>(&previous) {
+            eprintln!(
+                "Taking over the stale lock of process {} that started at {}",
+                owner.pid, owner.started_at
+            );
+        }
+        let owner = LockOwner {
+            pid: std::process::id(),
+            started_at: Utc::now(),
+        };
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(serde_json::to_string(&owner)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(Some(StateLock { file }))
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // The file itself stays, removing it would let a waiting run lock the
+        // removed file while a third run creates a new one. An empty file
+        // marks a clean exit. Closing the file releases the lock.
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn describe_owner(path: &Path) -> String {
+    match std::fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<LockOwner>(&json).ok())
+    {
+        Some(owner) => format!(
+            "Another run (process {}, started at {})",
+            owner.pid, owner.started_at
+        ),
+        None => "Another run".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn second_run_is_busy_until_the_lock_is_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_str().unwrap();
+
+        let Lock::Acquired(lock) = StateLock::acquire(state_dir, Duration::ZERO).await.unwrap()
+        else {
+            panic!("the state directory should not be locked");
+        };
+        let Lock::Busy(owner) = StateLock::acquire(state_dir, Duration::ZERO).await.unwrap() else {
+            panic!("the state directory should be locked");
+        };
+        assert!(
+            owner.starts_with(&format!("Another run (process {}", std::process::id())),
+            "{owner}"
+        );
+
+        drop(lock);
+        assert!(matches!(
+            StateLock::acquire(state_dir, Duration::ZERO).await.unwrap(),
+            Lock::Acquired(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stale_lock_of_a_crashed_run_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+        std::fs::write(&path, r#"{"pid":1234,"started_at":"2024-01-01T00:00:00Z"}"#).unwrap();
+
+        let lock = StateLock::acquire(dir.path().to_str().unwrap(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(matches!(lock, Lock::Acquired(_)));
+        let owner: LockOwner =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(owner.pid, std::process::id());
+    }
+}
@@ -9,10 +9,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     if let Err(err) = run(args).await {
         eprintln!("Error: {err}");
-        for cause in err.chain().skip(1) {
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
             eprintln!("Because: {cause}");
+            source = cause.source();
         }
-        std::process::exit(1);
+        std::process::exit(err.exit_code());
     }
     Ok(())
 }
@@ -0,0 +1,189 @@
+use anyhow::Result;
+use megalodon::Megalodon;
+use megalodon::entities::Status;
+use megalodon::error::{Error, Kind};
+use megalodon::megalodon::{GetAccountStatusesInputOptions, GetFavouritesInputOptions};
+
+use crate::config::MediaConfig;
+use crate::http::WebClient;
+use crate::network::{DatePage, LikeDeletion, SocialNetwork};
+use crate::post::{mastodon_create_status, mastodon_upload_attachment};
+use crate::retry::mastodon_retry;
+use crate::sync::{NewMedia, NewStatus};
+
+/// A Mastodon account with verified credentials.
+pub struct MastodonNetwork {
+    client: Box<dyn Megalodon + Send + Sync>,
+    http: WebClient,
+    account_id: String,
+    sync_reblogs: bool,
+    media: MediaConfig,
+}
+
+impl MastodonNetwork {
+    pub fn new(
+        client: Box<dyn Megalodon + Send + Sync>,
+        http: WebClient,
+        account_id: String,
+        sync_reblogs: bool,
+        media: MediaConfig,
+    ) -> Self {
+        MastodonNetwork {
+            client,
+            http,
+            account_id,
+            sync_reblogs,
+            media,
+        }
+    }
+}
+
+impl SocialNetwork for MastodonNetwork {
+    type Post = Status;
+    type Media = String;
+
+    fn name(&self) -> &'static str {
+        "Mastodon"
+    }
+
+    async fn fetch_timeline(&self) -> Result<Vec<Status>> {
+        // Get most recent 50 toots, exclude replies for now.
+        let options = GetAccountStatusesInputOptions {
+            limit: Some(50),
+            pinned: Some(false),
+            exclude_replies: Some(true),
+            exclude_reblogs: Some(!self.sync_reblogs),
+            only_public: Some(true),
+            ..Default::default()
+        };
+        let response = mastodon_retry("fetch timeline", true, || {
+            self.client
+                .get_account_statuses(self.account_id.clone(), Some(&options))
+        })
+        .await?;
+        Ok(response.json)
+    }
+
+    async fn upload_media(
+        &self,
+        attachment: &NewMedia,
+        _status: &NewStatus,
+    ) -> Result<Option<String>> {
+        Ok(Some(
+            mastodon_upload_attachment(&*self.client, &self.http, attachment).await?,
+        ))
+    }
+
+    async fn create_post(
+        &self,
+        status: &NewStatus,
+        media: Vec<String>,
+        reply_to: Option<&str>,
+    ) -> Result<String> {
+        mastodon_create_status(
+            &*self.client,
+            &self.http,
+            &self.media,
+            status,
+            media,
+            reply_to,
+        )
+        .await
+    }
+
+    async fn delete_post(&self, id: &str) -> Result<()> {
+        match mastodon_retry("delete status", true, || {
+            self.client.delete_status(id.to_string())
+        })
+        .await
+        {
+            Ok(_) => Ok(()),
+            // The status could have been deleted already by the user.
+            Err(error) if http_status(&error) == Some(404) => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn list_posts(&self, cursor: Option<String>) -> Result<DatePage> {
+        let options = GetAccountStatusesInputOptions {
+            // Maximum number of statuses to get is 40.
+            limit: Some(40),
+            max_id: cursor,
+            ..Default::default()
+        };
+        let response = mastodon_retry("fetch statuses", true, || {
+            self.client
+                .get_account_statuses(self.account_id.clone(), Some(&options))
+        })
+        .await?;
+        Ok(DatePage {
+            cursor: response.json.last().map(|status| status.id.clone()),
+            dates: response
+                .json
+                .into_iter()
+                .map(|status| (status.id, status.created_at))
+                .collect(),
+        })
+    }
+
+    async fn list_likes(&self, cursor: Option<String>) -> Result<DatePage> {
+        let options = GetFavouritesInputOptions {
+            // Maximum number of statuses to get is 40.
+            limit: Some(40),
+            max_id: cursor,
+            min_id: None,
+        };
+        let response = mastodon_retry("fetch favourites", true, || {
+            self.client.get_favourites(Some(&options))
+        })
+        .await?;
+        // Pagination: Parse the Link header to get the next max_id.
+        let cursor = match response.header.get("link") {
+            Some(link) => mastodon_parse_next_max_id(link.to_str()?),
+            None => None,
+        };
+        Ok(DatePage {
+            dates: response
+                .json
+                .iter()
+                .map(|status| (status.id.to_string(), status.created_at))
+                .collect(),
+            cursor,
+        })
+    }
+
+    async fn delete_like(&self, id: &str) -> Result<LikeDeletion> {
+        match mastodon_retry("unfavourite", true, || {
+            self.client.unfavourite_status(id.to_string())
+        })
+        .await
+        {
+            Ok(_) => Ok(LikeDeletion::Deleted),
+            Err(error) => match http_status(&error) {
+                // The status could have been deleted already by the user,
+                // ignore API errors in that case.
+                Some(404) => Ok(LikeDeletion::Deleted),
+                Some(429) => Ok(LikeDeletion::RateLimited),
+                _ => Err(error.into()),
+            },
+        }
+    }
+}
+
+/// HTTP status code of a failed Mastodon API request.
+pub fn http_status(error: &Error) -> Option<u16> {
+    match error {
+        Error::OwnError(own_error) if matches!(own_error.kind, Kind::HTTPStatusError) => {
+            own_error.status
+        }
+        _ => None,
+    }
+}
+
+// Todo: Megalodon should provide API methods for pagination.
+fn mastodon_parse_next_max_id(link_header: &str) -> Option<String> {
+    let re = regex::Regex::new(r#"max_id=(\d+)"#).unwrap();
+    re.captures(link_header)
+        .and_then(|captures| captures.get(1))
+        .map(|max_id| max_id.as_str().to_string())
+}
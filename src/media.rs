@@ -0,0 +1,746 @@
+use anyhow::{Context, Result, bail};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+use std::process::Stdio;
+use tempfile::tempdir;
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use tokio::task::spawn_blocking;
+
+use crate::mp4;
+
+/// Largest image blob that Bluesky accepts.
+pub const BLUESKY_IMAGE_LIMIT: usize = 1_000_000;
+
+/// JPEG qualities that are tried to get below the size limit. Below the
+/// minimum the image is scaled down instead.
+const MIN_QUALITY: u8 = 50;
+const MAX_QUALITY: u8 = 92;
+
+/// Images are not scaled down further than this, to fail instead of posting
+/// a thumbnail.
+const MIN_SIDE: u32 = 256;
+
+/// EXIF tag that points to the GPS data of an image.
+const EXIF_GPS_IFD: u16 = 0x8825;
+
+/// Bitrate of the audio of transcoded videos.
+const AUDIO_BITRATE: u64 = 128_000;
+
+/// Videos are not encoded with less than this to fit the size limit, because
+/// nobody wants to watch them.
+const MIN_VIDEO_BITRATE: u64 = 150_000;
+
+/// Media that cannot be converted, for example because ffmpeg is not
+/// installed. Posts link to the original post instead of failing with it.
+#[derive(Debug)]
+pub struct Unconvertible(pub String);
+
+impl fmt::Display for Unconvertible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unconvertible {}
+
+/// Whether an error means that the media cannot be converted, so retrying
+/// the post does not help.
+pub fn is_unconvertible(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<Unconvertible>().is_some()
+}
+
+/// The external programs for media conversions that are installed.
+#[derive(Debug, Clone, Copy)]
+pub struct MediaTools {
+    pub ffmpeg: bool,
+    pub ffprobe: bool,
+}
+
+static MEDIA_TOOLS: OnceCell<MediaTools> = OnceCell::const_new();
+
+/// Checks once whether ffmpeg and ffprobe can be run.
+pub async fn media_tools() -> MediaTools {
+    *MEDIA_TOOLS
+        .get_or_init(|| async {
+            MediaTools {
+                ffmpeg: can_run("ffmpeg").await,
+                ffprobe: can_run("ffprobe").await,
+            }
+        })
+        .await
+}
+
+async fn can_run(program: &str) -> bool {
+    Command::new(program)
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// Warns at startup about the conversions that are not possible without
+/// ffmpeg or ffprobe.
+pub async fn warn_about_missing_tools() {
+    let tools = media_tools().await;
+    if !tools.ffmpeg {
+        eprintln!(
+            "Warning: ffmpeg is not installed. Bluesky videos are only converted if they are H.264 with AAC audio, audio and videos over the limits link to the original post and animated GIFs are posted as still images."
+        );
+    }
+    if !tools.ffprobe {
+        eprintln!(
+            "Warning: ffprobe is not installed. Only MP4 videos can be checked against the Bluesky limits, other videos link to the original post."
+        );
+    }
+}
+
+async fn require_ffmpeg() -> Result<()> {
+    if !media_tools().await.ffmpeg {
+        bail!(Unconvertible("ffmpeg is not installed".to_string()));
+    }
+    Ok(())
+}
+
+/// Duration and display size of a video.
+#[derive(Debug, PartialEq)]
+pub struct VideoProbe {
+    pub duration: f64,
+    pub dimensions: Option<(u64, u64)>,
+}
+
+/// Reads the duration and size of a video with ffprobe, or from the boxes of
+/// an MP4 file if ffprobe is not installed.
+pub async fn probe_video(bytes: &[u8]) -> Result<VideoProbe> {
+    if !media_tools().await.ffprobe {
+        return mp4::probe(bytes).map_err(|error| {
+            Unconvertible(format!(
+                "ffprobe is not installed and the video cannot be read as MP4: {error:#}"
+            ))
+            .into()
+        });
+    }
+    let temp_dir = tempdir()?;
+    let path = temp_dir.path().join("video");
+    tokio::fs::write(&path, bytes).await?;
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-show_entries")
+        .arg("format=duration:stream=width,height:stream_tags=rotate:stream_side_data=rotation")
+        .arg("-of")
+        .arg("json")
+        .arg(&path)
+        .output()
+        .await
+        .context("Failed to execute ffprobe")?;
+    parse_video_probe(&output.stdout).context("Failed to parse ffprobe output")
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    format: FfprobeFormat,
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    duration: String,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    width: Option<u64>,
+    height: Option<u64>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    side_data_list: Vec<FfprobeSideData>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeSideData {
+    rotation: Option<i64>,
+}
+
+/// Reads the JSON output of ffprobe. Videos from phones are often stored in
+/// landscape with a rotation, so width and height are swapped for those.
+fn parse_video_probe(json: &[u8]) -> Result<VideoProbe> {
+    let output: FfprobeOutput = serde_json::from_slice(json)?;
+    let dimensions = output.streams.first().and_then(|stream| {
+        let rotation = stream
+            .side_data_list
+            .iter()
+            .find_map(|side_data| side_data.rotation)
+            .or_else(|| stream.tags.get("rotate")?.parse().ok())
+            .unwrap_or(0);
+        let (width, height) = (stream.width?, stream.height?);
+        match rotation.rem_euclid(180) {
+            90 => Some((height, width)),
+            _ => Some((width, height)),
+        }
+    });
+    Ok(VideoProbe {
+        duration: output.format.duration.parse()?,
+        dimensions,
+    })
+}
+
+/// An attachment ready to be uploaded to Bluesky.
+#[derive(Debug)]
+pub enum PreparedMedia {
+    Image(PreparedImage),
+    /// An animation that was converted to an MP4 video.
+    Video(Vec<u8>),
+}
+
+/// A JPEG or PNG image without location data below the size limit.
+#[derive(Debug)]
+pub struct PreparedImage {
+    pub bytes: Vec<u8>,
+    /// Width and height as the image is displayed.
+    pub dimensions: (u64, u64),
+}
+
+/// Prepares a downloaded image attachment for Bluesky. Animated GIFs become
+/// videos, because Bluesky only shows the first frame of them.
+pub async fn prepare_media(bytes: &[u8], url: &str) -> Result<PreparedMedia> {
+    let gif = bytes.to_vec();
+    if spawn_blocking(move || is_animated_gif(&gif)).await? {
+        match gif_to_mp4(bytes).await {
+            Ok(video) => return Ok(PreparedMedia::Video(video)),
+            Err(error) => eprintln!(
+                "Warning: failed converting animated GIF {url} to a video, posting its first frame instead: {error:#}"
+            ),
+        }
+    }
+    prepare_image(bytes, url, BLUESKY_IMAGE_LIMIT)
+        .await
+        .map(PreparedMedia::Image)
+}
+
+/// Converts an image to JPEG or PNG and compresses it to at most `limit`
+/// bytes. JPEG and PNG images that are small enough and have no location
+/// data are kept as they are. Decoding and encoding run on the blocking
+/// threads of tokio, so that they do not hold up other transfers.
+pub async fn prepare_image(bytes: &[u8], url: &str, limit: usize) -> Result<PreparedImage> {
+    let decoded = decode(bytes)
+        .await
+        .with_context(|| format!("Failed decoding image {url}"))?;
+    let dimensions = (decoded.image.width().into(), decoded.image.height().into());
+    if bytes.len() <= limit
+        && matches!(decoded.format, Some(ImageFormat::Jpeg | ImageFormat::Png))
+        && !decoded.has_location
+    {
+        // Viewers apply the EXIF orientation of the original file.
+        return Ok(PreparedImage {
+            bytes: bytes.to_vec(),
+            dimensions,
+        });
+    }
+    spawn_blocking(move || encode_to_fit(decoded.image, limit))
+        .await?
+        .with_context(|| format!("Failed compressing image {url} to less than {limit} bytes"))
+}
+
+/// A decoded image, turned as its EXIF orientation says.
+struct Decoded {
+    image: DynamicImage,
+    /// None if the image crate cannot read the format, like HEIC.
+    format: Option<ImageFormat>,
+    has_location: bool,
+}
+
+async fn decode(bytes: &[u8]) -> Result<Decoded> {
+    let image = bytes.to_vec();
+    match spawn_blocking(move || decode_image(&image)).await? {
+        Ok(decoded) => Ok(decoded),
+        // Let ffmpeg convert formats that the image crate cannot decode.
+        Err(error) => {
+            let png = ffmpeg_to_png(bytes).await.map_err(|_| error)?;
+            Ok(Decoded {
+                format: None,
+                ..spawn_blocking(move || decode_image(&png)).await??
+            })
+        }
+    }
+}
+
+fn decode_image(bytes: &[u8]) -> Result<Decoded> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder()?;
+    let exif = decoder.exif_metadata().ok().flatten();
+    let orientation = exif
+        .as_deref()
+        .and_then(Orientation::from_exif_chunk)
+        .unwrap_or(Orientation::NoTransforms);
+    let xmp = decoder.xmp_metadata().ok().flatten();
+    let has_location = exif.as_deref().is_some_and(exif_has_location)
+        || xmp.as_deref().is_some_and(xmp_has_location);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(Decoded {
+        image,
+        format,
+        has_location,
+    })
+}
+
+/// Checks if an XMP packet has GPS coordinates, as an element or an attribute.
+fn xmp_has_location(xmp: &[u8]) -> bool {
+    let xmp = String::from_utf8_lossy(xmp);
+    xmp.contains("GPSLatitude") || xmp.contains("GPSLongitude")
+}
+
+/// Checks if the first IFD of an EXIF chunk links to GPS data.
+fn exif_has_location(exif: &[u8]) -> bool {
+    let little_endian = match exif.get(..4) {
+        Some([0x49, 0x49, 42, 0]) => true,
+        Some([0x4d, 0x4d, 0, 42]) => false,
+        _ => return false,
+    };
+    let read = |offset: usize, len: usize| -> Option<u32> {
+        let bytes = exif.get(offset..offset + len)?;
+        let fold = |value: u32, byte: &u8| (value << 8) | u32::from(*byte);
+        Some(match little_endian {
+            true => bytes.iter().rev().fold(0, fold),
+            false => bytes.iter().fold(0, fold),
+        })
+    };
+    let Some(ifd) = read(4, 4).map(|ifd| ifd as usize) else {
+        return false;
+    };
+    let entries = read(ifd, 2).unwrap_or(0) as usize;
+    (0..entries).any(|entry| read(ifd + 2 + entry * 12, 2) == Some(EXIF_GPS_IFD.into()))
+}
+
+/// Encodes the image with the best quality that fits the limit. Images with
+/// transparency stay PNG if they fit. Encoding drops all metadata.
+fn encode_to_fit(mut image: DynamicImage, limit: usize) -> Result<PreparedImage> {
+    loop {
+        let dimensions = (image.width().into(), image.height().into());
+        if image.color().has_alpha() {
+            let mut png = Vec::new();
+            image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+            if png.len() <= limit {
+                return Ok(PreparedImage {
+                    bytes: png,
+                    dimensions,
+                });
+            }
+        }
+        if let Some(jpeg) = best_jpeg(&image, limit)? {
+            return Ok(PreparedImage {
+                bytes: jpeg,
+                dimensions,
+            });
+        }
+        if image.width().min(image.height()) <= MIN_SIDE {
+            bail!("Even the lowest quality is too large");
+        }
+        image = image.resize(
+            image.width() * 3 / 4,
+            image.height() * 3 / 4,
+            FilterType::Lanczos3,
+        );
+    }
+}
+
+/// Binary search for the highest JPEG quality below the limit.
+fn best_jpeg(image: &DynamicImage, limit: usize) -> Result<Option<Vec<u8>>> {
+    let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+    let (mut low, mut high) = (MIN_QUALITY, MAX_QUALITY);
+    let mut best = None;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let mut jpeg = Vec::new();
+        rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality))?;
+        if jpeg.len() <= limit {
+            best = Some(jpeg);
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+fn is_animated_gif(bytes: &[u8]) -> bool {
+    GifDecoder::new(Cursor::new(bytes))
+        .is_ok_and(|decoder| decoder.into_frames().take(2).count() > 1)
+}
+
+/// Converts an animated GIF to an MP4 video that Bluesky plays in a loop.
+async fn gif_to_mp4(bytes: &[u8]) -> Result<Vec<u8>> {
+    // Most encoders need even dimensions.
+    ffmpeg_convert(
+        bytes,
+        "animation.gif",
+        "animation.mp4",
+        &[
+            "-movflags",
+            "+faststart",
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+        ],
+    )
+    .await
+}
+
+/// Converts an audio attachment to an MP4 video that shows the cover image,
+/// or a black picture without a cover.
+pub async fn audio_to_mp4(audio: &[u8], cover: Option<&[u8]>) -> Result<Vec<u8>> {
+    require_ffmpeg().await?;
+    let temp_dir = tempdir()?;
+    let input = temp_dir.path().join("audio");
+    let output = temp_dir.path().join("audio.mp4");
+    tokio::fs::write(&input, audio).await?;
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error", "-y"]);
+    match cover {
+        Some(cover) => {
+            let cover_path = temp_dir.path().join("cover");
+            tokio::fs::write(&cover_path, cover).await?;
+            command
+                .args(["-loop", "1", "-framerate", "1", "-i"])
+                .arg(cover_path);
+        }
+        None => {
+            command.args(["-f", "lavfi", "-i", "color=c=black:s=1280x720:r=1"]);
+        }
+    }
+    let command = command
+        .arg("-i")
+        .arg(&input)
+        .args([
+            "-map",
+            "0:v:0",
+            "-map",
+            "1:a:0",
+            "-c:v",
+            "libx264",
+            "-tune",
+            "stillimage",
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+            "-c:a",
+            "aac",
+            "-b:a",
+            "128k",
+            "-shortest",
+            "-movflags",
+            "+faststart",
+        ])
+        .arg(&output)
+        .output()
+        .await
+        .context("Failed to execute ffmpeg")?;
+    if !command.status.success() {
+        bail!(Unconvertible(format!(
+            "ffmpeg error: {}",
+            String::from_utf8_lossy(&command.stderr)
+        )));
+    }
+    Ok(tokio::fs::read(&output).await?)
+}
+
+/// Cuts a video to at most `max_duration` seconds and encodes it again as
+/// H.264 and AAC if it would still be larger than `max_size` bytes.
+pub async fn fit_video(
+    bytes: &[u8],
+    duration: f64,
+    max_duration: f64,
+    max_size: u64,
+) -> Result<Vec<u8>> {
+    let duration_limit = max_duration.to_string();
+    let mut args = vec!["-t", &duration_limit];
+    let length = duration.min(max_duration);
+    // The size of the cut video, assuming a constant bitrate.
+    let estimated_size = bytes.len() as f64 * length / duration.max(1.);
+    let bitrate;
+    if estimated_size <= max_size as f64 * 0.95 {
+        args.extend(["-c", "copy"]);
+    } else {
+        let video_bitrate = video_bitrate(length, max_size)
+            .context("The video is too long to fit the size limit at a watchable quality")?;
+        bitrate = video_bitrate.to_string();
+        args.extend([
+            "-c:v", "libx264", "-preset", "veryfast", "-b:v", &bitrate, "-maxrate", &bitrate,
+            "-bufsize", &bitrate, "-pix_fmt", "yuv420p", "-c:a", "aac", "-b:a", "128k",
+        ]);
+    }
+    args.extend(["-movflags", "+faststart"]);
+    let video = ffmpeg_convert(bytes, "video", "video.mp4", &args).await?;
+    if video.len() as u64 > max_size {
+        bail!(
+            "The video is still {} bytes after transcoding, more than {max_size} bytes",
+            video.len()
+        );
+    }
+    Ok(video)
+}
+
+/// Video bitrate in bits per second for a video of the given length to stay
+/// below `max_size` bytes, with some room for the container.
+fn video_bitrate(duration: f64, max_size: u64) -> Option<u64> {
+    let total = (max_size as f64 * 8. * 0.95 / duration.max(1.)) as u64;
+    total
+        .checked_sub(AUDIO_BITRATE)
+        .filter(|bitrate| *bitrate >= MIN_VIDEO_BITRATE)
+}
+
+/// Converts the first frame of an image to PNG.
+async fn ffmpeg_to_png(bytes: &[u8]) -> Result<Vec<u8>> {
+    ffmpeg_convert(bytes, "image", "image.png", &["-frames:v", "1"]).await
+}
+
+async fn ffmpeg_convert(bytes: &[u8], input: &str, output: &str, args: &[&str]) -> Result<Vec<u8>> {
+    require_ffmpeg().await?;
+    let temp_dir = tempdir()?;
+    let (input, output) = (temp_dir.path().join(input), temp_dir.path().join(output));
+    tokio::fs::write(&input, bytes).await?;
+    let command = Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-i"])
+        .arg(&input)
+        .args(args)
+        .arg(&output)
+        .output()
+        .await
+        .context("Failed to execute ffmpeg")?;
+    if !command.status.success() {
+        bail!(Unconvertible(format!(
+            "ffmpeg error: {}",
+            String::from_utf8_lossy(&command.stderr)
+        )));
+    }
+    Ok(tokio::fs::read(&output).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, Rgb, RgbImage, RgbaImage};
+
+    /// An image that does not compress well.
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        let mut seed = 1u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let [r, g, b, _] = seed.to_le_bytes();
+            Rgb([r, g, b])
+        }))
+    }
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    /// Inserts an EXIF segment with a GPS pointer after the start of a JPEG.
+    fn with_location(jpeg: &[u8]) -> Vec<u8> {
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        // GPS IFD pointer of type LONG with one value, then no next IFD.
+        tiff.extend_from_slice(&[0x25, 0x88, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0]);
+        let mut segment = b"Exif\0\0".to_vec();
+        segment.extend_from_slice(&tiff);
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xff, 0xe1]);
+        bytes.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(&segment);
+        bytes.extend_from_slice(&jpeg[2..]);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn small_images_are_kept_unless_they_have_a_location() {
+        let jpeg = encode(&noise(32, 16), ImageFormat::Jpeg);
+        let prepared = prepare_image(&jpeg, "a.jpg", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(prepared.bytes, jpeg);
+        assert_eq!(prepared.dimensions, (32, 16));
+
+        let located = with_location(&jpeg);
+        assert!(decode(&located).await.unwrap().has_location);
+        let prepared = prepare_image(&located, "b.jpg", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_ne!(prepared.bytes, located);
+        assert!(!decode(&prepared.bytes).await.unwrap().has_location);
+    }
+
+    /// XMP with the location of a photo, as written by photo editors.
+    const XMP_LOCATION: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:exif="http://ns.adobe.com/exif/1.0/" exif:GPSLatitude="52,31.2N" exif:GPSLongitude="13,24.6E"/></rdf:RDF></x:xmpmeta>"#;
+
+    /// Inserts an XMP segment after the start of a JPEG.
+    fn with_xmp_location(jpeg: &[u8]) -> Vec<u8> {
+        let mut segment = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        segment.extend_from_slice(XMP_LOCATION.as_bytes());
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xff, 0xe1]);
+        bytes.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(&segment);
+        bytes.extend_from_slice(&jpeg[2..]);
+        bytes
+    }
+
+    /// Inserts an uncompressed iTXt chunk with XMP after the header of a PNG.
+    fn with_png_xmp_location(png: &[u8]) -> Vec<u8> {
+        let mut chunk = b"iTXtXML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        chunk.extend_from_slice(XMP_LOCATION.as_bytes());
+        let crc = chunk.iter().fold(!0u32, |crc, byte| {
+            (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+            })
+        });
+        // The signature and the IHDR chunk come first.
+        let mut bytes = png[..33].to_vec();
+        bytes.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+        bytes.extend_from_slice(&chunk);
+        bytes.extend_from_slice(&(!crc).to_be_bytes());
+        bytes.extend_from_slice(&png[33..]);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn xmp_locations_are_removed() {
+        let jpeg = encode(&noise(32, 16), ImageFormat::Jpeg);
+        let located = with_xmp_location(&jpeg);
+        assert!(decode(&located).await.unwrap().has_location);
+        let prepared = prepare_image(&located, "a.jpg", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_ne!(prepared.bytes, located);
+        assert!(!decode(&prepared.bytes).await.unwrap().has_location);
+
+        let png = encode(&noise(32, 16), ImageFormat::Png);
+        assert!(!decode(&png).await.unwrap().has_location);
+        let located = with_png_xmp_location(&png);
+        assert!(decode(&located).await.unwrap().has_location);
+        let prepared = prepare_image(&located, "a.png", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_ne!(prepared.bytes, located);
+        assert!(!decode(&prepared.bytes).await.unwrap().has_location);
+    }
+
+    #[tokio::test]
+    async fn other_formats_become_jpeg_or_png() {
+        let webp = encode(&noise(20, 10), ImageFormat::WebP);
+        let prepared = prepare_image(&webp, "a.webp", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(
+            image::guess_format(&prepared.bytes).unwrap(),
+            ImageFormat::Jpeg
+        );
+
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(20, 10));
+        let bmp = encode(&transparent, ImageFormat::Bmp);
+        let prepared = prepare_image(&bmp, "a.bmp", BLUESKY_IMAGE_LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(
+            image::guess_format(&prepared.bytes).unwrap(),
+            ImageFormat::Png
+        );
+    }
+
+    #[tokio::test]
+    async fn large_images_are_compressed_below_the_limit() {
+        let png = encode(&noise(600, 400), ImageFormat::Png);
+        let limit = 300_000;
+        let prepared = prepare_image(&png, "a.png", limit).await.unwrap();
+        assert!(prepared.bytes.len() <= limit);
+        assert!(prepared.bytes.len() > limit / 2, "quality was not searched");
+        assert_eq!(prepared.dimensions, (600, 400));
+
+        // Too large even at the lowest quality, so the image is scaled down.
+        let prepared = prepare_image(&png, "a.png", 40_000).await.unwrap();
+        assert!(prepared.bytes.len() <= 40_000);
+        assert!(prepared.dimensions.0 < 600);
+        let (width, height) = prepared.dimensions;
+        assert!(
+            (width * 2).abs_diff(height * 3) <= 3,
+            "aspect ratio changed"
+        );
+    }
+
+    #[test]
+    fn video_bitrate_fits_the_size_limit() {
+        // 100 MB for 10 minutes leaves a bit more than 1 Mbit/s.
+        let bitrate = video_bitrate(600., 100_000_000).unwrap();
+        assert!((1_000_000..1_266_666).contains(&bitrate), "{bitrate}");
+        assert_eq!(video_bitrate(3600., 10_000_000), None);
+    }
+
+    #[test]
+    fn video_probe_swaps_the_sides_of_rotated_videos() {
+        let probe = parse_video_probe(
+            br#"{
+                "streams": [{
+                    "width": 1920,
+                    "height": 1080,
+                    "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]
+                }],
+                "format": {"duration": "12.500000"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            probe,
+            VideoProbe {
+                duration: 12.5,
+                dimensions: Some((1080, 1920)),
+            }
+        );
+
+        let probe = parse_video_probe(
+            br#"{"streams": [{"width": 640, "height": 480, "tags": {"rotate": "180"}}],
+                "format": {"duration": "3.0"}}"#,
+        )
+        .unwrap();
+        assert_eq!(probe.dimensions, Some((640, 480)));
+        let probe = parse_video_probe(br#"{"format": {"duration": "3.0"}}"#).unwrap();
+        assert_eq!(probe.dimensions, None);
+    }
+
+    #[test]
+    fn animated_gifs_are_detected() {
+        let frame = |color| Frame::new(RgbaImage::from_pixel(4, 4, color));
+        let mut gif = Vec::new();
+        GifEncoder::new(&mut gif)
+            .encode_frames([
+                frame(image::Rgba([255, 0, 0, 255])),
+                frame(image::Rgba([0, 0, 255, 255])),
+            ])
+            .unwrap();
+        assert!(is_animated_gif(&gif));
+
+        let still = encode(&noise(4, 4), ImageFormat::Gif);
+        assert!(!is_animated_gif(&still));
+        assert!(!is_animated_gif(b"not an image"));
+    }
+}
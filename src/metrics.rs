@@ -0,0 +1,400 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::report::{ActionStatus, RunReport};
+
+/// Upper bounds in seconds of the API latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    // Cumulative counts per bucket in LATENCY_BUCKETS.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsData {
+    runs: u64,
+    posts_synced: BTreeMap<String, u64>,
+    posts_skipped: BTreeMap<String, u64>,
+    failures: BTreeMap<String, u64>,
+    deleted: BTreeMap<String, u64>,
+    last_success: Option<DateTime<Utc>>,
+    // Keyed by network and operation.
+    api_latency: BTreeMap<(String, String), Histogram>,
+}
+
+/// Counters collected over the lifetime of the daemon, exposed in the
+/// Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    started_at: DateTime<Utc>,
+    data: Mutex<MetricsData>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            started_at: Utc::now(),
+            data: Mutex::new(MetricsData::default()),
+        }
+    }
+
+    /// Adds the results of a finished synchronization run. Only runs without
+    /// failed posts are successful.
+    pub fn record_run(&self, report: &RunReport) {
+        let mut data = self.data.lock().unwrap();
+        data.runs += 1;
+        for action in &report.actions {
+            let direction = action.direction.as_str().to_string();
+            match action.status {
+                ActionStatus::Posted => *data.posts_synced.entry(direction).or_default() += 1,
+                ActionStatus::Skipped => *data.posts_skipped.entry(direction).or_default() += 1,
+                ActionStatus::Failed => {
+                    *data
+                        .failures
+                        .entry(format!("post_{direction}"))
+                        .or_default() += 1
+                }
+                ActionStatus::Planned => {}
+            }
+        }
+        for (kind, count) in [
+            ("bluesky_posts", report.deletions.bluesky_posts),
+            ("mastodon_favs", report.deletions.mastodon_favs),
+            ("bluesky_favs", report.deletions.bluesky_favs),
+        ] {
+            if let Some(count) = count {
+                *data.deleted.entry(kind.to_string()).or_default() += count.deleted as u64;
+            }
+        }
+        // A run in which posts failed, for example during an outage of one
+        // network, does not count as success.
+        if report.failed_count() == 0 {
+            data.last_success = report.finished_at.or(Some(Utc::now()));
+        }
+    }
+
+    /// Counts a failure of the given kind, for example a run that was aborted.
+    pub fn record_failure(&self, kind: &str) {
+        let mut data = self.data.lock().unwrap();
+        *data.failures.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn observe_api_latency(&self, network: &str, operation: &str, duration: Duration) {
+        let mut data = self.data.lock().unwrap();
+        data.api_latency
+            .entry((network.to_string(), operation.to_string()))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Awaits the given API call and records how long it took.
+    pub async fn time_api<F: Future>(&self, network: &str, operation: &str, call: F) -> F::Output {
+        let start = Instant::now();
+        let output = call.await;
+        self.observe_api_latency(network, operation, start.elapsed());
+        output
+    }
+
+    /// A sync is healthy if the last successful run is not older than the
+    /// given maximum age. Before the first run completes the daemon start time
+    /// is used instead.
+    pub fn is_healthy(&self, max_age: Duration, now: DateTime<Utc>) -> bool {
+        let last = self
+            .data
+            .lock()
+            .unwrap()
+            .last_success
+            .unwrap_or(self.started_at);
+        match chrono::Duration::from_std(max_age) {
+            Ok(max_age) => now - last <= max_age,
+            Err(_) => true,
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "mbs_runs_total",
+            "counter",
+            "Completed synchronization runs.",
+        );
+        let _ = writeln!(out, "mbs_runs_total {}", data.runs);
+
+        write_header(
+            &mut out,
+            "mbs_posts_synced_total",
+            "counter",
+            "Posts synced per direction.",
+        );
+        write_labeled(
+            &mut out,
+            "mbs_posts_synced_total",
+            "direction",
+            &data.posts_synced,
+        );
+
+        write_header(
+            &mut out,
+            "mbs_posts_skipped_total",
+            "counter",
+            "Posts skipped as duplicates or existing posts per direction.",
+        );
+        write_labeled(
+            &mut out,
+            "mbs_posts_skipped_total",
+            "direction",
+            &data.posts_skipped,
+        );
+
+        write_header(
+            &mut out,
+            "mbs_failures_total",
+            "counter",
+            "Failures by kind.",
+        );
+        write_labeled(&mut out, "mbs_failures_total", "kind", &data.failures);
+
+        write_header(
+            &mut out,
+            "mbs_deleted_total",
+            "counter",
+            "Old posts and favourites deleted.",
+        );
+        write_labeled(&mut out, "mbs_deleted_total", "type", &data.deleted);
+
+        write_header(
+            &mut out,
+            "mbs_last_success_timestamp_seconds",
+            "gauge",
+            "Unix timestamp of the last run without failures.",
+        );
+        let _ = writeln!(
+            out,
+            "mbs_last_success_timestamp_seconds {}",
+            data.last_success.map(|date| date.timestamp()).unwrap_or(0)
+        );
+
+        write_header(
+            &mut out,
+            "mbs_api_request_duration_seconds",
+            "histogram",
+            "Latency of API calls to Mastodon and Bluesky.",
+        );
+        for ((network, operation), histogram) in &data.api_latency {
+            let labels = format!("network=\"{network}\",operation=\"{operation}\"");
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "mbs_api_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "mbs_api_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "mbs_api_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "mbs_api_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+}
+
+fn write_labeled(out: &mut String, name: &str, label: &str, values: &BTreeMap<String, u64>) {
+    for (value, count) in values {
+        let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+    }
+}
+
+/// Serves `/metrics` and `/healthz` until the process exits.
+pub async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>, max_age: Duration) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Error accepting metrics connection: {e}");
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &metrics, max_age).await {
+                debug!("metrics connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    max_age: Duration,
+) -> Result<()> {
+    // We only need the request line, so reading the first chunk is enough.
+    let mut buffer = [0; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await??;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, body) = route(method, path, metrics, max_age, Utc::now());
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Returns the HTTP status code and body for a request.
+fn route(
+    method: &str,
+    path: &str,
+    metrics: &Metrics,
+    max_age: Duration,
+    now: DateTime<Utc>,
+) -> (u16, String) {
+    if method != "GET" {
+        return (405, "Method not allowed\n".to_string());
+    }
+    match path {
+        "/metrics" => (200, metrics.render()),
+        "/healthz" => {
+            if metrics.is_healthy(max_age, now) {
+                (200, "ok\n".to_string())
+            } else {
+                (503, "last successful sync is too old\n".to_string())
+            }
+        }
+        _ => (404, "Not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{DeletionCount, Direction};
+    use crate::sync::NewStatus;
+
+    #[test]
+    fn render_includes_run_results() {
+        let metrics = Metrics::new();
+        let mut report = RunReport::new(false);
+        report.posted(Direction::MastodonToBluesky, &NewStatus::default(), None);
+        report.skipped(Direction::BlueskyToMastodon, &NewStatus::default(), "dup");
+        report.deletions.bluesky_posts = Some(DeletionCount {
+            expired: 2,
+            deleted: 2,
+        });
+        report.finish();
+        metrics.record_run(&report);
+        metrics.record_failure("run");
+        metrics.observe_api_latency("bluesky", "fetch_timeline", Duration::from_millis(300));
+
+        let output = metrics.render();
+        assert!(output.contains("mbs_runs_total 1\n"));
+        assert!(output.contains("mbs_posts_synced_total{direction=\"mastodon_to_bluesky\"} 1\n"));
+        assert!(output.contains("mbs_posts_skipped_total{direction=\"bluesky_to_mastodon\"} 1\n"));
+        assert!(output.contains("mbs_failures_total{kind=\"run\"} 1\n"));
+        assert!(output.contains("mbs_deleted_total{type=\"bluesky_posts\"} 2\n"));
+        assert!(output.contains(
+            "mbs_api_request_duration_seconds_bucket{network=\"bluesky\",operation=\"fetch_timeline\",le=\"0.25\"} 0\n"
+        ));
+        assert!(output.contains(
+            "mbs_api_request_duration_seconds_bucket{network=\"bluesky\",operation=\"fetch_timeline\",le=\"0.5\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn healthz_fails_when_last_sync_is_too_old() {
+        let metrics = Metrics::new();
+        let max_age = Duration::from_secs(60);
+
+        let (status, _) = route("GET", "/healthz", &metrics, max_age, Utc::now());
+        assert_eq!(status, 200);
+
+        let later = Utc::now() + chrono::Duration::seconds(120);
+        let (status, _) = route("GET", "/healthz", &metrics, max_age, later);
+        assert_eq!(status, 503);
+
+        // Runs in which every post failed do not make the sync healthy.
+        let mut report = RunReport::new(false);
+        report.failed(
+            Direction::MastodonToBluesky,
+            &NewStatus::default(),
+            &anyhow::anyhow!("Bluesky is down"),
+        );
+        report.finished_at = Some(later);
+        metrics.record_run(&report);
+        let (status, _) = route("GET", "/healthz", &metrics, max_age, later);
+        assert_eq!(status, 503);
+
+        let mut report = RunReport::new(false);
+        report.finished_at = Some(later);
+        metrics.record_run(&report);
+        let (status, _) = route("GET", "/healthz", &metrics, max_age, later);
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let metrics = Metrics::new();
+        let (status, _) = route("GET", "/", &metrics, Duration::from_secs(60), Utc::now());
+        assert_eq!(status, 404);
+    }
+}
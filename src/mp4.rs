@@ -0,0 +1,704 @@
+//! Reading and writing MP4 files without ffmpeg, for the common case of H.264
+//! video with AAC audio.
+
+use anyhow::{Context, Result, bail};
+
+use crate::media::VideoProbe;
+
+/// Timescale of the video track, the clock of MPEG-TS timestamps.
+pub const VIDEO_TIMESCALE: u32 = 90_000;
+
+/// Timescale of the movie, in milliseconds.
+const MOVIE_TIMESCALE: u32 = 1_000;
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// One video frame or AAC frame.
+#[derive(Debug)]
+pub struct Sample {
+    pub data: Vec<u8>,
+    /// Duration in the timescale of the track.
+    pub duration: u32,
+    /// Presentation time minus decoding time of a video frame.
+    pub composition_offset: u32,
+    /// Whether decoding can start at this frame.
+    pub sync: bool,
+}
+
+/// H.264 video with frames in length prefixed NAL units.
+#[derive(Debug)]
+pub struct VideoTrack {
+    pub width: u16,
+    pub height: u16,
+    pub sps: Vec<u8>,
+    pub pps: Vec<u8>,
+    pub samples: Vec<Sample>,
+    /// Seconds before the first frame is shown.
+    pub delay: f64,
+}
+
+/// AAC audio with raw frames.
+#[derive(Debug)]
+pub struct AudioTrack {
+    /// The AudioSpecificConfig of the decoder.
+    pub config: Vec<u8>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<Sample>,
+    /// Seconds before the first frame is played.
+    pub delay: f64,
+}
+
+/// Reads the duration and the display size of the first video track from
+/// the boxes of an MP4 file.
+pub fn probe(data: &[u8]) -> Result<VideoProbe> {
+    let moov = find_box(data, b"moov")?.context("No moov box, this is not an MP4 file")?;
+    let mvhd = find_box(moov, b"mvhd")?.context("No mvhd box in the MP4 file")?;
+    let mut reader = Reader::new(mvhd);
+    let (timescale, mut duration) = if reader.u8()? == 1 {
+        reader.skip(19)?;
+        (reader.u32()?, reader.u64()?)
+    } else {
+        reader.skip(11)?;
+        (reader.u32()?, reader.u32()? as u64)
+    };
+    // Fragmented files have the duration in the movie extends box.
+    if (duration == 0 || duration == u32::MAX as u64)
+        && let Some(mehd) = find_box(moov, b"mvex")?
+            .map(|mvex| find_box(mvex, b"mehd"))
+            .transpose()?
+            .flatten()
+    {
+        let mut reader = Reader::new(mehd);
+        duration = if reader.u8()? == 1 {
+            reader.skip(3)?;
+            reader.u64()?
+        } else {
+            reader.skip(3)?;
+            reader.u32()? as u64
+        };
+    }
+    if timescale == 0 || duration == 0 || duration == u32::MAX as u64 {
+        bail!("The MP4 file has no duration");
+    }
+
+    let mut dimensions = None;
+    for (kind, trak) in read_boxes(moov)? {
+        if kind != b"trak" {
+            continue;
+        }
+        let handler = find_box(trak, b"mdia")?
+            .map(|mdia| find_box(mdia, b"hdlr"))
+            .transpose()?
+            .flatten()
+            .and_then(|hdlr| hdlr.get(8..12));
+        if handler != Some(b"vide".as_slice()) {
+            continue;
+        }
+        if let Some(tkhd) = find_box(trak, b"tkhd")? {
+            dimensions = track_dimensions(tkhd)?;
+        }
+        break;
+    }
+    Ok(VideoProbe {
+        duration: duration as f64 / timescale as f64,
+        dimensions,
+    })
+}
+
+/// Width and height of a track as it is displayed. Videos from phones are
+/// often stored in landscape with a rotation, so the sides are swapped for
+/// those.
+fn track_dimensions(tkhd: &[u8]) -> Result<Option<(u64, u64)>> {
+    let mut reader = Reader::new(tkhd);
+    let header = if reader.u8()? == 1 { 32 } else { 20 };
+    reader.skip(3 + header + 16)?;
+    let mut matrix = [0i32; 9];
+    for value in &mut matrix {
+        *value = reader.u32()? as i32;
+    }
+    let width = (reader.u32()? >> 16) as u64;
+    let height = (reader.u32()? >> 16) as u64;
+    if width == 0 || height == 0 {
+        return Ok(None);
+    }
+    // A rotation by 90 or 270 degrees has no scaling on the diagonal.
+    if matrix[0] == 0 && matrix[1] != 0 {
+        return Ok(Some((height, width)));
+    }
+    Ok(Some((width, height)))
+}
+
+/// Writes an MP4 file with the index in front of the media data, so that it
+/// can be played while it is downloaded.
+pub fn write(video: &VideoTrack, audio: Option<&AudioTrack>) -> Result<Vec<u8>> {
+    if video.samples.is_empty() {
+        bail!("The video has no frames");
+    }
+    if video.sps.len() < 4 || video.pps.is_empty() {
+        bail!("The video has no H.264 parameter sets");
+    }
+    let ftyp = mp4_box(
+        b"ftyp",
+        &[
+            b"isom",
+            &0x200u32.to_be_bytes(),
+            b"isom",
+            b"iso2",
+            b"avc1",
+            b"mp41",
+        ],
+    );
+    let mut tracks = vec![Track {
+        id: 1,
+        handler: b"vide",
+        name: "VideoHandler",
+        timescale: VIDEO_TIMESCALE,
+        samples: &video.samples,
+        delay: video.delay,
+        media_time: video.samples[0].composition_offset,
+        size: (video.width, video.height),
+        volume: 0,
+        media_header: full_box(b"vmhd", 0, 1, &[&[0; 8]]),
+        sample_entry: avc1(video),
+    }];
+    if let Some(audio) = audio.filter(|audio| !audio.samples.is_empty()) {
+        tracks.push(Track {
+            id: 2,
+            handler: b"soun",
+            name: "SoundHandler",
+            timescale: audio.sample_rate,
+            samples: &audio.samples,
+            delay: audio.delay,
+            media_time: 0,
+            size: (0, 0),
+            volume: 0x0100,
+            media_header: full_box(b"smhd", 0, 0, &[&[0; 4]]),
+            sample_entry: mp4a(audio),
+        });
+    }
+
+    // The offsets do not change the size of the index, so it is written
+    // once to know where the media data starts.
+    let moov_size = moov(&tracks, &vec![0; tracks.len()])?.len();
+    let mut offset = (ftyp.len() + moov_size + 8) as u64;
+    let mut chunk_offsets = Vec::new();
+    for track in &tracks {
+        chunk_offsets.push(
+            u32::try_from(offset)
+                .context("The video is too large for an MP4 file without ffmpeg")?,
+        );
+        offset += track
+            .samples
+            .iter()
+            .map(|sample| sample.data.len() as u64)
+            .sum::<u64>();
+    }
+    let moov = moov(&tracks, &chunk_offsets)?;
+    let mdat_size = u32::try_from(offset - (ftyp.len() + moov.len()) as u64)
+        .context("The video is too large for an MP4 file without ffmpeg")?;
+
+    let mut file = Vec::with_capacity(offset as usize);
+    file.extend_from_slice(&ftyp);
+    file.extend_from_slice(&moov);
+    file.extend_from_slice(&mdat_size.to_be_bytes());
+    file.extend_from_slice(b"mdat");
+    for track in &tracks {
+        for sample in track.samples {
+            file.extend_from_slice(&sample.data);
+        }
+    }
+    Ok(file)
+}
+
+/// A track with its boxes that differ between video and audio.
+struct Track<'a> {
+    id: u32,
+    handler: &'a [u8; 4],
+    name: &'a str,
+    timescale: u32,
+    samples: &'a [Sample],
+    delay: f64,
+    /// Media time of the first presented sample.
+    media_time: u32,
+    size: (u16, u16),
+    volume: u16,
+    media_header: Vec<u8>,
+    sample_entry: Vec<u8>,
+}
+
+impl Track<'_> {
+    fn media_duration(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| sample.duration as u64)
+            .sum()
+    }
+
+    /// Presented duration in the movie timescale, without the delay.
+    fn movie_duration(&self) -> u64 {
+        self.media_duration().saturating_sub(self.media_time as u64) * MOVIE_TIMESCALE as u64
+            / self.timescale as u64
+    }
+
+    fn movie_delay(&self) -> u64 {
+        (self.delay.max(0.) * MOVIE_TIMESCALE as f64).round() as u64
+    }
+}
+
+fn moov(tracks: &[Track], chunk_offsets: &[u32]) -> Result<Vec<u8>> {
+    let duration = tracks
+        .iter()
+        .map(|track| track.movie_delay() + track.movie_duration())
+        .max()
+        .unwrap_or_default();
+    let mvhd = full_box(
+        b"mvhd",
+        0,
+        0,
+        &[
+            &[0; 8],
+            &MOVIE_TIMESCALE.to_be_bytes(),
+            &u32::try_from(duration)?.to_be_bytes(),
+            &0x0001_0000u32.to_be_bytes(),
+            &0x0100u16.to_be_bytes(),
+            &[0; 10],
+            &matrix(),
+            &[0; 24],
+            &(tracks.len() as u32 + 1).to_be_bytes(),
+        ],
+    );
+    let mut traks = Vec::new();
+    for (track, offset) in tracks.iter().zip(chunk_offsets) {
+        traks.extend(trak(track, *offset)?);
+    }
+    Ok(mp4_box(b"moov", &[&mvhd, &traks]))
+}
+
+fn trak(track: &Track, chunk_offset: u32) -> Result<Vec<u8>> {
+    let duration = u32::try_from(track.movie_delay() + track.movie_duration())?;
+    let (width, height) = track.size;
+    let tkhd = full_box(
+        b"tkhd",
+        0,
+        // Enabled and used in the presentation.
+        3,
+        &[
+            &[0; 8],
+            &track.id.to_be_bytes(),
+            &[0; 4],
+            &duration.to_be_bytes(),
+            &[0; 12],
+            &track.volume.to_be_bytes(),
+            &[0; 2],
+            &matrix(),
+            &((width as u32) << 16).to_be_bytes(),
+            &((height as u32) << 16).to_be_bytes(),
+        ],
+    );
+
+    // An empty edit delays a track that starts later than the other one.
+    let mut edits = Vec::new();
+    if track.movie_delay() > 0 {
+        edits.push((u32::try_from(track.movie_delay())?, -1));
+    }
+    edits.push((
+        u32::try_from(track.movie_duration())?,
+        track.media_time as i32,
+    ));
+    let mut elst = (edits.len() as u32).to_be_bytes().to_vec();
+    for (segment_duration, media_time) in edits {
+        elst.extend_from_slice(&segment_duration.to_be_bytes());
+        elst.extend_from_slice(&media_time.to_be_bytes());
+        elst.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    }
+    let edts = mp4_box(b"edts", &[&full_box(b"elst", 0, 0, &[&elst])]);
+
+    let mdhd = full_box(
+        b"mdhd",
+        0,
+        0,
+        &[
+            &[0; 8],
+            &track.timescale.to_be_bytes(),
+            &u32::try_from(track.media_duration())?.to_be_bytes(),
+            // The language "und" packed in 5 bit letters.
+            &0x55c4u16.to_be_bytes(),
+            &[0; 2],
+        ],
+    );
+    let hdlr = full_box(
+        b"hdlr",
+        0,
+        0,
+        &[
+            &[0; 4],
+            track.handler,
+            &[0; 12],
+            track.name.as_bytes(),
+            &[0],
+        ],
+    );
+    let dinf = mp4_box(
+        b"dinf",
+        &[&full_box(
+            b"dref",
+            0,
+            0,
+            &[&1u32.to_be_bytes(), &full_box(b"url ", 0, 1, &[])],
+        )],
+    );
+    let minf = mp4_box(
+        b"minf",
+        &[&track.media_header, &dinf, &stbl(track, chunk_offset)],
+    );
+    let mdia = mp4_box(b"mdia", &[&mdhd, &hdlr, &minf]);
+    Ok(mp4_box(b"trak", &[&tkhd, &edts, &mdia]))
+}
+
+/// The sample table with all samples in one chunk.
+fn stbl(track: &Track, chunk_offset: u32) -> Vec<u8> {
+    let samples = track.samples;
+    let stsd = full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes(), &track.sample_entry]);
+    let stts = full_box(
+        b"stts",
+        0,
+        0,
+        &[&run_lengths(samples.iter().map(|sample| sample.duration))],
+    );
+    let mut boxes = vec![stsd, stts];
+    if samples.iter().any(|sample| sample.composition_offset != 0) {
+        boxes.push(full_box(
+            b"ctts",
+            0,
+            0,
+            &[&run_lengths(
+                samples.iter().map(|sample| sample.composition_offset),
+            )],
+        ));
+    }
+    // Without a sync sample box every sample is a sync sample.
+    if samples.iter().any(|sample| !sample.sync) {
+        let numbers: Vec<u32> = (1..)
+            .zip(samples)
+            .filter(|(_, sample)| sample.sync)
+            .map(|(number, _)| number)
+            .collect();
+        boxes.push(full_box(b"stss", 0, 0, &[&u32_table(&numbers)]));
+    }
+    boxes.push(full_box(
+        b"stsc",
+        0,
+        0,
+        &[
+            &1u32.to_be_bytes(),
+            &1u32.to_be_bytes(),
+            &(samples.len() as u32).to_be_bytes(),
+            &1u32.to_be_bytes(),
+        ],
+    ));
+    let sizes: Vec<u32> = samples
+        .iter()
+        .map(|sample| sample.data.len() as u32)
+        .collect();
+    boxes.push(full_box(b"stsz", 0, 0, &[&[0; 4], &u32_table(&sizes)]));
+    boxes.push(full_box(b"stco", 0, 0, &[&u32_table(&[chunk_offset])]));
+    mp4_box(
+        b"stbl",
+        &boxes.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+    )
+}
+
+fn avc1(video: &VideoTrack) -> Vec<u8> {
+    let mut avcc = vec![
+        1,
+        video.sps[1],
+        video.sps[2],
+        video.sps[3],
+        // NAL units have a 4 byte length.
+        0xff,
+        // One SPS.
+        0xe1,
+    ];
+    avcc.extend_from_slice(&(video.sps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(&video.sps);
+    avcc.push(1);
+    avcc.extend_from_slice(&(video.pps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(&video.pps);
+    mp4_box(
+        b"avc1",
+        &[
+            &[0; 6],
+            &1u16.to_be_bytes(),
+            &[0; 16],
+            &video.width.to_be_bytes(),
+            &video.height.to_be_bytes(),
+            // 72 dpi.
+            &0x0048_0000u32.to_be_bytes(),
+            &0x0048_0000u32.to_be_bytes(),
+            &[0; 4],
+            &1u16.to_be_bytes(),
+            &[0; 32],
+            &0x0018u16.to_be_bytes(),
+            &(-1i16).to_be_bytes(),
+            &mp4_box(b"avcC", &[&avcc]),
+        ],
+    )
+}
+
+fn mp4a(audio: &AudioTrack) -> Vec<u8> {
+    let duration: u64 = audio
+        .samples
+        .iter()
+        .map(|sample| sample.duration as u64)
+        .sum();
+    let bytes: u64 = audio
+        .samples
+        .iter()
+        .map(|sample| sample.data.len() as u64)
+        .sum();
+    let bitrate = (bytes * 8 * audio.sample_rate as u64 / duration.max(1)) as u32;
+    let decoder_config = descriptor(
+        0x04,
+        &[
+            // AAC audio stream.
+            &[0x40, 0x15],
+            &[0; 3],
+            &bitrate.to_be_bytes(),
+            &bitrate.to_be_bytes(),
+            &descriptor(0x05, &[&audio.config]),
+        ],
+    );
+    let es = descriptor(
+        0x03,
+        &[
+            &2u16.to_be_bytes(),
+            &[0],
+            &decoder_config,
+            &descriptor(0x06, &[&[0x02]]),
+        ],
+    );
+    // Rates that do not fit the 16.16 field are read from the decoder
+    // config.
+    let sample_rate = if audio.sample_rate <= 0xffff {
+        audio.sample_rate << 16
+    } else {
+        0
+    };
+    mp4_box(
+        b"mp4a",
+        &[
+            &[0; 6],
+            &1u16.to_be_bytes(),
+            &[0; 8],
+            &audio.channels.max(1).to_be_bytes(),
+            &16u16.to_be_bytes(),
+            &[0; 4],
+            &sample_rate.to_be_bytes(),
+            &full_box(b"esds", 0, 0, &[&es]),
+        ],
+    )
+}
+
+/// An MPEG-4 descriptor with a content shorter than 128 bytes.
+fn descriptor(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let content = parts.concat();
+    [&[tag, content.len() as u8], content.as_slice()].concat()
+}
+
+/// Entry count and runs of equal values.
+fn run_lengths(values: impl Iterator<Item = u32>) -> Vec<u8> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    let mut table = (runs.len() as u32).to_be_bytes().to_vec();
+    for (count, value) in runs {
+        table.extend_from_slice(&count.to_be_bytes());
+        table.extend_from_slice(&value.to_be_bytes());
+    }
+    table
+}
+
+/// Entry count and the values.
+fn u32_table(values: &[u32]) -> Vec<u8> {
+    let mut table = (values.len() as u32).to_be_bytes().to_vec();
+    for value in values {
+        table.extend_from_slice(&value.to_be_bytes());
+    }
+    table
+}
+
+fn matrix() -> Vec<u8> {
+    UNITY_MATRIX
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn mp4_box(kind: &[u8; 4], parts: &[&[u8]]) -> Vec<u8> {
+    let size: usize = 8 + parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut data = Vec::with_capacity(size);
+    data.extend_from_slice(&(size as u32).to_be_bytes());
+    data.extend_from_slice(kind);
+    for part in parts {
+        data.extend_from_slice(part);
+    }
+    data
+}
+
+/// A box with version and flags.
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, parts: &[&[u8]]) -> Vec<u8> {
+    let header = ((version as u32) << 24 | flags).to_be_bytes();
+    let mut all: Vec<&[u8]> = vec![&header];
+    all.extend_from_slice(parts);
+    mp4_box(kind, &all)
+}
+
+/// The boxes in `data` with their type and content. A box that claims to be
+/// larger than the data ends the list, so that the index in front of a
+/// truncated file can still be read.
+fn read_boxes(mut data: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let mut reader = Reader::new(data);
+        let size = reader.u32()? as u64;
+        let kind = &data[4..8];
+        let (header, size) = match size {
+            0 => (8, data.len() as u64),
+            1 => {
+                reader.skip(4)?;
+                (16, reader.u64()?)
+            }
+            size => (8, size),
+        };
+        if size < header {
+            bail!("Invalid MP4 box {}", String::from_utf8_lossy(kind));
+        }
+        if size > data.len() as u64 {
+            break;
+        }
+        boxes.push((kind, &data[header as usize..size as usize]));
+        data = &data[size as usize..];
+    }
+    Ok(boxes)
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    Ok(read_boxes(data)?
+        .into_iter()
+        .find(|(found, _)| *found == kind)
+        .map(|(_, content)| content))
+}
+
+/// Reads big endian numbers from the content of a box.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let (bytes, rest) = self
+            .data
+            .split_first_chunk::<N>()
+            .context("Unexpected end of an MP4 box")?;
+        self.data = rest;
+        Ok(*bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<()> {
+        self.data = self
+            .data
+            .get(count..)
+            .context("Unexpected end of an MP4 box")?;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(size: usize, duration: u32, sync: bool) -> Sample {
+        Sample {
+            data: vec![0; size],
+            duration,
+            composition_offset: 0,
+            sync,
+        }
+    }
+
+    fn video(width: u16, height: u16) -> VideoTrack {
+        VideoTrack {
+            width,
+            height,
+            sps: vec![0x67, 0x42, 0xc0, 0x1e],
+            pps: vec![0x68, 0xce, 0x3c, 0x80],
+            // Two seconds at 25 frames per second.
+            samples: (0..50).map(|i| sample(100, 3_600, i % 25 == 0)).collect(),
+            delay: 0.,
+        }
+    }
+
+    #[test]
+    fn written_files_can_be_probed() {
+        let audio = AudioTrack {
+            config: vec![0x12, 0x10],
+            sample_rate: 44_100,
+            channels: 2,
+            samples: (0..100).map(|_| sample(20, 1024, true)).collect(),
+            delay: 0.5,
+        };
+        let file = write(&video(640, 360), Some(&audio)).unwrap();
+        assert_eq!(&file[4..8], b"ftyp");
+        let probe = probe(&file).unwrap();
+        assert_eq!(probe.dimensions, Some((640, 360)));
+        // The audio starts half a second later and lasts 2.32 seconds.
+        assert!((probe.duration - 2.822).abs() < 0.01, "{}", probe.duration);
+
+        // The media data follows the index.
+        let boxes = read_boxes(&file).unwrap();
+        let kinds: Vec<&[u8]> = boxes.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"ftyp", b"moov", b"mdat"]);
+        assert_eq!(boxes[2].1.len(), 50 * 100 + 100 * 20);
+    }
+
+    #[test]
+    fn rotated_videos_have_swapped_sides() {
+        let mut file = write(&video(1920, 1080), None).unwrap();
+        // Rotate the video track by 90 degrees.
+        let tkhd = file.windows(4).position(|kind| kind == b"tkhd").unwrap();
+        let matrix = tkhd + 4 + 4 + 20 + 16;
+        let rotation: Vec<u8> = [0, 0x0001_0000, 0, -0x0001_0000i32, 0]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        file[matrix..matrix + 20].copy_from_slice(&rotation);
+        assert_eq!(probe(&file).unwrap().dimensions, Some((1080, 1920)));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(probe(b"\x1a\x45\xdf\xa3 webm").is_err());
+        assert!(probe(&mp4_box(b"ftyp", &[b"isom"])).is_err());
+    }
+}
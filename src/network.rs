@@ -0,0 +1,65 @@
+use anyhow::Result;
+
+use crate::DatePostList;
+use crate::sync::{NewMedia, NewStatus};
+
+/// One page of post or like IDs keyed to their dates, with the cursor to
+/// fetch the next (older) page.
+#[derive(Debug, Default)]
+pub struct DatePage {
+    pub dates: DatePostList,
+    pub cursor: Option<String>,
+}
+
+/// Result of removing a like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LikeDeletion {
+    Deleted,
+    /// The like does not exist anymore or cannot be deleted, forget about it.
+    Gone,
+    /// The API rate limit is still exceeded after retrying, stop deleting
+    /// likes for now.
+    RateLimited,
+}
+
+/// The operations synchronization needs from a social network. Implemented
+/// for Mastodon and Bluesky, tests use in-memory fakes.
+pub trait SocialNetwork {
+    /// An entry of the account's own timeline.
+    type Post;
+    /// Reference to an uploaded attachment that can be added to a new post.
+    type Media;
+
+    /// Human readable name of the network for messages.
+    fn name(&self) -> &'static str;
+
+    /// Fetches the most recent posts of the account.
+    async fn fetch_timeline(&self) -> Result<Vec<Self::Post>>;
+
+    /// Downloads the attachment and uploads it for the given status. Returns
+    /// None if the attachment type is not supported and should be left out.
+    async fn upload_media(
+        &self,
+        attachment: &NewMedia,
+        status: &NewStatus,
+    ) -> Result<Option<Self::Media>>;
+
+    /// Creates a new post, optionally as reply to the post with the given ID.
+    /// Returns the ID of the new post.
+    async fn create_post(
+        &self,
+        status: &NewStatus,
+        media: Vec<Self::Media>,
+        reply_to: Option<&str>,
+    ) -> Result<String>;
+
+    async fn delete_post(&self, id: &str) -> Result<()>;
+
+    /// Lists the account's own posts, newest first.
+    async fn list_posts(&self, cursor: Option<String>) -> Result<DatePage>;
+
+    /// Lists the account's likes (favourites), newest first.
+    async fn list_likes(&self, cursor: Option<String>) -> Result<DatePage>;
+
+    async fn delete_like(&self, id: &str) -> Result<LikeDeletion>;
+}
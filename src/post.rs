@@ -1,18 +1,37 @@
 use crate::BskyAgent;
 use crate::NewMedia;
 use crate::bluesky_richtext::get_rich_text;
-use crate::bluesky_video::bluesky_upload_video;
-use crate::sync::NewStatus;
+use crate::bluesky_video::{VideoUpload, bluesky_upload_caption_blob, bluesky_upload_video};
+use crate::captions::{
+    BLUESKY_CAPTION_LIMIT, MASTODON_DESCRIPTION_LIMIT, fetch_stream_captions, truncate_text,
+    vtt_text,
+};
+use crate::config::{AudioMode, CaptionMode, MediaConfig, OversizedVideos};
+use crate::hls::download_stream_mp4;
+use crate::http::{USER_AGENT, WebClient};
+use crate::media::{
+    BLUESKY_IMAGE_LIMIT, PreparedMedia, audio_to_mp4, fit_video, is_unconvertible, media_tools,
+    prepare_image, prepare_media, probe_video,
+};
+use crate::network::SocialNetwork;
+use crate::report::progress;
+use crate::retry::mastodon_retry;
+use crate::sync::{
+    MediaKind, NewCaption, NewStatus, bsky_post_web_url, bsky_post_with_full_video_link,
+    toot_with_full_video_link,
+};
 use anyhow::Context;
 use anyhow::Result;
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use bsky_sdk::api::app::bsky::embed::defs::{AspectRatio, AspectRatioData};
+use bsky_sdk::api::app::bsky::embed::images::Image;
+use bsky_sdk::api::app::bsky::embed::video::Caption;
 use bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs;
 use bsky_sdk::api::app::bsky::richtext::facet::MainFeaturesItem;
 use bsky_sdk::api::types::string::Language;
 use bsky_sdk::api::types::{BlobRef, Union};
 use bsky_sdk::rich_text::RichText;
-use image_compressor::Factor;
-use image_compressor::compressor::Compressor;
+use futures::{StreamExt, TryStreamExt, stream};
 use megalodon::Megalodon;
 use megalodon::megalodon::PostStatusOutput;
 use megalodon::megalodon::UploadMediaInputOptions;
@@ -22,18 +41,15 @@ use megalodon::{
     megalodon::PostStatusInputOptions,
 };
 use scraper::{Html, Selector};
-use serde_json::to_string;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU64;
 use std::path::Path;
-use std::process::Command;
 use std::time::Duration;
 use std::vec;
-use tempfile::NamedTempFile;
 use tempfile::tempdir;
 use tokio::fs::File;
-use tokio::fs::metadata;
-use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
 use tokio::time::sleep;
 use url::Url;
 
@@ -44,128 +60,211 @@ struct LinkPreviewMetadata {
     image_url: String,
 }
 
-/// Send new status with any given replies to Mastodon.
-pub async fn post_to_mastodon(
-    mastodon: &(dyn Megalodon + Send + Sync),
-    toot: &NewStatus,
+/// IDs of the parts of a thread that are already posted, keyed by their
+/// position: "" for the root, "0" for its first reply, "0.1" for the second
+/// reply to that and so on.
+pub type ThreadProgress = BTreeMap<String, String>;
+
+/// Sends a new status with any given replies to the network. Returns the ID
+/// of the new post, or None on a dry run. Up to `parallel` attachments of a
+/// post are transferred at the same time. Parts of the thread in `posted` are
+/// not sent again, every part that is sent is recorded there so that a failed
+/// thread can be continued later.
+pub async fn post_thread<N: SocialNetwork>(
+    network: &N,
+    status: &NewStatus,
     dry_run: bool,
-) -> Result<()> {
-    if let Some(reply_to) = &toot.in_reply_to_id {
-        println!(
-            "Posting thread reply for {} to Mastodon: {}",
-            reply_to, toot.text
+    parallel: usize,
+    posted: &mut ThreadProgress,
+) -> Result<Option<String>> {
+    let name = network.name();
+    if let Some(reply_to) = &status.in_reply_to_id {
+        progress!(
+            "Posting thread reply for {} to {name}: {}",
+            reply_to,
+            status.text
         );
     } else {
-        println!("Posting to Mastodon: {}", toot.text);
+        progress!("Posting to {name}: {}", status.text);
     }
     let mut status_id = "".to_string();
     if !dry_run {
-        status_id = send_single_post_to_mastodon(mastodon, toot).await?;
+        status_id = send_post_once(
+            network,
+            status,
+            status.in_reply_to_id.as_deref(),
+            parallel,
+            posted,
+            String::new(),
+        )
+        .await?;
     }
 
     // Recursion does not work well with async functions, so we use iteration
     // here instead.
     let mut replies = Vec::new();
-    for reply in &toot.replies {
-        replies.push((status_id.clone(), reply));
+    for (index, reply) in status.replies.iter().enumerate() {
+        replies.push((index.to_string(), status_id.clone(), reply));
     }
 
     while !replies.is_empty() {
-        let (parent_id, reply) = replies.remove(0);
-        let mut new_reply = reply.clone();
-        // Set the new ID of the parent status to reply to.
-        new_reply.in_reply_to_id = Some(parent_id.clone());
-
-        println!(
-            "Posting thread reply for {} to Mastodon: {}",
-            &parent_id, reply.text
+        let (position, parent_id, reply) = replies.remove(0);
+
+        progress!(
+            "Posting thread reply for {} to {name}: {}",
+            &parent_id,
+            reply.text
         );
         let mut parent_status_id = "".to_string();
         if !dry_run {
-            parent_status_id = send_single_post_to_mastodon(mastodon, &new_reply).await?;
+            parent_status_id = send_post_once(
+                network,
+                reply,
+                Some(&parent_id),
+                parallel,
+                posted,
+                position.clone(),
+            )
+            .await?;
         }
-        for remaining_reply in &reply.replies {
-            replies.push((parent_status_id.clone(), remaining_reply));
+        for (index, remaining_reply) in reply.replies.iter().enumerate() {
+            replies.push((
+                format!("{position}.{index}"),
+                parent_status_id.clone(),
+                remaining_reply,
+            ));
         }
     }
 
-    Ok(())
+    Ok(if dry_run { None } else { Some(status_id) })
 }
 
-/// Sends the given new status to Mastodon.
-async fn send_single_post_to_mastodon(
-    mastodon: &(dyn Megalodon + Send + Sync),
-    toot: &NewStatus,
+/// Sends a part of a thread unless it was posted before. Returns its ID.
+async fn send_post_once<N: SocialNetwork>(
+    network: &N,
+    status: &NewStatus,
+    reply_to: Option<&str>,
+    parallel: usize,
+    posted: &mut ThreadProgress,
+    position: String,
 ) -> Result<String> {
-    // Post attachments first, if there are any.
-    let mut media_ids = Vec::new();
-    if let Some(video_stream) = &toot.video_stream {
-        let media_id = mastodon_upload_video_stream(mastodon, video_stream).await?;
-        media_ids.push(media_id);
+    if let Some(id) = posted.get(&position) {
+        return Ok(id.clone());
     }
-    // Temporary directory where we will download any file attachments to.
+    let id = send_post(network, status, reply_to, parallel).await?;
+    posted.insert(position, id.clone());
+    Ok(id)
+}
+
+/// Uploads the attachments of a single status and creates it. The uploads run
+/// concurrently, the media keeps the order of the attachments.
+async fn send_post<N: SocialNetwork>(
+    network: &N,
+    status: &NewStatus,
+    reply_to: Option<&str>,
+    parallel: usize,
+) -> Result<String> {
+    let media: Vec<_> = stream::iter(&status.attachments)
+        .map(|attachment| network.upload_media(attachment, status))
+        .buffered(parallel.max(1))
+        .try_collect()
+        .await?;
+    let media = media.into_iter().flatten().collect();
+    network.create_post(status, media, reply_to).await
+}
+
+/// Downloads an attachment and uploads it to Mastodon. Returns the media ID.
+pub async fn mastodon_upload_attachment(
+    mastodon: &(dyn Megalodon + Send + Sync),
+    http: &WebClient,
+    attachment: &NewMedia,
+) -> Result<String> {
+    // Temporary directory where we will download the file attachment to.
     let temp_dir = tempdir()?;
-    for attachment in &toot.attachments {
-        let response = reqwest::get(&attachment.attachment_url)
-            .await
-            .context(format!(
-                "Failed downloading attachment {}",
-                attachment.attachment_url
-            ))?;
-        let file_name = match Path::new(response.url().path()).file_name() {
-            Some(f) => f,
-            None => bail!(
-                "Failed to create file name from attachment {}",
-                attachment.attachment_url
-            ),
-        };
+    let response = http
+        .download(&attachment.attachment_url)
+        .await
+        .context(format!(
+            "Failed downloading attachment {}",
+            attachment.attachment_url
+        ))?;
+    let file_name = match Path::new(response.url().path()).file_name() {
+        Some(f) => f,
+        None => bail!(
+            "Failed to create file name from attachment {}",
+            attachment.attachment_url
+        ),
+    };
 
-        let path = temp_dir.path().join(file_name);
-        let string_path = path.to_string_lossy().into_owned();
-
-        let mut file = File::create(path).await?;
-        file.write_all(&response.bytes().await?).await?;
-
-        let upload = match &attachment.alt_text {
-            None => mastodon.upload_media(string_path, None).await?,
-            Some(description) => {
-                mastodon
-                    .upload_media(
-                        string_path,
-                        Some(&UploadMediaInputOptions {
-                            description: Some(description.clone()),
-                            focus: None,
-                        }),
-                    )
-                    .await?
-            }
+    let path = temp_dir.path().join(file_name);
+    let string_path = path.to_string_lossy().into_owned();
+
+    let mut file = File::create(path).await?;
+    file.write_all(&http.bytes(response).await?).await?;
+
+    let options = UploadMediaInputOptions {
+        description: attachment.alt_text.clone(),
+        focus: None,
+    };
+    let upload = mastodon_retry("media upload", true, || {
+        mastodon.upload_media(string_path.clone(), Some(&options))
+    })
+    .await?
+    .json();
+
+    Ok(match upload {
+        entities::UploadMedia::Attachment(attachment) => attachment.id,
+        entities::UploadMedia::AsyncAttachment(async_attachment) => {
+            mastodon_wait_until_uploaded(mastodon, &async_attachment.id)
+                .await?
+                .id
         }
-        .json();
+    })
+}
 
-        match upload {
-            entities::UploadMedia::Attachment(attachment) => {
-                media_ids.push(attachment.id);
-            }
-            entities::UploadMedia::AsyncAttachment(async_attachment) => {
-                let uploaded = mastodon_wait_until_uploaded(mastodon, &async_attachment.id).await?;
-                media_ids.push(uploaded.id);
+/// Creates a new status on Mastodon with already uploaded media. A Bluesky
+/// video stream is converted and uploaded first, or linked if it cannot be
+/// converted.
+pub async fn mastodon_create_status(
+    mastodon: &(dyn Megalodon + Send + Sync),
+    http: &WebClient,
+    media: &MediaConfig,
+    toot: &NewStatus,
+    mut media_ids: Vec<String>,
+    reply_to: Option<&str>,
+) -> Result<String> {
+    let mut text = toot.text.clone();
+    if let Some(video_stream) = &toot.video_stream {
+        match mastodon_upload_video_stream(mastodon, http, media, video_stream, &toot.language)
+            .await
+        {
+            Ok(media_id) => media_ids.insert(0, media_id),
+            Err(error) if is_unconvertible(&error) => {
+                eprintln!(
+                    "Warning: cannot convert video stream {video_stream}, linking the original post instead: {error:#}"
+                );
+                text = toot_with_full_video_link(
+                    &toot.text,
+                    &bsky_post_web_url(&toot.original_post_url),
+                );
             }
+            Err(error) => return Err(error),
         }
     }
 
-    let status = mastodon
-        .post_status(
-            toot.text.clone(),
-            Some(&PostStatusInputOptions {
-                media_ids: Some(media_ids),
-                sensitive: Some(false),
-                visibility: Some(StatusVisibility::Public),
-                language: Some(toot.language.clone()),
-                ..Default::default()
-            }),
-        )
-        .await?
-        .json();
+    let options = PostStatusInputOptions {
+        media_ids: Some(media_ids),
+        in_reply_to_id: reply_to.map(String::from),
+        sensitive: Some(false),
+        visibility: Some(StatusVisibility::Public),
+        language: Some(toot.language.clone()),
+        ..Default::default()
+    };
+    let status = mastodon_retry("post status", false, || {
+        mastodon.post_status(text.clone(), Some(&options))
+    })
+    .await?
+    .json();
 
     match status {
         PostStatusOutput::Status(status) => Ok(status.id),
@@ -177,24 +276,107 @@ async fn send_single_post_to_mastodon(
 }
 
 // Download a Bluesky video stream, convert it with ffmpeg and upload it to
-// Mastodon. Returns the media ID of the uploaded video.
+// Mastodon. Without ffmpeg, or if it fails, H.264 streams are remuxed in
+// Rust. Captions become the description of the video or are burned in.
+// Returns the media ID of the uploaded video.
 async fn mastodon_upload_video_stream(
     mastodon: &(dyn Megalodon + Send + Sync),
+    http: &WebClient,
+    media: &MediaConfig,
     stream_url: &str,
+    language: &str,
 ) -> Result<String> {
+    let captions = match fetch_stream_captions(http, stream_url, language).await {
+        Ok(captions) => captions,
+        Err(error) => {
+            eprintln!("Warning: failed downloading captions of {stream_url}: {error:#}");
+            None
+        }
+    };
+    let ffmpeg = media_tools().await.ffmpeg;
+    let mut burn_in = captions.is_some() && media.captions == CaptionMode::BurnIn;
+    if burn_in && !ffmpeg {
+        eprintln!(
+            "Warning: ffmpeg is not installed, the captions of {stream_url} become the description of the video instead"
+        );
+        burn_in = false;
+    }
     let temp_dir = tempdir()?;
     let path = temp_dir.path().join("video.mp4");
-    let command = Command::new("ffmpeg")
+    let mut converted = false;
+    if ffmpeg {
+        let burned_captions = captions.as_deref().filter(|_| burn_in);
+        match ffmpeg_stream_to_mp4(stream_url, burned_captions, &path).await {
+            Ok(()) => converted = true,
+            Err(error) => {
+                eprintln!(
+                    "Warning: ffmpeg failed converting video stream {stream_url}, trying without it: {error:#}"
+                );
+                burn_in = false;
+            }
+        }
+    }
+    if !converted {
+        tokio::fs::write(&path, download_stream_mp4(http, stream_url).await?).await?;
+    }
+    let description = captions
+        .filter(|_| !burn_in)
+        .map(|captions| vtt_text(&captions))
+        .filter(|text| !text.is_empty())
+        .map(|text| truncate_text(&text, MASTODON_DESCRIPTION_LIMIT));
+
+    let options = UploadMediaInputOptions {
+        description,
+        focus: None,
+    };
+    let upload = mastodon_retry("video upload", true, || {
+        mastodon.upload_media(path.to_string_lossy().to_string(), Some(&options))
+    })
+    .await?
+    .json();
+
+    Ok(match upload {
+        entities::UploadMedia::Attachment(attachment) => attachment.id,
+        entities::UploadMedia::AsyncAttachment(async_attachment) => {
+            let uploaded = mastodon_wait_until_uploaded(mastodon, &async_attachment.id).await?;
+            uploaded.id
+        }
+    })
+}
+
+/// Converts an HLS stream to an MP4 file with ffmpeg. Captions are drawn
+/// into the picture, which needs the video to be encoded again.
+async fn ffmpeg_stream_to_mp4(stream_url: &str, captions: Option<&str>, path: &Path) -> Result<()> {
+    let mut ffmpeg = Command::new("ffmpeg");
+    ffmpeg
+        .arg("-user_agent")
+        .arg(USER_AGENT)
         .arg("-i")
         .arg(stream_url)
         .arg("-acodec")
         .arg("copy")
         .arg("-bsf:a")
-        .arg("aac_adtstoasc")
-        .arg("-vcodec")
-        .arg("copy")
+        .arg("aac_adtstoasc");
+    let captions_dir = tempdir()?;
+    match captions {
+        Some(captions) => {
+            let captions_path = captions_dir.path().join("captions.vtt");
+            tokio::fs::write(&captions_path, captions).await?;
+            ffmpeg
+                .arg("-vf")
+                .arg(format!("subtitles={}", captions_path.to_string_lossy()))
+                .args([
+                    "-vcodec", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p",
+                ]);
+        }
+        None => {
+            ffmpeg.arg("-vcodec").arg("copy");
+        }
+    }
+    let command = ffmpeg
         .arg(path.to_string_lossy().to_string())
         .output()
+        .await
         .context(format!(
             "Failed to execute ffmpeg for video stream {stream_url}"
         ))?;
@@ -204,19 +386,7 @@ async fn mastodon_upload_video_stream(
             String::from_utf8_lossy(&command.stderr)
         );
     }
-
-    let upload = mastodon
-        .upload_media(path.to_string_lossy().to_string(), None)
-        .await?
-        .json();
-
-    Ok(match upload {
-        entities::UploadMedia::Attachment(attachment) => attachment.id,
-        entities::UploadMedia::AsyncAttachment(async_attachment) => {
-            let uploaded = mastodon_wait_until_uploaded(mastodon, &async_attachment.id).await?;
-            uploaded.id
-        }
-    })
+    Ok(())
 }
 
 async fn mastodon_wait_until_uploaded(
@@ -224,7 +394,7 @@ async fn mastodon_wait_until_uploaded(
     id: &str,
 ) -> Result<entities::Attachment, error::Error> {
     loop {
-        let res = client.get_media(id.to_string()).await;
+        let res = mastodon_retry("media status", true, || client.get_media(id.to_string())).await;
         return match res {
             Ok(res) => Ok(res.json()),
             Err(err) => match err {
@@ -241,99 +411,177 @@ async fn mastodon_wait_until_uploaded(
     }
 }
 
-/// Send a new status update to Bluesky, including thread replies and
-/// attachments.
-pub async fn post_to_bluesky(
+/// An attachment uploaded to Bluesky.
+pub enum BlueskyMedia {
+    Image(Box<Image>),
+    /// A video or a link embed replacing it.
+    Embed(Union<RecordEmbedRefs>),
+    /// A video that was cut to the maximum duration, with the URL of the
+    /// original post that has the full video.
+    CutVideo(Union<RecordEmbedRefs>, String),
+}
+
+/// Downloads an attachment and uploads it to Bluesky. Audio becomes a video
+/// or a link to the original post, other attachments that are neither images
+/// nor videos are left out.
+pub async fn bluesky_upload_attachment(
     bsky_agent: &BskyAgent,
+    http: &WebClient,
+    video_service: &str,
+    media: &MediaConfig,
+    attachment: &NewMedia,
     post: &NewStatus,
-    dry_run: bool,
-) -> Result<()> {
-    if let Some(reply_to) = &post.in_reply_to_id {
-        println!(
-            "Posting thread reply for {} to Bluesky: {}",
-            reply_to, post.text
-        );
-    } else {
-        println!("Posting to Bluesky: {}", post.text);
-    }
-    let mut status_id = "".to_string();
-    if !dry_run {
-        status_id = send_single_post_to_bluesky(bsky_agent, post).await?;
-    }
-
-    // Recursion does not work well with async functions, so we use iteration
-    // here instead.
-    let mut replies = Vec::new();
-    for reply in &post.replies {
-        replies.push((status_id.clone(), reply));
-    }
+) -> Result<Option<BlueskyMedia>> {
+    let response = http
+        .download(&attachment.attachment_url)
+        .await
+        .context(format!(
+            "Failed downloading attachment {}",
+            attachment.attachment_url
+        ))?;
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .context(format!(
+            "Failed getting content type of {}",
+            &attachment.attachment_url
+        ))?
+        .to_str()
+        .context(format!(
+            "Failed converting content type of {} to string",
+            &attachment.attachment_url
+        ))?
+        .to_string();
+    let bytes = http.bytes(response).await?;
 
-    while !replies.is_empty() {
-        let (parent_id, reply) = replies.remove(0);
-        let mut new_reply = reply.clone();
-        // Set the new ID of the parent status to reply to.
-        new_reply.in_reply_to_id = Some(parent_id.clone());
-
-        println!(
-            "Posting thread reply for {} to Bluesky: {}",
-            &parent_id, reply.text
-        );
-        let mut parent_status_id = "".to_string();
-        if !dry_run {
-            parent_status_id = send_single_post_to_bluesky(bsky_agent, &new_reply).await?;
+    if content_type.starts_with("image/") {
+        match prepare_media(&bytes, &attachment.attachment_url).await? {
+            PreparedMedia::Image(image) => {
+                let dimensions = attachment.dimensions.unwrap_or(image.dimensions);
+                let blob = bluesky_upload_blob(image.bytes, &attachment.attachment_url, bsky_agent)
+                    .await?;
+                Ok(Some(BlueskyMedia::Image(Box::new(
+                    bsky_sdk::api::app::bsky::embed::images::ImageData {
+                        alt: attachment.alt_text.clone().unwrap_or_default(),
+                        // The server knows the size of the original image.
+                        aspect_ratio: bluesky_aspect_ratio(Some(dimensions)),
+                        image: blob,
+                    }
+                    .into(),
+                ))))
+            }
+            PreparedMedia::Video(video) => {
+                // Converted GIFs are shown like GIFV attachments.
+                let animation = NewMedia {
+                    kind: MediaKind::Gifv,
+                    ..attachment.clone()
+                };
+                Ok(Some(
+                    bluesky_upload_or_embed_video(
+                        &video,
+                        &animation,
+                        post,
+                        bsky_agent,
+                        http,
+                        video_service,
+                        media,
+                    )
+                    .await?,
+                ))
+            }
         }
-        for remaining_reply in &reply.replies {
-            replies.push((parent_status_id.clone(), remaining_reply));
+    } else if content_type.starts_with("video/") {
+        Ok(Some(
+            bluesky_upload_or_embed_video(
+                &bytes,
+                attachment,
+                post,
+                bsky_agent,
+                http,
+                video_service,
+                media,
+            )
+            .await?,
+        ))
+    } else if content_type.starts_with("audio/") || attachment.kind == MediaKind::Audio {
+        if media.audio == AudioMode::Link {
+            return Ok(Some(BlueskyMedia::Embed(
+                bluesky_original_post_embed(post, bsky_agent, http).await?,
+            )));
         }
+        let cover = match &attachment.cover_url {
+            Some(cover_url) => match download_bytes(http, cover_url).await {
+                Ok(cover) => Some(cover),
+                Err(error) => {
+                    eprintln!("Warning: failed downloading cover image {cover_url}: {error:#}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let video = match audio_to_mp4(&bytes, cover.as_deref()).await {
+            Ok(video) => video,
+            Err(error) if is_unconvertible(&error) => {
+                eprintln!(
+                    "Warning: cannot convert audio {} to a video, linking the original post instead: {error:#}",
+                    attachment.attachment_url
+                );
+                return Ok(Some(BlueskyMedia::Embed(
+                    bluesky_original_post_embed(post, bsky_agent, http).await?,
+                )));
+            }
+            Err(error) => {
+                return Err(error.context(format!(
+                    "Failed converting audio {} to a video",
+                    attachment.attachment_url
+                )));
+            }
+        };
+        Ok(Some(
+            bluesky_upload_or_embed_video(
+                &video,
+                attachment,
+                post,
+                bsky_agent,
+                http,
+                video_service,
+                media,
+            )
+            .await?,
+        ))
+    } else {
+        Ok(None)
     }
-
-    Ok(())
 }
 
-/// Sends the given new status to Bluesky.
-async fn send_single_post_to_bluesky(bsky_agent: &BskyAgent, post: &NewStatus) -> Result<String> {
-    // Compute richtext once to extract links for preview embeds and to use in the record
-    let rt = get_rich_text(&post.text);
+/// Creates a new post on Bluesky with already uploaded media. Returns the
+/// record URI of the new post.
+pub async fn bluesky_create_post(
+    bsky_agent: &BskyAgent,
+    http: &WebClient,
+    post: &NewStatus,
+    media: Vec<BlueskyMedia>,
+) -> Result<String> {
     let mut images = Vec::new();
     let mut embed = None;
-    for attachment in &post.attachments {
-        let response = reqwest::get(&attachment.attachment_url)
-            .await
-            .context(format!(
-                "Failed downloading attachment {}",
-                attachment.attachment_url
-            ))?;
-        let content_type = response
-            .headers()
-            .get("content-type")
-            .context(format!(
-                "Failed getting content type of {}",
-                &attachment.attachment_url
-            ))?
-            .to_str()
-            .context(format!(
-                "Failed converting content type of {} to string",
-                &attachment.attachment_url
-            ))?
-            .to_string();
-        let bytes = response.bytes().await?;
-
-        if content_type.starts_with("image/") {
-            images.push(
-                bsky_sdk::api::app::bsky::embed::images::ImageData {
-                    alt: attachment.alt_text.clone().unwrap_or_default(),
-                    aspect_ratio: None,
-                    image: bluesky_upload_image(&bytes, &attachment.attachment_url, bsky_agent)
-                        .await?,
-                }
-                .into(),
-            );
-        } else if content_type.starts_with("video/") {
-            embed =
-                Some(bluesky_upload_or_embed_video(&bytes, attachment, post, bsky_agent).await?);
-            break;
+    let mut text = post.text.clone();
+    for uploaded in media {
+        match uploaded {
+            BlueskyMedia::Image(image) => images.push(*image),
+            // A post can only have one video, which replaces any images.
+            BlueskyMedia::Embed(video) => {
+                embed = Some(video);
+                break;
+            }
+            BlueskyMedia::CutVideo(video, full_video_url) => {
+                embed = Some(video);
+                text = bsky_post_with_full_video_link(&post.text, &full_video_url);
+                break;
+            }
         }
     }
+    // Compute richtext once to extract links for preview embeds and to use in the record
+    let rt = get_rich_text(&text);
     // If there is no video then use the images.
     if embed.is_none() {
         if !images.is_empty() {
@@ -345,7 +593,7 @@ async fn send_single_post_to_bluesky(bsky_agent: &BskyAgent, post: &NewStatus) -
         } else {
             // If there are no attachments, try to create a link preview embed
             // if there are any links in the post.
-            embed = bluesky_link_preview_embed(&rt, bsky_agent).await;
+            embed = bluesky_link_preview_embed(&rt, bsky_agent, http).await;
         }
     }
     let languages = match Language::new(post.language.clone()) {
@@ -366,6 +614,8 @@ async fn send_single_post_to_bluesky(bsky_agent: &BskyAgent, post: &NewStatus) -
             facets: rt.facets,
             labels: None,
             langs: languages,
+            // Replies need references to the root and parent post including
+            // their CIDs, threads are not synced to Bluesky yet.
             reply: None,
             tags: None,
             text: rt.text,
@@ -373,20 +623,21 @@ async fn send_single_post_to_bluesky(bsky_agent: &BskyAgent, post: &NewStatus) -
         .await
         .context(format!("Failed posting to Bluesky {}", post.text))?;
 
-    Ok(to_string(&record.cid)?)
+    Ok(record.uri.clone())
 }
 
 // Extract links from richtext facets and fetch preview embeds for the first successful link
 async fn bluesky_link_preview_embed(
     rt: &RichText,
     bsky_agent: &BskyAgent,
+    http: &WebClient,
 ) -> Option<bsky_sdk::api::types::Union<RecordEmbedRefs>> {
     // Try to fetch a link preview for each link facet, in reverse order
     if let Some(facets) = &rt.facets {
         for facet in facets.iter().rev() {
             for feature in &facet.features {
                 if let Union::Refs(MainFeaturesItem::Link(link)) = feature
-                    && let Some(embed) = fetch_link_preview_embed(&link.uri, bsky_agent).await
+                    && let Some(embed) = fetch_link_preview_embed(&link.uri, bsky_agent, http).await
                 {
                     return Some(embed);
                 }
@@ -399,11 +650,12 @@ async fn bluesky_link_preview_embed(
 async fn fetch_link_preview_embed(
     url: &str,
     bsky_agent: &BskyAgent,
+    http: &WebClient,
 ) -> Option<bsky_sdk::api::types::Union<RecordEmbedRefs>> {
-    let response = match reqwest::get(url).await {
+    let response = match http.download_link_preview(url).await {
         Ok(response) => response,
         Err(error) => {
-            eprintln!("Warning: failed fetching link preview {url}: {error}");
+            eprintln!("Warning: failed fetching link preview {url}: {error:#}");
             return None;
         }
     };
@@ -415,10 +667,10 @@ async fn fetch_link_preview_embed(
         return None;
     }
     let final_url = response.url().clone();
-    let html = match response.text().await {
+    let html = match http.text(response).await {
         Ok(html) => html,
         Err(error) => {
-            eprintln!("Warning: failed reading link preview body {url}: {error}");
+            eprintln!("Warning: failed reading link preview body {url}: {error:#}");
             return None;
         }
     };
@@ -427,11 +679,11 @@ async fn fetch_link_preview_embed(
         None => return None,
     };
 
-    let thumb_response = match reqwest::get(&metadata.image_url).await {
+    let thumb_response = match http.download(&metadata.image_url).await {
         Ok(response) => response,
         Err(error) => {
             eprintln!(
-                "Warning: failed downloading link preview image {}: {error}",
+                "Warning: failed downloading link preview image {}: {error:#}",
                 metadata.image_url
             );
             return None;
@@ -445,18 +697,18 @@ async fn fetch_link_preview_embed(
         );
         return None;
     }
-    let thumb_bytes = match thumb_response.bytes().await {
+    let thumb_bytes = match http.bytes(thumb_response).await {
         Ok(bytes) => bytes,
         Err(error) => {
             eprintln!(
-                "Warning: failed reading link preview image {}: {error}",
+                "Warning: failed reading link preview image {}: {error:#}",
                 metadata.image_url
             );
             return None;
         }
     };
     let thumb = match bluesky_upload_image(&thumb_bytes, &metadata.image_url, bsky_agent).await {
-        Ok(blob) => blob,
+        Ok((blob, _)) => blob,
         Err(error) => {
             eprintln!(
                 "Warning: failed uploading link preview image {}: {error:#}",
@@ -540,159 +792,280 @@ fn parse_social_metadata(html: &str) -> HashMap<String, String> {
     metadata
 }
 
-async fn resize_image_if_needed(download_bytes: &[u8], url: &str) -> Result<Vec<u8>> {
-    // Check that the image is not larger than 1MB.
-    let size = download_bytes.len();
-    if size > 1_000_000 {
-        let tmp_file = NamedTempFile::new()?;
-        let mut source_file = File::create(tmp_file.path()).await?;
-        source_file.write_all(download_bytes).await?;
-        // Try with 100% quality first, then decrease by 10% until we
-        // get less than 1MB.
-        let mut quality = 100.;
-        loop {
-            let dest_dir = tempdir()?;
-            let mut compressor = Compressor::new(tmp_file.path(), dest_dir.path());
-            compressor.set_factor(Factor::new(quality, 1.0));
-            // Dyn errors are weird, can't throw them with `?`.`
-            let compressed = match compressor.compress_to_jpg() {
-                Ok(compressed) => compressed,
-                Err(e) => {
-                    bail!("Failed compressing image {url} to less than 1MB: {e}");
-                }
-            };
-            let new_size = metadata(&compressed).await?.len();
-            if new_size < 1_000_000 {
-                let mut compressed_file = File::open(compressed).await?;
-                let mut compressed_bytes = Vec::new();
-                compressed_file.read_to_end(&mut compressed_bytes).await?;
-                return Ok(compressed_bytes);
-            }
-            quality -= 10.;
-            if quality < 0.1 {
-                bail!("Could not compress image {url} to less than 1MB");
-            }
-        }
-    }
-    Ok(download_bytes.to_vec())
+async fn download_bytes(http: &WebClient, url: &str) -> Result<Vec<u8>> {
+    let response = http.download(url).await?;
+    http.bytes(response).await
 }
 
-// Before uploading a video to Bluesky, we need to check if it is less than 60
-// seconds. When it is longer we embed it as external post instead.
+// Before uploading a video to Bluesky, we check its duration and size. Videos
+// over the limits of the config are embedded as link to the original post, or
+// cut and encoded again to fit. GIFV attachments are shown like GIFs.
 async fn bluesky_upload_or_embed_video(
     video_bytes: &[u8],
     attachment: &NewMedia,
     post: &NewStatus,
     bsky_agent: &BskyAgent,
-) -> Result<bsky_sdk::api::types::Union<RecordEmbedRefs>> {
-    // Save video bytes to a temporary file and check if it is less than
-    // 60 seconds.
-    let tmp_file = NamedTempFile::new()?;
-    let mut video_file = File::create(tmp_file.path()).await?;
-    video_file.write_all(video_bytes).await?;
-    let ffprobe_output = Command::new("ffprobe")
-        .arg("-v")
-        .arg("error")
-        .arg("-show_entries")
-        .arg("format=duration")
-        .arg("-of")
-        .arg("default=noprint_wrappers=1:nokey=1")
-        .arg(tmp_file.path())
-        .output()
-        .context(format!(
-            "Failed to execute ffprobe for video {}",
-            attachment.attachment_url
-        ))?;
-    let duration: f64 = String::from_utf8_lossy(&ffprobe_output.stdout)
-        .trim()
-        .parse()
-        .context(format!(
-            "Failed to parse ffprobe output for video {}",
+    http: &WebClient,
+    video_service: &str,
+    media: &MediaConfig,
+) -> Result<BlueskyMedia> {
+    let probe = match probe_video(video_bytes).await {
+        Ok(probe) => probe,
+        Err(error) if is_unconvertible(&error) => {
+            eprintln!(
+                "Warning: cannot check video {} against the Bluesky limits, linking the original post instead: {error:#}",
+                attachment.attachment_url
+            );
+            return Ok(BlueskyMedia::Embed(
+                bluesky_original_post_embed(post, bsky_agent, http).await?,
+            ));
+        }
+        Err(error) => {
+            return Err(error.context(format!(
+                "Failed to probe video {}",
+                attachment.attachment_url
+            )));
+        }
+    };
+    let max_duration = media.max_video_duration as f64;
+    let max_size = media.max_video_size * 1024 * 1024;
+    let mut video = video_bytes.to_vec();
+    let mut cut = false;
+    if probe.duration > max_duration || video.len() as u64 > max_size {
+        if media.oversized_videos == OversizedVideos::Link {
+            return Ok(BlueskyMedia::Embed(
+                bluesky_original_post_embed(post, bsky_agent, http).await?,
+            ));
+        }
+        progress!(
+            "Transcoding video {} to fit the Bluesky limits...",
             attachment.attachment_url
-        ))?;
-    // If the video is longer then embed the original toot as link
-    // embed.
-    if duration > 60. {
-        let response = reqwest::get(&post.original_post_url)
-            .await
-            .context(format!(
-                "Failed extracting link preview {}",
-                post.original_post_url
-            ))?;
-        let html_bytes = response.bytes().await?;
-        let html = webpage::HTML::from_string(
-            String::from_utf8_lossy(&html_bytes).to_string(),
-            Some(post.original_post_url.clone()),
-        )
-        .context(format!(
-            "Failed parsing HTML from {}",
-            post.original_post_url
-        ))?;
-        let thumb = match html.opengraph.images.first() {
-            Some(image) => {
-                let thumb_bytes = reqwest::get(&image.url)
-                    .await
-                    .context(format!("Failed downloading thumbnail {}", image.url))?
-                    .bytes()
-                    .await?;
-                Some(bluesky_upload_image(&thumb_bytes, &image.url, bsky_agent).await?)
+        );
+        match fit_video(video_bytes, probe.duration, max_duration, max_size).await {
+            Ok(fitted) => {
+                video = fitted;
+                cut = probe.duration > max_duration;
             }
-            None => None,
-        };
-        let external = bsky_sdk::api::app::bsky::embed::external::MainData {
-            external: bsky_sdk::api::app::bsky::embed::external::ExternalData {
-                description: html
-                    .opengraph
-                    .properties
-                    .get("description")
-                    .unwrap_or(&"".to_string())
-                    .to_string(),
-                thumb,
-                title: html
-                    .opengraph
-                    .properties
-                    .get("title")
-                    .unwrap_or(&"".to_string())
-                    .to_string(),
-                uri: post.original_post_url.clone(),
+            Err(error) => {
+                eprintln!(
+                    "Warning: failed transcoding video {}, linking the original post instead: {error:#}",
+                    attachment.attachment_url
+                );
+                return Ok(BlueskyMedia::Embed(
+                    bluesky_original_post_embed(post, bsky_agent, http).await?,
+                ));
             }
-            .into(),
-        };
-        Ok(bsky_sdk::api::types::Union::Refs(
-            bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedExternalMain(
-                Box::new(external.into()),
-            ),
+        }
+    }
+    let blob = match bluesky_upload_video(
+        bsky_agent,
+        http,
+        video_service,
+        &attachment.attachment_url,
+        video,
+    )
+    .await?
+    {
+        VideoUpload::Uploaded(blob) => blob,
+        VideoUpload::LimitReached(reason) => {
+            eprintln!(
+                "Warning: not uploading video {} to Bluesky, linking the original post instead: {reason}",
+                attachment.attachment_url
+            );
+            return Ok(BlueskyMedia::Embed(
+                bluesky_original_post_embed(post, bsky_agent, http).await?,
+            ));
+        }
+    };
+    let video = bsky_sdk::api::app::bsky::embed::video::MainData {
+        alt: attachment.alt_text.clone(),
+        aspect_ratio: bluesky_aspect_ratio(probe.dimensions.or(attachment.dimensions)),
+        captions: bluesky_upload_captions(attachment, bsky_agent, http).await,
+        video: blob,
+    };
+    let embed = bluesky_video_embed(video, attachment.kind == MediaKind::Gifv)?;
+    if cut {
+        Ok(BlueskyMedia::CutVideo(
+            embed,
+            post.original_post_url.clone(),
         ))
     } else {
-        let blob = bluesky_upload_video(bsky_agent, &attachment.attachment_url, video_bytes.into())
-            .await?;
-        let video = bsky_sdk::api::app::bsky::embed::video::MainData {
-            alt: attachment.alt_text.clone(),
-            aspect_ratio: None,
-            captions: None,
-            video: blob,
-        };
-        Ok(bsky_sdk::api::types::Union::Refs(
-            bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedVideoMain(Box::new(
-                video.into(),
-            )),
-        ))
+        Ok(BlueskyMedia::Embed(embed))
+    }
+}
+
+/// Uploads the caption files of a video. Captions that cannot be uploaded are
+/// left out with a warning.
+async fn bluesky_upload_captions(
+    attachment: &NewMedia,
+    bsky_agent: &BskyAgent,
+    http: &WebClient,
+) -> Option<Vec<Caption>> {
+    let mut captions = Vec::new();
+    for caption in &attachment.captions {
+        match bluesky_upload_caption(caption, bsky_agent, http).await {
+            Ok(uploaded) => captions.push(uploaded),
+            Err(error) => eprintln!(
+                "Warning: leaving out captions {} of video {}: {error:#}",
+                caption.url, attachment.attachment_url
+            ),
+        }
+    }
+    (!captions.is_empty()).then_some(captions)
+}
+
+async fn bluesky_upload_caption(
+    caption: &NewCaption,
+    bsky_agent: &BskyAgent,
+    http: &WebClient,
+) -> Result<Caption> {
+    let lang = Language::new(caption.language.clone())
+        .map_err(|e| anyhow!("Invalid language tag '{}': {e}", caption.language))?;
+    let bytes = download_bytes(http, &caption.url)
+        .await
+        .context(format!("Failed downloading captions {}", caption.url))?;
+    if !bytes
+        .strip_prefix("\u{feff}".as_bytes())
+        .unwrap_or(&bytes)
+        .starts_with(b"WEBVTT")
+    {
+        bail!("{} is not a WebVTT file", caption.url);
+    }
+    if bytes.len() > BLUESKY_CAPTION_LIMIT {
+        bail!(
+            "{} is larger than the Bluesky limit of {BLUESKY_CAPTION_LIMIT} bytes",
+            caption.url
+        );
+    }
+    let file = bluesky_upload_caption_blob(bsky_agent, http, bytes)
+        .await
+        .context(format!(
+            "Failed uploading captions {} to Bluesky",
+            caption.url
+        ))?;
+    Ok(bsky_sdk::api::app::bsky::embed::video::CaptionData { file, lang }.into())
+}
+
+fn bluesky_video_embed(
+    video: bsky_sdk::api::app::bsky::embed::video::MainData,
+    looping: bool,
+) -> Result<bsky_sdk::api::types::Union<RecordEmbedRefs>> {
+    let mut video: bsky_sdk::api::app::bsky::embed::video::Main = video.into();
+    if looping {
+        // Clients play GIF presentations in a loop without sound controls.
+        // This version of the lexicon does not know the field yet, so it is
+        // added as extra data.
+        let mut json = serde_json::to_value(&video)?;
+        json["presentation"] = "gif".into();
+        video = serde_json::from_value(json)?;
     }
+    Ok(bsky_sdk::api::types::Union::Refs(
+        bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedVideoMain(Box::new(
+            video,
+        )),
+    ))
 }
 
+/// A link preview of the original post, for attachments that cannot be
+/// uploaded to Bluesky.
+async fn bluesky_original_post_embed(
+    post: &NewStatus,
+    bsky_agent: &BskyAgent,
+    http: &WebClient,
+) -> Result<bsky_sdk::api::types::Union<RecordEmbedRefs>> {
+    let response = http
+        .download(&post.original_post_url)
+        .await
+        .context(format!(
+            "Failed extracting link preview {}",
+            post.original_post_url
+        ))?;
+    let html_bytes = http.bytes(response).await?;
+    let html = webpage::HTML::from_string(
+        String::from_utf8_lossy(&html_bytes).to_string(),
+        Some(post.original_post_url.clone()),
+    )
+    .context(format!(
+        "Failed parsing HTML from {}",
+        post.original_post_url
+    ))?;
+    let thumb = match html.opengraph.images.first() {
+        Some(image) => {
+            let response = http
+                .download(&image.url)
+                .await
+                .context(format!("Failed downloading thumbnail {}", image.url))?;
+            let thumb_bytes = http.bytes(response).await?;
+            Some(
+                bluesky_upload_image(&thumb_bytes, &image.url, bsky_agent)
+                    .await?
+                    .0,
+            )
+        }
+        None => None,
+    };
+    let external = bsky_sdk::api::app::bsky::embed::external::MainData {
+        external: bsky_sdk::api::app::bsky::embed::external::ExternalData {
+            description: html
+                .opengraph
+                .properties
+                .get("description")
+                .unwrap_or(&"".to_string())
+                .to_string(),
+            thumb,
+            title: html
+                .opengraph
+                .properties
+                .get("title")
+                .unwrap_or(&"".to_string())
+                .to_string(),
+            uri: post.original_post_url.clone(),
+        }
+        .into(),
+    };
+    Ok(bsky_sdk::api::types::Union::Refs(
+        bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedExternalMain(Box::new(
+            external.into(),
+        )),
+    ))
+}
+
+/// Bluesky clients reserve space for media with this ratio instead of
+/// cropping it.
+fn bluesky_aspect_ratio(dimensions: Option<(u64, u64)>) -> Option<AspectRatio> {
+    let (width, height) = dimensions?;
+    Some(
+        AspectRatioData {
+            width: NonZeroU64::new(width)?,
+            height: NonZeroU64::new(height)?,
+        }
+        .into(),
+    )
+}
+
+/// Uploads an image, converted and compressed for Bluesky. Returns the blob
+/// and the dimensions of the image.
 async fn bluesky_upload_image(
     image_bytes: &[u8],
     image_url: &str,
     bsky_agent: &BskyAgent,
-) -> Result<BlobRef> {
-    let attachment_bytes = resize_image_if_needed(image_bytes, image_url).await?;
+) -> Result<(BlobRef, (u64, u64))> {
+    let image = prepare_image(image_bytes, image_url, BLUESKY_IMAGE_LIMIT).await?;
+    let blob = bluesky_upload_blob(image.bytes, image_url, bsky_agent).await?;
+    Ok((blob, image.dimensions))
+}
 
+async fn bluesky_upload_blob(
+    image_bytes: Vec<u8>,
+    image_url: &str,
+    bsky_agent: &BskyAgent,
+) -> Result<BlobRef> {
     let output = bsky_agent
         .api
         .com
         .atproto
         .repo
-        .upload_blob(attachment_bytes)
+        .upload_blob(image_bytes)
         .await
         .context(format!("Failed uploading image to Bluesky {}", image_url))?;
     Ok(output.data.blob)
@@ -700,9 +1073,114 @@ async fn bluesky_upload_image(
 
 #[cfg(test)]
 mod tests {
-    use super::{extract_link_preview_metadata, parse_social_metadata};
+    use super::{
+        ThreadProgress, bluesky_aspect_ratio, bluesky_video_embed, extract_link_preview_metadata,
+        parse_social_metadata, post_thread,
+    };
+    use crate::fake_network::FakeNetwork;
+    use crate::sync::{MediaKind, NewMedia, NewStatus};
     use url::Url;
 
+    fn status(text: &str, replies: Vec<NewStatus>) -> NewStatus {
+        NewStatus {
+            text: text.to_string(),
+            replies,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn post_thread_replies_to_the_new_parent_posts() {
+        let mut root = status(
+            "root",
+            vec![
+                status("first", vec![status("nested", Vec::new())]),
+                status("second", Vec::new()),
+            ],
+        );
+        root.attachments.push(NewMedia {
+            attachment_url: "https://example.com/image.png".to_string(),
+            alt_text: None,
+            dimensions: None,
+            kind: MediaKind::Image,
+            cover_url: None,
+            captions: Vec::new(),
+        });
+        let network = FakeNetwork::<()>::new(Vec::new());
+
+        assert_eq!(
+            post_thread(&network, &root, true, 4, &mut ThreadProgress::new())
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(network.created().is_empty());
+
+        let id = post_thread(&network, &root, false, 4, &mut ThreadProgress::new())
+            .await
+            .unwrap();
+        assert_eq!(id.as_deref(), Some("fake-1"));
+        let created: Vec<_> = network
+            .created()
+            .into_iter()
+            .map(|post| (post.id, post.text, post.reply_to))
+            .collect();
+        assert_eq!(
+            created,
+            vec![
+                ("fake-1".into(), "root".into(), None),
+                ("fake-2".into(), "first".into(), Some("fake-1".into())),
+                ("fake-3".into(), "second".into(), Some("fake-1".into())),
+                ("fake-4".into(), "nested".into(), Some("fake-2".into())),
+            ]
+        );
+        assert_eq!(
+            network.created()[0].media,
+            vec!["https://example.com/image.png"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_thread_is_continued_without_the_posted_parts() {
+        let root = status(
+            "root",
+            vec![
+                status("first", vec![status("nested", Vec::new())]),
+                status("second", Vec::new()),
+            ],
+        );
+        let network = FakeNetwork::<()>::new(Vec::new());
+        let mut posted = ThreadProgress::new();
+
+        network.fail_text(Some("second"));
+        assert!(
+            post_thread(&network, &root, false, 4, &mut posted)
+                .await
+                .is_err()
+        );
+        assert_eq!(posted.len(), 2);
+
+        network.fail_text(None);
+        let id = post_thread(&network, &root, false, 4, &mut posted)
+            .await
+            .unwrap();
+        assert_eq!(id.as_deref(), Some("fake-1"));
+        let created: Vec<_> = network
+            .created()
+            .into_iter()
+            .map(|post| (post.text, post.reply_to))
+            .collect();
+        assert_eq!(
+            created,
+            vec![
+                ("root".into(), None),
+                ("first".into(), Some("fake-1".into())),
+                ("second".into(), Some("fake-1".into())),
+                ("nested".into(), Some("fake-2".into())),
+            ]
+        );
+    }
+
     #[test]
     fn parse_social_metadata_supports_property_and_name_attributes() {
         let html = r#"
@@ -770,6 +1248,42 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn attachments_are_uploaded_in_parallel_in_order() {
+        let mut post = status("gallery", Vec::new());
+        for i in 1..=4 {
+            post.attachments.push(NewMedia {
+                attachment_url: format!("https://example.com/{i}.png"),
+                alt_text: None,
+                dimensions: None,
+                kind: MediaKind::Image,
+                cover_url: None,
+                captions: Vec::new(),
+            });
+        }
+
+        let network = FakeNetwork::<()>::new(Vec::new());
+        post_thread(&network, &post, false, 3, &mut ThreadProgress::new())
+            .await
+            .unwrap();
+        assert_eq!(network.max_uploads_in_flight(), 3);
+        assert_eq!(
+            network.created()[0].media,
+            vec![
+                "https://example.com/1.png",
+                "https://example.com/2.png",
+                "https://example.com/3.png",
+                "https://example.com/4.png",
+            ]
+        );
+
+        let network = FakeNetwork::<()>::new(Vec::new());
+        post_thread(&network, &post, false, 1, &mut ThreadProgress::new())
+            .await
+            .unwrap();
+        assert_eq!(network.max_uploads_in_flight(), 1);
+    }
+
     #[test]
     fn parse_social_metadata_keeps_first_og_image() {
         let html = r#"
@@ -788,4 +1302,31 @@ mod tests {
             Some(&"https://example.com/first.jpg".to_string())
         );
     }
+
+    #[test]
+    fn aspect_ratio_needs_both_sides() {
+        let ratio = bluesky_aspect_ratio(Some((40, 30))).unwrap();
+        assert_eq!((ratio.width.get(), ratio.height.get()), (40, 30));
+        assert!(bluesky_aspect_ratio(Some((0, 30))).is_none());
+    }
+
+    #[test]
+    fn looping_videos_are_presented_as_gifs() {
+        let video: bsky_sdk::api::app::bsky::embed::video::MainData =
+            serde_json::from_value(serde_json::json!({
+                "video": {
+                    "$type": "blob",
+                    "ref": { "$link": "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy" },
+                    "mimeType": "video/mp4",
+                    "size": 1000
+                }
+            }))
+            .unwrap();
+        let looping =
+            serde_json::to_value(bluesky_video_embed(video.clone(), true).unwrap()).unwrap();
+        assert_eq!(looping["presentation"], "gif");
+        assert_eq!(looping["video"]["mimeType"], "video/mp4");
+        let plain = serde_json::to_value(bluesky_video_embed(video, false).unwrap()).unwrap();
+        assert!(plain.get("presentation").is_none());
+    }
 }
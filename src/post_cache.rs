@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use bsky_sdk::api::app::bsky::feed::defs::FeedViewPost;
+use chrono::prelude::*;
+use megalodon::entities::Status;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+
+use crate::state_dir::write_atomic;
+
+/// Entries are kept this much longer than strictly needed, in case the clock
+/// of this machine and the servers differ.
+const PRUNE_MARGIN: chrono::Duration = chrono::Duration::days(1);
+
+/// Texts of recently synced posts with the time they were posted. Posts in the
+/// cache are never synced again, which prevents loops between the networks.
+#[derive(Debug, Default, PartialEq)]
+pub struct PostCache {
+    entries: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// The post cache file, older versions stored a set of texts without times.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PostCacheFile {
+    Timestamped(BTreeMap<String, DateTime<Utc>>),
+    Legacy(HashSet<String>),
+}
+
+impl PostCache {
+    /// Reads the JSON encoded cache file from disk or provides an empty
+    /// cache. A corrupted cache is an error because an empty cache would allow
+    /// double posting.
+    pub fn load(cache_file: &str) -> Result<Self> {
+        let Ok(json) = fs::read_to_string(cache_file) else {
+            return Ok(PostCache::default());
+        };
+        let file = serde_json::from_str::<PostCacheFile>(&json).context(format!(
+            "Failed parsing post cache {cache_file}, fix or delete the file"
+        ))?;
+        let entries = match file {
+            PostCacheFile::Timestamped(entries) => entries,
+            // Without times the entries are treated as new, they are pruned
+            // once the timelines have moved past them.
+            PostCacheFile::Legacy(texts) => {
+                let now = Utc::now();
+                texts.into_iter().map(|text| (text, now)).collect()
+            }
+        };
+        Ok(PostCache { entries })
+    }
+
+    pub async fn save(&self, cache_file: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.entries)?;
+        write_atomic(cache_file, json.as_bytes())
+            .await
+            .context(format!("Failed writing {cache_file}"))
+    }
+
+    pub fn contains(&self, text: &str) -> bool {
+        self.entries.contains_key(text)
+    }
+
+    /// Records a post that was just synced.
+    pub fn insert(&mut self, text: String) {
+        self.entries.insert(text, Utc::now());
+    }
+
+    /// Drops the entries that are older than every fetched post. Neither the
+    /// original post nor its copy can show up in a timeline fetch again then.
+    /// Returns true if entries were removed.
+    pub fn prune(&mut self, toots: &[Status], bsky_posts: &[FeedViewPost]) -> bool {
+        let Some(oldest) = oldest_post_date(toots, bsky_posts) else {
+            return false;
+        };
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, posted_at| *posted_at >= oldest - PRUNE_MARGIN);
+        self.entries.len() != before
+    }
+}
+
+/// Date of the oldest post in both timelines. Bluesky posts are dated by the
+/// time the server indexed them, which for reposts is before the repost.
+fn oldest_post_date(toots: &[Status], bsky_posts: &[FeedViewPost]) -> Option<DateTime<Utc>> {
+    let toot_dates = toots.iter().map(|toot| toot.created_at);
+    let bsky_dates = bsky_posts
+        .iter()
+        .map(|post| DateTime::<Utc>::from(*post.post.indexed_at.as_ref()));
+    toot_dates.chain(bsky_dates).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toot_at(created_at: &str) -> Status {
+        let mut toot: Status =
+            serde_json::from_str(&fs::read_to_string("tests/mastodon_long_url.json").unwrap())
+                .unwrap();
+        toot.created_at = created_at.parse().unwrap();
+        toot
+    }
+
+    #[test]
+    fn legacy_cache_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("post_cache.json");
+        std::fs::write(&file, r#"["first post", "second post"]"#).unwrap();
+
+        let cache = PostCache::load(file.to_str().unwrap()).unwrap();
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.contains("first post"));
+        assert!(!cache.contains("third post"));
+    }
+
+    #[tokio::test]
+    async fn entries_survive_a_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("post_cache.json");
+        let file = file.to_str().unwrap();
+        let mut cache = PostCache::default();
+        cache.insert("a post".to_string());
+
+        cache.save(file).await.unwrap();
+        assert_eq!(PostCache::load(file).unwrap(), cache);
+    }
+
+    #[test]
+    fn only_entries_older_than_the_timelines_are_pruned() {
+        let mut cache = PostCache::default();
+        for (text, posted_at) in [
+            ("ancient", "2024-01-01T12:00:00Z"),
+            ("within margin", "2024-03-09T12:00:00Z"),
+            ("recent", "2024-03-20T12:00:00Z"),
+        ] {
+            cache
+                .entries
+                .insert(text.to_string(), posted_at.parse().unwrap());
+        }
+
+        // Nothing is known about the timelines, keep everything.
+        assert!(!cache.prune(&[], &[]));
+        assert_eq!(cache.entries.len(), 3);
+
+        let toots = [
+            toot_at("2024-03-25T12:00:00Z"),
+            toot_at("2024-03-10T00:00:00Z"),
+        ];
+        assert!(cache.prune(&toots, &[]));
+        assert!(!cache.contains("ancient"));
+        assert!(cache.contains("within margin"));
+        assert!(cache.contains("recent"));
+    }
+}
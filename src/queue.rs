@@ -0,0 +1,211 @@
+use anyhow::Context;
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+
+use crate::report::Direction;
+use crate::state_dir::write_atomic;
+use crate::sync::NewStatus;
+
+/// Queued posts are given up after this many failed attempts. They stay in
+/// the queue so that they are not synced again, until they are dropped.
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// A post that failed to be synced and is tried again on every run until it
+/// succeeds, is given up or is dropped manually.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedPost {
+    /// Short identifier to refer to the post with `queue drop`.
+    pub id: String,
+    pub direction: Direction,
+    pub status: NewStatus,
+    /// IDs of the parts of the thread that were already posted, see
+    /// [`crate::post::post_thread`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub posted: BTreeMap<String, String>,
+    pub attempts: u32,
+    /// The post failed [`MAX_ATTEMPTS`] times and is not retried anymore.
+    #[serde(default)]
+    pub given_up: bool,
+    pub last_error: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    pub last_attempt_at: DateTime<Utc>,
+}
+
+impl QueuedPost {
+    /// Creates a queue entry for a post that just failed for the first time.
+    pub fn new(direction: Direction, status: NewStatus, error: &anyhow::Error) -> Self {
+        let now = Utc::now();
+        let mut hasher = DefaultHasher::new();
+        direction.as_str().hash(&mut hasher);
+        status.text.hash(&mut hasher);
+        now.timestamp_nanos_opt().hash(&mut hasher);
+        QueuedPost {
+            id: format!("{:08x}", hasher.finish() as u32),
+            direction,
+            status,
+            posted: BTreeMap::new(),
+            attempts: 1,
+            given_up: false,
+            last_error: Some(format!("{error:#}")),
+            queued_at: now,
+            last_attempt_at: now,
+        }
+    }
+
+    /// Records another failed attempt. Gives up on the post after
+    /// [`MAX_ATTEMPTS`].
+    pub fn failed_again(&mut self, error: &anyhow::Error) {
+        self.attempts += 1;
+        self.given_up = self.attempts >= MAX_ATTEMPTS;
+        self.last_error = Some(format!("{error:#}"));
+        self.last_attempt_at = Utc::now();
+    }
+}
+
+// Read the JSON encoded queue file from disk or provide an empty queue. A
+// corrupted queue is an error, ignoring it would lose the queued posts.
+pub fn read_post_queue(queue_file: &str) -> Result<Vec<QueuedPost>> {
+    match fs::read_to_string(queue_file) {
+        Ok(json) => serde_json::from_str(&json).context(format!(
+            "Failed parsing post queue {queue_file}, fix or delete the file"
+        )),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+pub async fn save_post_queue(queue_file: &str, queue: &[QueuedPost]) -> Result<()> {
+    if queue.is_empty() {
+        // If the queue file exists delete it.
+        if tokio::fs::metadata(queue_file).await.is_ok() {
+            tokio::fs::remove_file(queue_file).await?;
+        }
+        return Ok(());
+    }
+    let json = serde_json::to_string_pretty(queue)?;
+    write_atomic(queue_file, json.as_bytes()).await?;
+    Ok(())
+}
+
+/// The queue entry of a post with the same text in the same direction, if it
+/// is already queued.
+pub fn find_queued<'a>(
+    queue: &'a [QueuedPost],
+    direction: Direction,
+    status: &NewStatus,
+) -> Option<&'a QueuedPost> {
+    queue
+        .iter()
+        .find(|queued| queued.direction == direction && queued.status.text == status.text)
+}
+
+/// Removes the queue entries with the given IDs, or all entries if no ID is
+/// given. Returns the removed entries and the IDs that were not found.
+pub fn drop_from_queue(
+    queue: &mut Vec<QueuedPost>,
+    ids: &[String],
+) -> (Vec<QueuedPost>, Vec<String>) {
+    if ids.is_empty() {
+        return (std::mem::take(queue), Vec::new());
+    }
+    let missing = ids
+        .iter()
+        .filter(|id| !queue.iter().any(|queued| &queued.id == *id))
+        .cloned()
+        .collect();
+    let (dropped, kept) = std::mem::take(queue)
+        .into_iter()
+        .partition(|queued| ids.contains(&queued.id));
+    *queue = kept;
+    (dropped, missing)
+}
+
+/// Human readable listing of the queue for `queue list`.
+pub fn format_queue(queue: &[QueuedPost]) -> String {
+    if queue.is_empty() {
+        return "The post queue is empty.".to_string();
+    }
+    let mut lines = Vec::new();
+    for queued in queue {
+        let state = match queued.given_up {
+            true => "given up",
+            false => "queued",
+        };
+        lines.push(format!(
+            "{} to {} ({} attempts, {state} {}): {}",
+            queued.id,
+            queued.direction.target(),
+            queued.attempts,
+            queued.queued_at.format("%Y-%m-%d %H:%M"),
+            queued.status.text.lines().next().unwrap_or_default(),
+        ));
+        lines.push(format!("    source: {}", queued.status.original_post_url));
+        if let Some(error) = &queued.last_error {
+            lines.push(format!("    last error: {error}"));
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(text: &str) -> QueuedPost {
+        QueuedPost::new(
+            Direction::MastodonToBluesky,
+            NewStatus {
+                text: text.to_string(),
+                ..Default::default()
+            },
+            &anyhow::anyhow!("Bluesky is down"),
+        )
+    }
+
+    #[test]
+    fn failed_again_counts_attempts() {
+        let mut post = queued("a");
+        post.failed_again(&anyhow::anyhow!("still down"));
+        assert_eq!(post.attempts, 2);
+        assert_eq!(post.last_error.as_deref(), Some("still down"));
+        assert!(!post.given_up);
+
+        for _ in 2..MAX_ATTEMPTS {
+            post.failed_again(&anyhow::anyhow!("invalid record"));
+        }
+        assert_eq!(post.attempts, MAX_ATTEMPTS);
+        assert!(post.given_up);
+        assert!(format_queue(&[post]).contains("10 attempts, given up"));
+    }
+
+    #[test]
+    fn drop_from_queue_by_id_or_all() {
+        let mut queue = vec![queued("a"), queued("b")];
+        let id = queue[0].id.clone();
+        let (dropped, missing) = drop_from_queue(&mut queue, &[id, "nope".to_string()]);
+        assert_eq!(missing, vec!["nope".to_string()]);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].status.text, "a");
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].status.text, "b");
+
+        let (dropped, _) = drop_from_queue(&mut queue, &[]);
+        assert_eq!(dropped.len(), 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn queue_roundtrips_through_json() {
+        let queue = vec![queued("a")];
+        let json = serde_json::to_string(&queue).unwrap();
+        let read: Vec<QueuedPost> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read[0].id, queue[0].id);
+        assert!(find_queued(&read, Direction::MastodonToBluesky, &queue[0].status).is_some());
+        // The same text in the other direction is a different post.
+        assert!(find_queued(&read, Direction::BlueskyToMastodon, &queue[0].status).is_none());
+    }
+}
@@ -1,14 +1,61 @@
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result, bail};
 use megalodon::generator;
-use std::io;
+use serde::Deserialize;
+use std::io::{self, IsTerminal};
 
 use super::*;
+use crate::bluesky_oauth::{oauth_login, oauth_supported};
+use crate::http::{USER_AGENT, WebClient};
+use crate::secret::Secret;
 
-pub async fn mastodon_register() -> Result<MastodonConfig> {
-    let base_url = console_input(
-        "Provide the URL of your Mastodon instance, for example https://mastodon.social ",
+/// Logs in to Mastodon with the given credentials, or asks for the missing
+/// ones interactively. The base URL is used if none is given.
+pub async fn mastodon_register(
+    login: &MastodonLogin,
+    base_url: Option<&str>,
+) -> Result<MastodonConfig> {
+    let base_url = match login.url.as_deref().or(base_url) {
+        Some(base_url) => base_url.to_string(),
+        None => console_input(
+            "Provide the URL of your Mastodon instance, for example https://mastodon.social ",
+        )?,
+    };
+    let Some(access_token) = &login.access_token else {
+        return mastodon_authorize(base_url).await;
+    };
+
+    // Check the token right away instead of failing on the first sync.
+    let client = generator(
+        megalodon::SNS::Mastodon,
+        base_url.clone(),
+        Some(access_token.clone()),
+        Some(USER_AGENT.to_string()),
+    )?;
+    client
+        .verify_account_credentials()
+        .await
+        .context("The Mastodon access token is not valid")?;
+    Ok(MastodonConfig {
+        base_url,
+        client_id: login.client_id.clone().unwrap_or_default(),
+        client_secret: Secret::inline(login.client_secret.clone().unwrap_or_default()),
+        access_token: Secret::inline(access_token),
+        // Tokens created in the Mastodon settings do not expire.
+        refresh_token: Secret::inline("none"),
+        sync_reblogs: true,
+        sync_hashtag: None,
+        delete_old_favs: false,
+    })
+}
+
+/// Registers the app on the Mastodon instance and lets the user authorize it.
+async fn mastodon_authorize(base_url: String) -> Result<MastodonConfig> {
+    let client = generator(
+        megalodon::SNS::Mastodon,
+        base_url.clone(),
+        None,
+        Some(USER_AGENT.to_string()),
     )?;
-    let client = generator(megalodon::SNS::Mastodon, base_url.clone(), None, None)?;
     let options = megalodon::megalodon::AppInputOptions {
         scopes: Some(["read".to_string(), "write".to_string()].to_vec()),
         website: Some("https://github.com/klausi/mastodon-bluesky-sync".to_string()),
@@ -37,35 +84,133 @@ pub async fn mastodon_register() -> Result<MastodonConfig> {
     Ok(MastodonConfig {
         base_url,
         client_id,
-        client_secret,
-        access_token: token_data.access_token,
-        refresh_token: token_data.refresh_token.unwrap_or("none".to_string()),
+        client_secret: Secret::inline(client_secret),
+        access_token: Secret::inline(token_data.access_token),
+        refresh_token: Secret::inline(token_data.refresh_token.unwrap_or("none".to_string())),
         sync_reblogs: true,
         sync_hashtag: None,
         delete_old_favs: false,
     })
 }
 
-pub async fn bluesky_register() -> Result<BlueskyConfig> {
-    let email = console_input("Enter your Bluesky email address")?;
-    let app_password = console_input(
-        "Generate a Bluesky App password at https://bsky.app/settings/app-passwords and paste it here",
-    )?;
-    let _agent = get_new_bluesky_agent(&email, &app_password).await?;
-    // Bluesky access tokens do not work for longer periods of time, so we need
-    // to store an app password here.
-    // See https://github.com/sugyan/atrium/issues/246
-    Ok(BlueskyConfig {
-        email,
-        app_password,
+/// New tokens from a refresh token grant.
+#[derive(Debug, Deserialize)]
+pub struct RefreshedToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+/// Registration stores "none" if the server did not issue a refresh token.
+pub fn has_refresh_token(config: &MastodonConfig) -> bool {
+    let refresh_token = config.refresh_token.expose();
+    !refresh_token.is_empty() && refresh_token != "none"
+}
+
+/// Exchanges the refresh token for a new access token.
+pub async fn mastodon_refresh_token(
+    config: &MastodonConfig,
+    http: &WebClient,
+) -> Result<RefreshedToken> {
+    // Megalodon's refresh_access_token() sends the wrong grant type, so the
+    // token request is made directly.
+    let body = serde_urlencoded::to_string([
+        ("grant_type", "refresh_token"),
+        ("refresh_token", config.refresh_token.expose()),
+        ("client_id", config.client_id.as_str()),
+        ("client_secret", config.client_secret.expose()),
+    ])?;
+    let url = format!("{}/oauth/token", config.base_url.trim_end_matches('/'));
+    let response = http
+        .client()
+        .post(&url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    let json = http.text(response).await?;
+    serde_json::from_str(&json).context("Invalid token response from Mastodon")
+}
+
+/// Logs in to Bluesky with the given app password, with OAuth or with an app
+/// password that is asked for interactively. The service URL is used if none
+/// is given.
+pub async fn bluesky_register(
+    login: &BlueskyLogin,
+    service_url: Option<&str>,
+    state_dir: &str,
+    http: &WebClient,
+) -> Result<BlueskyConfig> {
+    let mut config = BlueskyConfig {
+        auth: BlueskyAuth::AppPassword,
+        email: String::new(),
+        app_password: Secret::default(),
+        service_url: login
+            .service_url
+            .as_deref()
+            .or(service_url)
+            .map(String::from)
+            .unwrap_or_else(config_bluesky_service_default),
+        video_service_url: config_bluesky_video_service_default(),
         sync_reposts: true,
         sync_hashtag: None,
         delete_old_posts: false,
         delete_old_favs: false,
-    })
+    };
+    let (email, app_password) = match (&login.email, &login.app_password) {
+        (Some(email), Some(app_password)) => (email.clone(), app_password.clone()),
+        // Nobody could open the OAuth link or enter an app password, waiting
+        // for that would hang cron jobs and containers.
+        _ if !io::stdin().is_terminal() => bail!(
+            "Cannot log in to Bluesky without a terminal, pass an app password with --bluesky-email and --bluesky-app-password or the MBS_BLUESKY_EMAIL and MBS_BLUESKY_APP_PASSWORD environment variables"
+        ),
+        _ if oauth_supported(http, &config.service_url).await => {
+            bluesky_oauth_register(&config.service_url, state_dir, http).await?;
+            config.auth = BlueskyAuth::OAuth;
+            return Ok(config);
+        }
+        // Fall back to an app password for servers without OAuth support.
+        // Bluesky access tokens do not work for longer periods of time, so we
+        // need to store the app password.
+        // See https://github.com/sugyan/atrium/issues/246
+        _ => (
+            console_input("Enter your Bluesky email address")?,
+            console_input(
+                "Generate a Bluesky App password at https://bsky.app/settings/app-passwords and paste it here",
+            )?,
+        ),
+    };
+    config.email = email;
+    config.app_password = Secret::inline(app_password);
+    let _agent = get_new_bluesky_agent(
+        &config,
+        &state_file(state_dir, "bluesky-auth-cache.json"),
+        http,
+    )
+    .await?;
+    Ok(config)
+}
+
+/// Logs in to Bluesky with OAuth and saves the session.
+pub async fn bluesky_oauth_register(
+    service_url: &str,
+    state_dir: &str,
+    http: &WebClient,
+) -> Result<()> {
+    let session = oauth_login(http, service_url).await?;
+    session.save(&bluesky_oauth_session_file(state_dir)).await
 }
 
 fn console_input(prompt: &str) -> Result<String> {
+    // Waiting for input that never comes would hang cron jobs and containers.
+    if !io::stdin().is_terminal() {
+        bail!(
+            "Cannot ask \"{prompt}\" without a terminal, pass the credentials as options or environment variables instead, see --help"
+        );
+    }
     println!("{prompt}: ");
     let mut line = String::new();
     let _ = io::stdin().read_line(&mut line)?;
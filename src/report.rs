@@ -0,0 +1,385 @@
+use chrono::prelude::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::Config;
+use crate::sync::NewStatus;
+
+/// Whether the run report is printed as JSON. Progress messages go to stderr
+/// in that case so that stdout only contains the machine readable report.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Prints a progress message for humans. Goes to stdout in text mode and to
+/// stderr in JSON mode.
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::report::json_output() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use progress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable progress messages and a summary
+    #[default]
+    Text,
+    /// Machine readable JSON report on stdout
+    Json,
+}
+
+pub fn set_output_format(format: OutputFormat) {
+    JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    MastodonToBluesky,
+    BlueskyToMastodon,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::MastodonToBluesky => "mastodon_to_bluesky",
+            Direction::BlueskyToMastodon => "bluesky_to_mastodon",
+        }
+    }
+
+    /// Name of the network posts are sent to.
+    pub fn target(&self) -> &'static str {
+        match self {
+            Direction::MastodonToBluesky => "Bluesky",
+            Direction::BlueskyToMastodon => "Mastodon",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionStatus {
+    /// The post would have been synced, but this is a dry run.
+    Planned,
+    Posted,
+    Skipped,
+    Failed,
+}
+
+/// One post that was considered for synchronization.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportAction {
+    pub direction: Direction,
+    pub status: ActionStatus,
+    pub source_url: String,
+    pub text: String,
+    pub target_id: Option<String>,
+    pub skip_reason: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Counts of old posts or favourites found and deleted by the delete modules.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DeletionCount {
+    /// Number of items older than the retention period.
+    pub expired: usize,
+    pub deleted: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeletionStats {
+    pub bluesky_posts: Option<DeletionCount>,
+    pub mastodon_favs: Option<DeletionCount>,
+    pub bluesky_favs: Option<DeletionCount>,
+}
+
+/// Everything that happened during one synchronization run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub dry_run: bool,
+    pub actions: Vec<ReportAction>,
+    pub deletions: DeletionStats,
+}
+
+impl RunReport {
+    pub fn new(dry_run: bool) -> Self {
+        RunReport {
+            started_at: Utc::now(),
+            finished_at: None,
+            dry_run,
+            actions: Vec::new(),
+            deletions: DeletionStats::default(),
+        }
+    }
+
+    /// Records a successful post, or a planned one on a dry run.
+    pub fn posted(&mut self, direction: Direction, status: &NewStatus, target_id: Option<String>) {
+        let action_status = if self.dry_run {
+            ActionStatus::Planned
+        } else {
+            ActionStatus::Posted
+        };
+        self.push(direction, status, action_status, target_id, None, None);
+    }
+
+    pub fn skipped(&mut self, direction: Direction, status: &NewStatus, reason: &str) {
+        self.push(
+            direction,
+            status,
+            ActionStatus::Skipped,
+            None,
+            Some(reason.to_string()),
+            None,
+        );
+    }
+
+    pub fn failed(&mut self, direction: Direction, status: &NewStatus, error: &anyhow::Error) {
+        self.push(
+            direction,
+            status,
+            ActionStatus::Failed,
+            None,
+            None,
+            Some(format!("{error:#}")),
+        );
+    }
+
+    fn push(
+        &mut self,
+        direction: Direction,
+        status: &NewStatus,
+        action_status: ActionStatus,
+        target_id: Option<String>,
+        skip_reason: Option<String>,
+        error: Option<String>,
+    ) {
+        self.actions.push(ReportAction {
+            direction,
+            status: action_status,
+            source_url: status.original_post_url.clone(),
+            text: status.text.clone(),
+            target_id,
+            skip_reason,
+            error,
+        });
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_at = Some(Utc::now());
+    }
+
+    /// Number of actions in the given direction with the given status.
+    pub fn count(&self, direction: Direction, status: ActionStatus) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| action.direction == direction && action.status == status)
+            .count()
+    }
+
+    /// Number of posts that failed to sync in this run.
+    pub fn failed_count(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| action.status == ActionStatus::Failed)
+            .count()
+    }
+
+    /// Renders a short human readable summary of the run.
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        for (direction, label) in [
+            (Direction::MastodonToBluesky, "Mastodon -> Bluesky"),
+            (Direction::BlueskyToMastodon, "Bluesky -> Mastodon"),
+        ] {
+            let posted = if self.dry_run {
+                format!("{} planned", self.count(direction, ActionStatus::Planned))
+            } else {
+                format!("{} posted", self.count(direction, ActionStatus::Posted))
+            };
+            lines.push(format!(
+                "{label}: {posted}, {} skipped, {} failed",
+                self.count(direction, ActionStatus::Skipped),
+                self.count(direction, ActionStatus::Failed),
+            ));
+        }
+        for (count, label) in [
+            (self.deletions.bluesky_posts, "Bluesky posts"),
+            (self.deletions.mastodon_favs, "Mastodon favourites"),
+            (self.deletions.bluesky_favs, "Bluesky likes"),
+        ] {
+            if let Some(count) = count {
+                lines.push(format!(
+                    "Old {label}: {} expired, {} deleted",
+                    count.expired, count.deleted
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Prints the report in the requested output format.
+    pub fn print(&self, format: OutputFormat) -> anyhow::Result<()> {
+        match format {
+            OutputFormat::Text => println!("{}", self.summary()),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+        }
+        Ok(())
+    }
+}
+
+/// A feature of the config and whether it is switched on.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckFeature {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// Result of the `check` command: the logged in accounts and the features
+/// that the config enables.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    pub config_file: String,
+    pub state_dir: String,
+    pub mastodon_account: String,
+    pub bluesky_account: String,
+    pub features: Vec<CheckFeature>,
+}
+
+impl CheckReport {
+    /// The features of the config in the order of the README.
+    pub fn features(config: &Config) -> Vec<CheckFeature> {
+        let hashtag = |tag: &Option<String>| match tag {
+            Some(tag) if !tag.is_empty() => (tag.clone(), true),
+            _ => ("a hashtag".to_string(), false),
+        };
+        let (mastodon_tag, mastodon_tag_enabled) = hashtag(&config.mastodon.sync_hashtag);
+        let (bluesky_tag, bluesky_tag_enabled) = hashtag(&config.bluesky.sync_hashtag);
+        [
+            (
+                "Sync Mastodon boosts to Bluesky".to_string(),
+                config.mastodon.sync_reblogs,
+            ),
+            (
+                "Sync Bluesky reposts to Mastodon".to_string(),
+                config.bluesky.sync_reposts,
+            ),
+            (
+                format!("Only sync Mastodon posts with {mastodon_tag}"),
+                mastodon_tag_enabled,
+            ),
+            (
+                format!("Only sync Bluesky posts with {bluesky_tag}"),
+                bluesky_tag_enabled,
+            ),
+            (
+                "Delete Bluesky posts older than 90 days".to_string(),
+                config.bluesky.delete_old_posts,
+            ),
+            (
+                "Delete Mastodon favourites older than 90 days".to_string(),
+                config.mastodon.delete_old_favs,
+            ),
+            (
+                "Delete Bluesky likes older than 90 days".to_string(),
+                config.bluesky.delete_old_favs,
+            ),
+            (
+                "Download from private network addresses".to_string(),
+                config.fetch.allow_private_networks,
+            ),
+        ]
+        .into_iter()
+        .map(|(name, enabled)| CheckFeature { name, enabled })
+        .collect()
+    }
+
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!("Config file {} is valid", self.config_file),
+            format!("State directory: {}", self.state_dir),
+            format!("Mastodon: logged in as {}", self.mastodon_account),
+            format!("Bluesky: logged in as {}", self.bluesky_account),
+            "Features:".to_string(),
+        ];
+        for feature in &self.features {
+            let mark = if feature.enabled { "x" } else { " " };
+            lines.push(format!("  [{mark}] {}", feature.name));
+        }
+        lines.join("\n")
+    }
+
+    /// Prints the report in the requested output format.
+    pub fn print(&self, format: OutputFormat) -> anyhow::Result<()> {
+        match format {
+            OutputFormat::Text => println!("{}", self.summary()),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(text: &str) -> NewStatus {
+        NewStatus {
+            text: text.to_string(),
+            original_post_url: format!("https://example.com/{text}"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dry_run_records_planned_actions() {
+        let mut report = RunReport::new(true);
+        report.posted(Direction::MastodonToBluesky, &status("a"), None);
+        report.skipped(Direction::MastodonToBluesky, &status("b"), "duplicate");
+
+        assert_eq!(
+            report.count(Direction::MastodonToBluesky, ActionStatus::Planned),
+            1
+        );
+        assert_eq!(
+            report.count(Direction::MastodonToBluesky, ActionStatus::Posted),
+            0
+        );
+        assert_eq!(
+            report.summary().lines().next().unwrap(),
+            "Mastodon -> Bluesky: 1 planned, 1 skipped, 0 failed"
+        );
+    }
+
+    #[test]
+    fn json_report_contains_action_fields() {
+        let mut report = RunReport::new(false);
+        report.failed(
+            Direction::BlueskyToMastodon,
+            &status("c"),
+            &anyhow::anyhow!("boom"),
+        );
+        report.deletions.bluesky_posts = Some(DeletionCount {
+            expired: 3,
+            deleted: 2,
+        });
+
+        let json = serde_json::to_value(&report).unwrap();
+        let action = &json["actions"][0];
+        assert_eq!(action["direction"], "bluesky_to_mastodon");
+        assert_eq!(action["status"], "failed");
+        assert_eq!(action["source_url"], "https://example.com/c");
+        assert_eq!(action["error"], "boom");
+        assert_eq!(json["deletions"]["bluesky_posts"]["deleted"], 2);
+        assert!(json["deletions"]["mastodon_favs"].is_null());
+    }
+}
@@ -0,0 +1,456 @@
+use atrium_xrpc_client::reqwest::{ReqwestClient, ReqwestClientBuilder};
+use bsky_sdk::api::xrpc::{
+    HttpClient, XrpcClient,
+    http::{
+        self, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header::AUTHORIZATION,
+    },
+};
+use chrono::prelude::*;
+use megalodon::error::{Error, Kind};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::bluesky_oauth::OAuthAuthorizer;
+use crate::http::WebClient;
+use crate::report::progress;
+
+/// How often and how long to retry transient API errors.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    /// Upper bound for the exponential backoff.
+    pub max_delay: Duration,
+    /// Longest rate limit wait we accept, longer waits fail immediately.
+    pub max_rate_limit_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_rate_limit_wait: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for the given zero based retry number.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+
+    /// Returns how long to wait before the next attempt after a failed
+    /// response with the given status and headers, or None if we should give
+    /// up.
+    fn delay(&self, retry: u32, response: Option<(u16, &HeaderMap)>) -> Option<Duration> {
+        if retry + 1 >= self.max_attempts {
+            return None;
+        }
+        let wait =
+            response.and_then(|(status, headers)| rate_limit_wait(status, headers, Utc::now()));
+        match wait {
+            Some(wait) if wait > self.max_rate_limit_wait => None,
+            Some(wait) => Some(wait),
+            None => Some(self.backoff(retry)),
+        }
+    }
+}
+
+/// Server errors and rate limits are transient. Requests that are not
+/// idempotent, like creating a post, are only retried when the server
+/// definitely did not process them.
+fn is_retryable_status(status: u16, idempotent: bool) -> bool {
+    match status {
+        429 | 503 => true,
+        408 | 500 | 502 | 504 => idempotent,
+        _ => false,
+    }
+}
+
+/// Extracts the time to wait from `Retry-After` or `RateLimit-Reset` headers.
+///
+/// `Retry-After` is either a number of seconds or an HTTP date. Bluesky sends
+/// `RateLimit-Reset` as a Unix timestamp, Mastodon sends `X-RateLimit-Reset`
+/// as an ISO 8601 date, the IETF draft uses a number of seconds.
+///
+/// Bluesky sends the rate limit headers with every response, so they only
+/// count for rate limited (429) responses. `Retry-After` also counts for an
+/// unavailable server (503). Other errors are retried with backoff.
+pub fn rate_limit_wait(status: u16, headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    if !matches!(status, 429 | 503) {
+        return None;
+    }
+    if let Some(value) = header_str(headers, "retry-after") {
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+            return Some(until(date.with_timezone(&Utc), now));
+        }
+    }
+    if status != 429 {
+        return None;
+    }
+    for name in ["ratelimit-reset", "x-ratelimit-reset"] {
+        let Some(value) = header_str(headers, name) else {
+            continue;
+        };
+        if let Ok(number) = value.parse::<i64>() {
+            // Anything that large is a timestamp, not a number of seconds.
+            if number > 1_000_000_000 {
+                if let Some(date) = DateTime::from_timestamp(number, 0) {
+                    return Some(until(date, now));
+                }
+            } else {
+                return Some(Duration::from_secs(number.max(0) as u64));
+            }
+        }
+        if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+            return Some(until(date.with_timezone(&Utc), now));
+        }
+    }
+    None
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn until(date: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    (date - now).to_std().unwrap_or_default()
+}
+
+/// Calls a Mastodon API function and retries it on transient errors.
+pub async fn mastodon_retry<T, F, Fut>(
+    operation: &str,
+    idempotent: bool,
+    mut call: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let policy = RetryPolicy::default();
+    let mut retry = 0;
+    loop {
+        let error = match call().await {
+            Ok(result) => return Ok(result),
+            Err(error) => error,
+        };
+        let delay = match &error {
+            Error::OwnError(own_error) => match (&own_error.kind, own_error.status) {
+                (Kind::HTTPStatusError, Some(status))
+                    if is_retryable_status(status, idempotent) =>
+                {
+                    policy.delay(
+                        retry,
+                        own_error.header.as_ref().map(|headers| (status, headers)),
+                    )
+                }
+                _ => None,
+            },
+            // A failed connection never reached the server, but after a
+            // timeout the server might have handled the request already.
+            Error::RequestError(request_error)
+                if request_error.is_connect() || (idempotent && request_error.is_timeout()) =>
+            {
+                policy.delay(retry, None)
+            }
+            _ => None,
+        };
+        let Some(delay) = delay else {
+            return Err(error);
+        };
+        progress!(
+            "Mastodon {operation} failed, retrying in {}s: {error}",
+            delay.as_secs()
+        );
+        sleep(delay).await;
+        retry += 1;
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+type HttpResult = Result<Response<Vec<u8>>, BoxError>;
+
+/// XRPC client that retries transient errors and honors rate limit headers.
+/// Used for all Bluesky API calls.
+#[derive(Clone)]
+pub struct RetryClient {
+    inner: ReqwestClient,
+    policy: RetryPolicy,
+    oauth: Option<Arc<OAuthAuthorizer>>,
+}
+
+impl RetryClient {
+    pub fn new(base_uri: impl AsRef<str>, http: &WebClient) -> Self {
+        Self {
+            inner: ReqwestClientBuilder::new(base_uri)
+                .client(http.client().clone())
+                .build(),
+            policy: RetryPolicy::default(),
+            oauth: None,
+        }
+    }
+
+    /// Client that authorizes requests with the OAuth session instead of the
+    /// bearer token of the agent.
+    pub fn with_oauth(
+        base_uri: impl AsRef<str>,
+        http: &WebClient,
+        oauth: Arc<OAuthAuthorizer>,
+    ) -> Self {
+        Self {
+            oauth: Some(oauth),
+            ..Self::new(base_uri, http)
+        }
+    }
+
+    /// Sends the request with a DPoP bound access token. Rejections because
+    /// of a missing nonce or an expired token are retried once each.
+    async fn send_dpop(&self, request: Request<Vec<u8>>, oauth: &OAuthAuthorizer) -> HttpResult {
+        let uri = request.uri();
+        let url = format!(
+            "{}://{}{}",
+            uri.scheme_str().unwrap_or("https"),
+            uri.authority().map(|a| a.as_str()).unwrap_or_default(),
+            uri.path()
+        );
+        let mut token = oauth.access_token().await?;
+        let (mut nonce_retried, mut refreshed) = (false, false);
+        loop {
+            // Proofs must not be reused, so every retry gets a new one.
+            let authorize = || -> Result<_, BoxError> {
+                let mut attempt = clone_request(&request)?;
+                let proof = oauth.proof(request.method().as_str(), &url, &token)?;
+                let headers = attempt.headers_mut();
+                headers.insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("DPoP {token}"))?,
+                );
+                headers.insert("DPoP", HeaderValue::from_str(&proof)?);
+                Ok(attempt)
+            };
+
+            let response = self.send_with_retries(authorize).await?;
+            let nonce_changed = oauth.update_nonce(response.headers());
+            if response.status() != StatusCode::UNAUTHORIZED {
+                return Ok(response);
+            }
+            let challenge = header_str(response.headers(), "www-authenticate").unwrap_or_default();
+            if challenge.contains("use_dpop_nonce") && nonce_changed && !nonce_retried {
+                nonce_retried = true;
+            } else if !refreshed {
+                refreshed = true;
+                token = oauth.refresh(&token).await?;
+            } else {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Sends the request built by `build` and retries transient errors.
+    /// Requests cannot be cloned, so they are built again for every attempt.
+    async fn send_with_retries(
+        &self,
+        build: impl Fn() -> Result<Request<Vec<u8>>, BoxError>,
+    ) -> HttpResult {
+        let mut retry = 0;
+        loop {
+            let attempt = build()?;
+            let idempotent = attempt.method() == Method::GET;
+            let path = attempt.uri().path().to_string();
+
+            let (delay, result) = match self.inner.send_http(attempt).await {
+                Ok(response) if is_retryable_status(response.status().as_u16(), idempotent) => (
+                    self.policy.delay(
+                        retry,
+                        Some((response.status().as_u16(), response.headers())),
+                    ),
+                    Ok(response),
+                ),
+                Ok(response) => return Ok(response),
+                // The request might not have reached the server, but we don't
+                // know for sure, so only retry idempotent requests.
+                Err(error) if idempotent => (self.policy.delay(retry, None), Err(error)),
+                Err(error) => return Err(error),
+            };
+            let Some(delay) = delay else {
+                return result;
+            };
+            progress!(
+                "Bluesky request {path} failed, retrying in {}s",
+                delay.as_secs()
+            );
+            sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+fn clone_request(request: &Request<Vec<u8>>) -> Result<Request<Vec<u8>>, http::Error> {
+    let mut clone = Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(request.body().clone())?;
+    *clone.headers_mut() = request.headers().clone();
+    Ok(clone)
+}
+
+impl HttpClient for RetryClient {
+    async fn send_http(&self, request: Request<Vec<u8>>) -> HttpResult {
+        match &self.oauth {
+            // The agent adds the authorization header for authenticated calls.
+            Some(oauth) if request.headers().contains_key(AUTHORIZATION) => {
+                self.send_dpop(request, oauth).await
+            }
+            _ => {
+                self.send_with_retries(|| Ok(clone_request(&request)?))
+                    .await
+            }
+        }
+    }
+}
+
+impl XrpcClient for RetryClient {
+    fn base_uri(&self) -> String {
+        self.inner.base_uri()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds_and_date() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            rate_limit_wait(429, &headers("retry-after", "17"), now),
+            Some(Duration::from_secs(17))
+        );
+        assert_eq!(
+            rate_limit_wait(
+                503,
+                &headers("retry-after", "Mon, 01 Jan 2024 12:01:00 GMT"),
+                now
+            ),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn rate_limit_reset_formats() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        // Bluesky: Unix timestamp.
+        let reset = (now.timestamp() + 30).to_string();
+        assert_eq!(
+            rate_limit_wait(429, &headers("ratelimit-reset", &reset), now),
+            Some(Duration::from_secs(30))
+        );
+        // Mastodon: ISO 8601 date.
+        assert_eq!(
+            rate_limit_wait(
+                429,
+                &headers("x-ratelimit-reset", "2024-01-01T12:05:00.000Z"),
+                now
+            ),
+            Some(Duration::from_secs(300))
+        );
+        // Reset dates in the past mean no waiting.
+        assert_eq!(
+            rate_limit_wait(
+                429,
+                &headers("x-ratelimit-reset", "2024-01-01T11:00:00Z"),
+                now
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(rate_limit_wait(429, &HeaderMap::new(), now), None);
+        // Only rate limited responses wait for the reset.
+        assert_eq!(
+            rate_limit_wait(503, &headers("ratelimit-reset", &reset), now),
+            None
+        );
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_bounded() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0, None), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(2, None), Some(Duration::from_secs(4)));
+        assert_eq!(policy.delay(3, None), None);
+        let policy = RetryPolicy {
+            max_attempts: 20,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(10, None), Some(Duration::from_secs(30)));
+        let retry_after = headers("retry-after", "3600");
+        assert_eq!(policy.delay(0, Some((429, &retry_after))), None);
+    }
+
+    #[test]
+    fn server_errors_ignore_the_rate_limit_reset() {
+        let policy = RetryPolicy::default();
+        // Bluesky sends the reset of the rate limit window with every error.
+        let reset = (Utc::now().timestamp() + 3600).to_string();
+        let headers = headers("ratelimit-reset", &reset);
+        assert_eq!(
+            policy.delay(1, Some((500, &headers))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.delay(1, Some((503, &headers))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(policy.delay(1, Some((429, &headers))), None);
+    }
+
+    #[test]
+    fn posts_are_only_retried_when_not_processed() {
+        assert!(is_retryable_status(429, false));
+        assert!(is_retryable_status(503, false));
+        assert!(!is_retryable_status(500, false));
+        assert!(is_retryable_status(500, true));
+        assert!(!is_retryable_status(404, true));
+    }
+
+    #[tokio::test]
+    async fn timeouts_of_non_idempotent_mastodon_calls_are_not_retried() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        let result = mastodon_retry("post status", false, || async {
+            client.post(server.uri()).send().await.map_err(Error::from)
+        })
+        .await;
+
+        assert!(matches!(result, Err(Error::RequestError(error)) if error.is_timeout()));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::Path;
+use tokio::fs;
+
+use crate::state_dir::write_atomic_private;
+
+/// Keyring service name that all secrets are stored under.
+const KEYRING_SERVICE: &str = "mastodon-bluesky-sync";
+
+/// Where the registration stores new credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SecretStore {
+    /// The keyring of the operating system, falls back to files if there is none
+    #[default]
+    Keyring,
+    /// Files next to the cache files, only readable by the current user
+    File,
+    /// Directly in the config file
+    Config,
+}
+
+/// A credential in the config file. Instead of the value itself the config
+/// can reference where it is stored: `env:VAR`, `file:/path` or
+/// `keyring:name`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret {
+    /// The value as written in the config file.
+    reference: String,
+    /// The resolved credential, None until [`Secret::resolve`] is called for
+    /// references.
+    value: Option<String>,
+}
+
+enum Source<'a> {
+    Inline,
+    Env(&'a str),
+    File(&'a str),
+    Keyring(&'a str),
+}
+
+impl Secret {
+    /// A secret stored directly in the config file.
+    pub fn inline(value: impl Into<String>) -> Self {
+        let value = value.into();
+        Secret {
+            reference: value.clone(),
+            value: Some(value),
+        }
+    }
+
+    fn from_reference(reference: String) -> Self {
+        let mut secret = Secret {
+            reference,
+            value: None,
+        };
+        if let Source::Inline = secret.source() {
+            secret.value = Some(secret.reference.clone());
+        }
+        secret
+    }
+
+    fn source(&self) -> Source<'_> {
+        if let Some(var) = self.reference.strip_prefix("env:") {
+            Source::Env(var)
+        } else if let Some(path) = self.reference.strip_prefix("file:") {
+            Source::File(path)
+        } else if let Some(name) = self.reference.strip_prefix("keyring:") {
+            Source::Keyring(name)
+        } else {
+            Source::Inline
+        }
+    }
+
+    /// The credential itself. Empty if the secret was not resolved.
+    pub fn expose(&self) -> &str {
+        self.value.as_deref().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reference.is_empty()
+    }
+
+    /// Reads the credential from the referenced location.
+    pub async fn resolve(&mut self) -> Result<()> {
+        let value = match self.source() {
+            Source::Inline => return Ok(()),
+            Source::Env(var) => std::env::var(var)
+                .with_context(|| format!("Environment variable {var} is not set"))?,
+            Source::File(path) => fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read secret file {path}"))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            Source::Keyring(name) => {
+                let name = name.to_string();
+                tokio::task::spawn_blocking(move || {
+                    keyring::Entry::new(KEYRING_SERVICE, &name)?.get_password()
+                })
+                .await?
+                .with_context(|| format!("Failed to read {} from the keyring", self.reference))?
+            }
+        };
+        self.value = Some(value);
+        Ok(())
+    }
+
+    /// Changes the credential, for example after a token refresh, and writes
+    /// it to the referenced location. Inline secrets are only changed in
+    /// memory, the config file needs to be saved.
+    pub async fn update(&mut self, value: String) -> Result<()> {
+        match self.source() {
+            Source::Inline => self.reference = value.clone(),
+            Source::Env(var) => {
+                bail!(
+                    "The new value cannot be stored in the environment variable {var}, please update it"
+                )
+            }
+            Source::File(path) => write_secret_file(Path::new(path), &value).await?,
+            Source::Keyring(name) => keyring_set(name, &value).await?,
+        }
+        self.value = Some(value);
+        Ok(())
+    }
+
+    /// Moves an inline secret to the given store. The name identifies it in
+    /// the keyring or the secrets directory.
+    pub async fn store(&mut self, store: SecretStore, name: &str, secrets_dir: &str) -> Result<()> {
+        if !matches!(self.source(), Source::Inline) || self.is_empty() {
+            return Ok(());
+        }
+        let value = self.expose().to_string();
+        if store == SecretStore::Keyring {
+            match keyring_set(name, &value).await {
+                Ok(()) => {
+                    self.reference = format!("keyring:{name}");
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Storing {name} in the keyring failed, using a file instead: {e:#}")
+                }
+            }
+        }
+        if store != SecretStore::Config {
+            let path = std::path::absolute(Path::new(secrets_dir).join(name))?;
+            write_secret_file(&path, &value).await?;
+            self.reference = format!("file:{}", path.display());
+        }
+        Ok(())
+    }
+}
+
+async fn keyring_set(name: &str, value: &str) -> Result<()> {
+    let (name, value) = (name.to_string(), value.to_string());
+    tokio::task::spawn_blocking(move || {
+        keyring::Entry::new(KEYRING_SERVICE, &name)?.set_password(&value)
+    })
+    .await??;
+    Ok(())
+}
+
+async fn write_secret_file(path: &Path, value: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    write_atomic_private(path, value.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write secret file {}", path.display()))
+}
+
+// Never print credentials, for example in debug logs.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source() {
+            Source::Inline => write!(f, "Secret(***)"),
+            _ => write!(f, "Secret({})", self.reference),
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.reference)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Secret::from_reference(String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn secrets_from_env_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("token");
+        std::fs::write(&file, "from-file\n").unwrap();
+        // SAFETY: no other test reads this variable.
+        unsafe { std::env::set_var("MBS_TEST_SECRET", "from-env") };
+
+        let mut secret = Secret::from_reference("env:MBS_TEST_SECRET".to_string());
+        assert_eq!(secret.expose(), "");
+        secret.resolve().await.unwrap();
+        assert_eq!(secret.expose(), "from-env");
+        assert!(secret.update("new".to_string()).await.is_err());
+
+        let mut secret = Secret::from_reference(format!("file:{}", file.display()));
+        secret.resolve().await.unwrap();
+        assert_eq!(secret.expose(), "from-file");
+        secret.update("refreshed".to_string()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "refreshed");
+
+        let secret = Secret::from_reference("plain".to_string());
+        assert_eq!(secret.expose(), "plain");
+        assert_eq!(format!("{secret:?}"), "Secret(***)");
+
+        let mut secret = Secret::from_reference("env:MBS_TEST_MISSING".to_string());
+        assert!(secret.resolve().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn inline_secrets_are_moved_to_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let secrets_dir = dir.path().to_str().unwrap();
+        let mut secret = Secret::inline("token");
+        secret
+            .store(SecretStore::File, "mastodon-access-token", secrets_dir)
+            .await
+            .unwrap();
+        let path = dir.path().join("mastodon-access-token");
+        assert_eq!(secret.reference, format!("file:{}", path.display()));
+        assert_eq!(secret.expose(), "token");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "token");
+
+        let mut secret = Secret::inline("token");
+        secret
+            .store(SecretStore::Config, "mastodon-access-token", secrets_dir)
+            .await
+            .unwrap();
+        assert_eq!(secret.reference, "token");
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::report::progress;
+
+/// Name of the directory below the XDG state directory.
+const APP_DIR: &str = "mastodon-bluesky-sync";
+
+/// Environment variable of older versions that only moved the cache files.
+const LEGACY_ENV: &str = "MBS_CACHE_DIR";
+
+/// Files that older versions wrote to the working directory.
+const LEGACY_FILES: [&str; 9] = [
+    "post_cache.json",
+    "post_queue.json",
+    "bluesky_cache.json",
+    "mastodon_fav_cache.json",
+    "mastodon_fav_cursor_cache.json",
+    "bluesky_like_cache.json",
+    "bluesky_like_cursor_cache.json",
+    "bluesky-auth-cache.json",
+    "bluesky-oauth-session.json",
+];
+
+/// Picks the directory for caches, sessions and secret files and creates it.
+///
+/// The command line option or `MBS_STATE_DIR` comes first, then the legacy
+/// `MBS_CACHE_DIR`, the `state_dir` of the config file and finally the XDG
+/// state directory. For the last two the files that older versions kept in
+/// the working directory are moved there.
+pub async fn state_dir_prepare(arg: Option<&str>, config: Option<&str>) -> Result<String> {
+    let legacy = std::env::var(LEGACY_ENV).ok();
+    let (dir, migrate) = match (arg, legacy, config) {
+        (Some(dir), _, _) => (dir.to_string(), false),
+        (None, Some(dir), _) => (dir, false),
+        (None, None, Some(dir)) => (dir.to_string(), true),
+        (None, None, None) => (state_dir_default(), true),
+    };
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create the state directory {dir}"))?;
+    if migrate {
+        state_dir_migrate(Path::new("."), Path::new(&dir)).await?;
+    }
+    Ok(dir)
+}
+
+/// `$XDG_STATE_HOME/mastodon-bluesky-sync`, on systems without it the local
+/// data directory.
+fn state_dir_default() -> String {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join(APP_DIR))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Moves the files of older versions from the given directory, unless the
+/// state directory has its own version of them already.
+async fn state_dir_migrate(from: &Path, to: &Path) -> Result<()> {
+    if fs::canonicalize(from).await.ok() == fs::canonicalize(to).await.ok() {
+        return Ok(());
+    }
+    for name in LEGACY_FILES {
+        let (old, new) = (from.join(name), to.join(name));
+        if !fs::try_exists(&old).await? || fs::try_exists(&new).await? {
+            continue;
+        }
+        // Renaming fails across file systems, for example into a volume.
+        if fs::rename(&old, &new).await.is_err() {
+            fs::copy(&old, &new)
+                .await
+                .with_context(|| format!("Failed to copy {} to {}", old.display(), to.display()))?;
+            fs::remove_file(&old).await?;
+        }
+        progress!("Moved {name} to the state directory {}", to.display());
+    }
+    Ok(())
+}
+
+/// Replaces the file with the contents by writing a temporary file next to it
+/// and renaming it. Readers and crashed runs never see a half written file.
+/// The permissions of an existing file are kept.
+pub async fn write_atomic(path: impl AsRef<Path>, contents: &[u8]) -> Result<()> {
+    write_atomic_mode(path.as_ref(), contents, None).await
+}
+
+/// Like [`write_atomic`], for files with credentials that only the current
+/// user may read.
+pub async fn write_atomic_private(path: impl AsRef<Path>, contents: &[u8]) -> Result<()> {
+    write_atomic_mode(path.as_ref(), contents, Some(0o600)).await
+}
+
+async fn write_atomic_mode(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?;
+    let temp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let result = async {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+        let mut file = options.open(&temp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        if mode.is_none()
+            && let Ok(metadata) = fs::metadata(path).await
+        {
+            fs::set_permissions(&temp, metadata.permissions()).await?;
+        }
+        fs::rename(&temp, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temp).await;
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn legacy_files_are_moved_to_the_state_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("work"), dir.path().join("state"));
+        std::fs::create_dir_all(&from).unwrap();
+        std::fs::create_dir_all(&to).unwrap();
+        std::fs::write(from.join("post_cache.json"), "[\"old\"]").unwrap();
+        std::fs::write(from.join("bluesky-auth-cache.json"), "old").unwrap();
+        std::fs::write(to.join("bluesky-auth-cache.json"), "new").unwrap();
+        std::fs::write(from.join("mastodon-bluesky-sync.toml"), "").unwrap();
+
+        state_dir_migrate(&from, &to).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(to.join("post_cache.json")).unwrap(),
+            "[\"old\"]"
+        );
+        assert!(!from.join("post_cache.json").exists());
+        // Newer files are not overwritten.
+        assert_eq!(
+            std::fs::read_to_string(to.join("bluesky-auth-cache.json")).unwrap(),
+            "new"
+        );
+        assert!(from.join("bluesky-auth-cache.json").exists());
+        // The config file stays where it is.
+        assert!(from.join("mastodon-bluesky-sync.toml").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn atomic_writes_keep_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        std::fs::write(&file, "old").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_atomic(&file, b"new").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        let secret = dir.path().join("token");
+        write_atomic_private(&secret, b"token").await.unwrap();
+        let mode = std::fs::metadata(&secret).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // No temporary files are left behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -4,14 +4,15 @@ use bsky_sdk::api::app::bsky::feed::defs::{FeedViewPostData, PostViewData, PostV
 use bsky_sdk::api::app::bsky::feed::post::RecordEmbedRefs;
 use bsky_sdk::api::app::bsky::richtext::facet::MainFeaturesItem;
 use bsky_sdk::api::types::{Object, TryFromUnknown, Union};
+use megalodon::entities::attachment::AttachmentType;
 use megalodon::entities::{QuotedStatus, Status};
 use regex::Regex;
-use std::collections::HashSet;
-use std::fs;
+use serde::{Deserialize, Serialize};
 use unicode_segmentation::UnicodeSegmentation;
 
 use crate::bluesky_richtext::get_rich_text;
 use crate::mastodon_html::parse_html_and_extract_inline_quote;
+use crate::post_cache::PostCache;
 
 // Represents new status updates that should be posted to Bluesky (bsky_posts)
 // and Mastodon (toots).
@@ -31,7 +32,7 @@ impl StatusUpdates {
 
 // A new status for posting. Optionally has links to media (images) that should
 // be attached.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NewStatus {
     pub text: String,
     // BCP47 language tag (e.g. "en", "de", "es").
@@ -64,10 +65,43 @@ impl Default for NewStatus {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NewMedia {
     pub attachment_url: String,
     pub alt_text: Option<String>,
+    /// Width and height in pixels, if the source network knows them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<(u64, u64)>,
+    #[serde(default)]
+    pub kind: MediaKind,
+    /// Cover image of an audio attachment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_url: Option<String>,
+    /// Caption files of a video, like the WebVTT files next to a video in an
+    /// archive. The timelines of both networks do not have them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub captions: Vec<NewCaption>,
+}
+
+/// A WebVTT caption file of a video.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewCaption {
+    pub url: String,
+    /// Language tag like "en".
+    pub language: String,
+}
+
+/// What the source network says an attachment is. Downloads are still
+/// handled according to their content type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    #[default]
+    Image,
+    Video,
+    /// A short looping video without sound, how Mastodon stores GIFs.
+    Gifv,
+    Audio,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -231,6 +265,26 @@ pub fn toot_and_post_are_equal(toot: &Status, bsky_post: &Object<FeedViewPostDat
     toot_text == bsky_text
 }
 
+/// The ID of the toot with the given text in the Mastodon timeline, if the
+/// status was posted before.
+pub fn find_toot(toots: &[Status], text: &str) -> Option<String> {
+    let text = unify_post_content(text);
+    toots
+        .iter()
+        .find(|toot| unify_post_content(&mastodon_toot_get_text(toot)) == text)
+        .map(|toot| toot.id.clone())
+}
+
+/// The URI of the post with the given text in the Bluesky timeline, if the
+/// status was posted before.
+pub fn find_bsky_post(bsky_posts: &[Object<FeedViewPostData>], text: &str) -> Option<String> {
+    let text = unify_post_content(text);
+    bsky_posts
+        .iter()
+        .find(|post| unify_post_content(&bsky_post_unshorten_decode(post)) == text)
+        .map(|post| post.post.uri.clone())
+}
+
 // Unifies bluesky text or toot text to a common format.
 fn unify_post_content(content: &str) -> String {
     let normalized = normalize_links_for_comparison(content);
@@ -262,6 +316,11 @@ fn strip_ellipsis_and_link_suffix(text: &str) -> String {
     let re_with_link = Regex::new(r"\s+https?://\S+$").expect("Invalid link regex");
     let replaced = re_with_link.replace(original, "");
     let stripped = replaced.trim_end();
+    // Posts with a cut video end with a link to the full video.
+    let stripped = stripped
+        .strip_suffix(&FULL_VIDEO_LABEL.to_lowercase())
+        .unwrap_or(stripped)
+        .trim_end();
 
     // Also strip just trailing ellipsis (indicates truncation/shortening)
     let final_stripped = stripped.trim_end_matches('…').trim_end();
@@ -395,6 +454,44 @@ pub fn bsky_post_shorten(text: &str, toot_url: &Option<String>) -> String {
     with_link
 }
 
+/// Label of the link to the original post under a video that was cut to the
+/// maximum duration of Bluesky videos.
+const FULL_VIDEO_LABEL: &str = "Full video:";
+
+/// Adds a link to the full video to the text of a post with a cut video,
+/// shortened to the Bluesky limit of 300 characters.
+pub fn bsky_post_with_full_video_link(text: &str, url: &str) -> String {
+    with_full_video_link(text, url, |post| get_rich_text(post).grapheme_len() <= 300)
+}
+
+/// Adds a link to the original post to the text of a toot whose video could
+/// not be converted, shortened to the Mastodon limit of 500 characters.
+pub fn toot_with_full_video_link(text: &str, url: &str) -> String {
+    with_full_video_link(text, url, |toot| mastodon_text_length(toot) <= 500)
+}
+
+fn with_full_video_link(text: &str, url: &str, fits: impl Fn(&str) -> bool) -> String {
+    let re = Regex::new(r"[^\s]+$").unwrap();
+    let mut shortened = text.trim().to_string();
+    let mut with_link = format!("{shortened}\n\n{FULL_VIDEO_LABEL} {url}");
+    while !fits(&with_link) && !shortened.is_empty() {
+        shortened = re.replace_all(&shortened, "").trim().to_string();
+        with_link = format!("{shortened}…\n\n{FULL_VIDEO_LABEL} {url}");
+    }
+    with_link.trim().to_string()
+}
+
+/// The bsky.app web address of a post, from its at:// URI.
+pub fn bsky_post_web_url(uri: &str) -> String {
+    match uri
+        .strip_prefix("at://")
+        .and_then(|rest| rest.split_once("/app.bsky.feed.post/"))
+    {
+        Some((did, rkey)) => format!("https://bsky.app/profile/{did}/post/{rkey}"),
+        None => uri.to_string(),
+    }
+}
+
 // Mastodon has a 500 character post limit. With embedded quote posts and long
 // links the content could get too long, shorten it to 500 characters.
 fn toot_shorten(text: &str, bsky_post: &Object<PostViewData>) -> String {
@@ -482,10 +579,7 @@ pub fn mastodon_toot_get_text(toot: &Status) -> String {
 // Ensure that sync posts have not been made before to prevent syncing loops.
 // Use a cache file to temporarily store posts and compare them on the next
 // invocation.
-pub fn filter_posted_before(
-    posts: StatusUpdates,
-    post_cache: &HashSet<String>,
-) -> Result<StatusUpdates> {
+pub fn filter_posted_before(posts: StatusUpdates, post_cache: &PostCache) -> Result<StatusUpdates> {
     // If there are no status updates then we don't need to check anything.
     if posts.toots.is_empty() && posts.bsky_posts.is_empty() {
         return Ok(posts);
@@ -516,28 +610,6 @@ pub fn filter_posted_before(
     Ok(filtered_posts)
 }
 
-// Read the JSON encoded cache file from disk or provide an empty default cache.
-pub fn read_post_cache(cache_file: &str) -> HashSet<String> {
-    match fs::read_to_string(cache_file) {
-        Ok(json) => {
-            match serde_json::from_str::<HashSet<String>>(&json) {
-                Ok(cache) => {
-                    // If the cache has more than 150 items already then empty it to not
-                    // accumulate too many items and allow posting the same text at a
-                    // later date.
-                    if cache.len() > 150 {
-                        HashSet::new()
-                    } else {
-                        cache
-                    }
-                }
-                Err(_) => HashSet::new(),
-            }
-        }
-        Err(_) => HashSet::new(),
-    }
-}
-
 // Returns a list of direct links to attachments for download.
 pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedia> {
     let mut links = Vec::new();
@@ -555,6 +627,10 @@ pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedi
                 } else {
                     Some(image.alt.clone())
                 },
+                dimensions: None,
+                kind: MediaKind::Image,
+                cover_url: None,
+                captions: Vec::new(),
             });
         }
     }
@@ -576,6 +652,10 @@ pub fn bsky_get_attachments(bsky_post: &Object<FeedViewPostData>) -> Vec<NewMedi
                         } else {
                             Some(image.alt.clone())
                         },
+                        dimensions: None,
+                        kind: MediaKind::Image,
+                        cover_url: None,
+                        captions: Vec::new(),
                     });
                 }
             }
@@ -625,6 +705,22 @@ pub fn toot_get_attachments(toot: &Status) -> Vec<NewMedia> {
             // Bluesky only allows a max length of 1,000 characters for alt
             // text, so we need to cut it off here.
             alt_text: truncate_option_string(attachment.description.clone(), 1_000),
+            dimensions: attachment
+                .meta
+                .as_ref()
+                .and_then(|meta| meta.original.as_ref())
+                .and_then(|original| Some((original.width?.into(), original.height?.into()))),
+            kind: match attachment.r#type {
+                AttachmentType::Gifv => MediaKind::Gifv,
+                AttachmentType::Video => MediaKind::Video,
+                AttachmentType::Audio => MediaKind::Audio,
+                AttachmentType::Image | AttachmentType::Unknown => MediaKind::Image,
+            },
+            cover_url: match attachment.r#type {
+                AttachmentType::Audio => attachment.preview_url.clone(),
+                _ => None,
+            },
+            captions: Vec::new(),
         });
     }
     links
@@ -666,18 +762,21 @@ pub mod tests {
     use bsky_sdk::api::app::bsky::feed::defs::FeedViewPostData;
     use bsky_sdk::api::types::Object;
     use megalodon::entities::Status;
+    use megalodon::entities::attachment::AttachmentType;
     use std::fs;
 
     use crate::{
-        SyncOptions, determine_posts, sync::mastodon_toot_get_text, sync::toot_and_post_are_equal,
-        sync::toot_shorten,
+        MediaKind, SyncOptions, bluesky_richtext::get_rich_text, determine_posts,
+        sync::bsky_post_web_url, sync::bsky_post_with_full_video_link, sync::mastodon_text_length,
+        sync::mastodon_toot_get_text, sync::toot_and_post_are_equal, sync::toot_get_attachments,
+        sync::toot_shorten, sync::toot_with_full_video_link,
     };
 
     // Test that embedded quote posts are included correctly.
     #[test]
     fn bsky_quote_post() {
         let post = read_bsky_post_from_json("tests/bsky_quote_post.json");
-        let posts = determine_posts(&Vec::new(), &vec![post], &SyncOptions::default());
+        let posts = determine_posts(&Vec::new(), &[post], &SyncOptions::default());
         assert_eq!(
             posts.toots[0].text,
             "Working on this and testing quote posts
@@ -710,7 +809,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             sync_reposts: true,
             ..Default::default()
         };
-        let posts = determine_posts(&Vec::new(), &vec![post], &sync_options);
+        let posts = determine_posts(&Vec::new(), &[post], &sync_options);
         assert_eq!(
             posts.toots[0].text,
             "♻️ martinthuer.at: Ich durfte auf der @univie.ac.at über die Kontrollfunktion der Medien sprechen. Wie Macht kontrolliert wird, warum das manchmal scheitert und wie das konkret funktioniert.
@@ -728,7 +827,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             sync_reposts: true,
             ..Default::default()
         };
-        let posts = determine_posts(&Vec::new(), &vec![post], &sync_options);
+        let posts = determine_posts(&Vec::new(), &[post], &sync_options);
         assert_eq!(posts.toots.len(), 1);
         assert!(posts.toots[0].text.starts_with("♻️ bohrn-mena.at: "));
     }
@@ -737,7 +836,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
     #[test]
     fn bsky_long_url() {
         let post = read_bsky_post_from_json("tests/bsky_long_url.json");
-        let posts = determine_posts(&Vec::new(), &vec![post], &SyncOptions::default());
+        let posts = determine_posts(&Vec::new(), &[post], &SyncOptions::default());
         assert_eq!(
             posts.toots[0].text,
             "Test post with a very long URL https://example.com/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
@@ -748,7 +847,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
     #[test]
     fn mastodon_long_url() {
         let post = read_mastodon_post_from_json("tests/mastodon_long_url.json");
-        let posts = determine_posts(&vec![post], &Vec::new(), &SyncOptions::default());
+        let posts = determine_posts(&[post], &Vec::new(), &SyncOptions::default());
         assert_eq!(
             posts.bsky_posts[0].text,
             "Test toot with long link <a href=\"http://example.com/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\">example.com/aaaaaaaaaaaaaaaaaa…</a>"
@@ -759,7 +858,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
     #[test]
     fn bsky_quote_attachment() {
         let post = read_bsky_post_from_json("tests/bsky_quote_attachment.json");
-        let posts = determine_posts(&Vec::new(), &vec![post], &SyncOptions::default());
+        let posts = determine_posts(&Vec::new(), &[post], &SyncOptions::default());
         assert_eq!(
             posts.toots[0].text,
             "Ich muss quote post attachments testen, habe hier was passendes gefunden 😀\n\n💬 patricialierzer.bsky.social:"
@@ -778,7 +877,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             sync_reposts: true,
             ..Default::default()
         };
-        let posts = determine_posts(&Vec::new(), &vec![post], &sync_options);
+        let posts = determine_posts(&Vec::new(), &[post], &sync_options);
         assert_eq!(
             posts.toots[0].text,
             "♻️ mjfree.bsky.social: I'm going to post this video every day so we never forget"
@@ -797,7 +896,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             sync_reposts: true,
             ..Default::default()
         };
-        let posts = determine_posts(&Vec::new(), &vec![post], &sync_options);
+        let posts = determine_posts(&Vec::new(), &[post], &sync_options);
         assert_eq!(
             posts.toots[0].text,
             "Testing quote post videos
@@ -819,7 +918,7 @@ https://github.com/klausi/mastodon-bluesky-sync/releases/tag/v0.2.0"
             sync_reposts: true,
             ..Default::default()
         };
-        let posts = determine_posts(&Vec::new(), &vec![post], &sync_options);
+        let posts = determine_posts(&Vec::new(), &[post], &sync_options);
         assert_eq!(posts.toots[0].text, "♻️ leasusemichel.bsky.social: \n \"Wir nennen die Taten unfassbar und die Täter monströs\",schreibt  @pickinese.bsky.social. Typen, die eigentlich durchschnittlich und gewöhnlich sind.
 \"In einer globalen Pandemie sexualisierter Gewalt gegen Frauen geben wir uns anhaltend begriffsstutzig.\"
 
@@ -831,7 +930,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
     #[test]
     fn mastodon_user_mention() {
         let post = read_mastodon_post_from_json("tests/mastodon_mention.json");
-        let posts = determine_posts(&vec![post], &Vec::new(), &SyncOptions::default());
+        let posts = determine_posts(&[post], &Vec::new(), &SyncOptions::default());
         assert_eq!(
             posts.bsky_posts[0].text,
             "Finally watched #RebelRidge recommended by @mekkaokereke a while ago... Good stuff! 🎬"
@@ -848,11 +947,36 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
             sync_reblogs: true,
             ..Default::default()
         };
-        let posts = determine_posts(&vec![mastodon_post], &vec![bsky_post], &sync_options);
+        let posts = determine_posts(&[mastodon_post], &[bsky_post], &sync_options);
         assert!(posts.toots.is_empty());
         assert!(posts.bsky_posts.is_empty());
     }
 
+    #[test]
+    fn toot_attachments_keep_the_original_size() {
+        let mastodon_post = read_mastodon_post_from_json("tests/mastodon_long_video.json");
+        let attachments = toot_get_attachments(&mastodon_post);
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].dimensions, Some((720, 720)));
+        assert_eq!(attachments[0].kind, MediaKind::Video);
+        assert_eq!(attachments[0].cover_url, None);
+    }
+
+    #[test]
+    fn toot_audio_attachments_keep_their_cover() {
+        let mut mastodon_post = read_mastodon_post_from_json("tests/mastodon_long_video.json");
+        // The video is on the boosted toot.
+        let attachment = &mut mastodon_post.reblog.as_mut().unwrap().media_attachments[0];
+        attachment.r#type = AttachmentType::Audio;
+        attachment.preview_url = Some("https://mastodon.example/cover.jpg".to_string());
+        let attachments = toot_get_attachments(&mastodon_post);
+        assert_eq!(attachments[0].kind, MediaKind::Audio);
+        assert_eq!(
+            attachments[0].cover_url.as_deref(),
+            Some("https://mastodon.example/cover.jpg")
+        );
+    }
+
     // Regression test: a Mastodon post that became a Bluesky post with a link
     // embed must not be synced back to Mastodon as new content.
     #[test]
@@ -860,11 +984,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
         let mastodon_post =
             read_mastodon_post_from_json("tests/mastodon_link_embed_roundtrip.json");
         let bsky_post = read_bsky_post_from_json("tests/bsky_link_embed_roundtrip.json");
-        let posts = determine_posts(
-            &vec![mastodon_post],
-            &vec![bsky_post],
-            &SyncOptions::default(),
-        );
+        let posts = determine_posts(&[mastodon_post], &[bsky_post], &SyncOptions::default());
         assert!(posts.toots.is_empty());
         assert!(posts.bsky_posts.is_empty());
     }
@@ -875,11 +995,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
     fn mastodon_bsky_duplicate_sync_case_should_be_equal() {
         let mastodon_post = read_mastodon_post_from_json("tests/mastodon_duplicate_sync_case.json");
         let bsky_post = read_bsky_post_from_json("tests/bsky_duplicate_sync_case.json");
-        let posts = determine_posts(
-            &vec![mastodon_post],
-            &vec![bsky_post],
-            &SyncOptions::default(),
-        );
+        let posts = determine_posts(&[mastodon_post], &[bsky_post], &SyncOptions::default());
         assert!(posts.toots.is_empty());
         assert!(posts.bsky_posts.is_empty());
     }
@@ -897,7 +1013,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
     #[test]
     fn mastodon_url_encoded() {
         let post = read_mastodon_post_from_json("tests/mastodon_url_encoded.json");
-        let posts = determine_posts(&vec![post], &Vec::new(), &SyncOptions::default());
+        let posts = determine_posts(&[post], &Vec::new(), &SyncOptions::default());
         assert_eq!(
             posts.bsky_posts[0].text,
             "TRANSPHOBIA IS MISOGYNY\n\nit’s telling fascists are eager to ban transgender women, but nary a peep about transgender men. \n\nand no, it’s not because they prefer the men. they don’t expect them to be competitive. after all, their assigned sex at birth was female. \n\nfascists… https://mastodon.social/@testuser/116299190222149167"
@@ -930,7 +1046,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
     #[test]
     fn mastodon_html_links_sync_to_bluesky_via_determine_posts() {
         let post = read_mastodon_post_from_json("tests/mastodon_html_links_sync.json");
-        let posts = determine_posts(&vec![post], &Vec::new(), &SyncOptions::default());
+        let posts = determine_posts(&[post], &Vec::new(), &SyncOptions::default());
         assert_eq!(posts.bsky_posts.len(), 1);
         assert_eq!(
             posts.bsky_posts[0].text,
@@ -944,7 +1060,7 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
     #[test]
     fn mastodon_link_issue_invisible_spans() {
         let post = read_mastodon_post_from_json("tests/mastodon_link_issue.json");
-        let posts = determine_posts(&vec![post], &Vec::new(), &SyncOptions::default());
+        let posts = determine_posts(&[post], &Vec::new(), &SyncOptions::default());
         assert_eq!(posts.bsky_posts.len(), 1);
         assert_eq!(
             posts.bsky_posts[0].text,
@@ -972,6 +1088,67 @@ https://www.derstandard.at/story/3000000250190/der-fall-pelicot-unfassbar-monstr
         assert!(!toot_and_post_are_equal(&mastodon_post, &bsky_post));
     }
 
+    #[test]
+    fn post_with_full_video_link_equals_the_toot() {
+        use bsky_sdk::api::types::TryFromUnknown;
+
+        let mut mastodon_post =
+            read_mastodon_post_from_json("tests/mastodon_reblog_loop_case.json");
+        mastodon_post.content = "<p>A long video.</p>".to_string();
+        mastodon_post.reblog = None;
+        let mut bsky_post = read_bsky_post_from_json("tests/bsky_repost_loop_case.json");
+        let mut record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
+            bsky_post.post.record.clone(),
+        )
+        .unwrap();
+        record.text =
+            bsky_post_with_full_video_link("A long video.", "https://mastodon.example/@alice/1");
+        assert_eq!(
+            record.text,
+            "A long video.\n\nFull video: https://mastodon.example/@alice/1"
+        );
+        record.facets = Some(Vec::new());
+        bsky_post.post.record =
+            serde_json::from_value(serde_json::to_value(record).unwrap()).unwrap();
+        assert!(toot_and_post_are_equal(&mastodon_post, &bsky_post));
+
+        let long = bsky_post_with_full_video_link(&"word ".repeat(80), "https://example.com/1");
+        assert!(get_rich_text(&long).grapheme_len() <= 300);
+        assert!(long.ends_with("word…\n\nFull video: https://example.com/1"));
+    }
+
+    #[test]
+    fn toot_with_full_video_link_equals_the_post() {
+        use bsky_sdk::api::types::TryFromUnknown;
+
+        let bsky_post = read_bsky_post_from_json("tests/bsky_repost_loop_case.json");
+        let mut record = bsky_sdk::api::app::bsky::feed::post::RecordData::try_from_unknown(
+            bsky_post.post.record.clone(),
+        )
+        .unwrap();
+        record.text = "A long video.".to_string();
+        record.facets = Some(Vec::new());
+        let mut bsky_post = bsky_post;
+        bsky_post.post.record =
+            serde_json::from_value(serde_json::to_value(record).unwrap()).unwrap();
+
+        let url = bsky_post_web_url("at://did:plc:alice/app.bsky.feed.post/3lb3f2ko4rc23");
+        assert_eq!(
+            url,
+            "https://bsky.app/profile/did:plc:alice/post/3lb3f2ko4rc23"
+        );
+        let text = toot_with_full_video_link("A long video.", &url);
+        let mut mastodon_post =
+            read_mastodon_post_from_json("tests/mastodon_reblog_loop_case.json");
+        mastodon_post.content = format!("<p>{}</p>", text.replace("\n\n", "</p><p>"));
+        mastodon_post.reblog = None;
+        assert!(toot_and_post_are_equal(&mastodon_post, &bsky_post));
+
+        let long = toot_with_full_video_link(&"word ".repeat(200), &url);
+        assert!(mastodon_text_length(&long) <= 500);
+        assert!(long.ends_with(&format!("word…\n\nFull video: {url}")));
+    }
+
     #[test]
     fn two_different_texts_not_equal() {
         use bsky_sdk::api::types::TryFromUnknown;
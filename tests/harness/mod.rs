@@ -0,0 +1,418 @@
+//! Local stand-ins for the Mastodon REST API, the Bluesky XRPC endpoints and
+//! the Bluesky video service. Every request is recorded so that tests can
+//! assert the exact payloads the real `run()` sends.
+
+#![allow(dead_code)]
+
+use clap::Parser;
+use mastodon_bluesky_sync::Error;
+use mastodon_bluesky_sync::args::Args;
+use serde_json::{Value, json};
+use std::path::Path;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+pub const DID: &str = "did:plc:alice";
+pub const CREATE_RECORD: &str = "/xrpc/com.atproto.repo.createRecord";
+pub const UPLOAD_BLOB: &str = "/xrpc/com.atproto.repo.uploadBlob";
+pub const GET_AUTHOR_FEED: &str = "/xrpc/app.bsky.feed.getAuthorFeed";
+pub const LIST_RECORDS: &str = "/xrpc/com.atproto.repo.listRecords";
+pub const UPLOAD_VIDEO: &str = "/xrpc/app.bsky.video.uploadVideo";
+pub const GET_JOB_STATUS: &str = "/xrpc/app.bsky.video.getJobStatus";
+pub const GET_UPLOAD_LIMITS: &str = "/xrpc/app.bsky.video.getUploadLimits";
+pub const POST_STATUS: &str = "/api/v1/statuses";
+pub const UPLOAD_MEDIA: &str = "/api/v2/media";
+
+pub const BLOB_CID: &str = "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy";
+pub const VIDEO_CID: &str = "bafkreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm";
+const RECORD_CID: &str = "bafyreifrq4n3g6m6djwntlws6fihto74ta2rv7d76zsv5zet7eubfsape4";
+
+/// A 1x1 pixel PNG image.
+pub const PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0xf8, 0xcf, 0xc0, 0xf0,
+    0x1f, 0x00, 0x05, 0x00, 0x01, 0xff, 0x89, 0x99, 0x3d, 0x1d, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45,
+    0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+];
+
+pub struct Harness {
+    pub mastodon: MockServer,
+    pub bluesky: MockServer,
+    pub video: MockServer,
+    dir: TempDir,
+}
+
+impl Harness {
+    /// Starts the servers, all endpoints respond successfully. The timelines
+    /// are set with [`Harness::timelines`].
+    pub async fn start() -> Self {
+        let harness = Harness {
+            mastodon: MockServer::start().await,
+            bluesky: MockServer::start().await,
+            video: MockServer::start().await,
+            dir: tempfile::tempdir().unwrap(),
+        };
+        harness.mount_mastodon().await;
+        harness.mount_bluesky().await;
+        harness.mount_video().await;
+        harness.write_config();
+        harness
+    }
+
+    /// Serves the given toots as Mastodon timeline and the feed entries as
+    /// Bluesky timeline.
+    pub async fn timelines(&self, toots: Vec<Value>, feed: Vec<Value>) {
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/api/v1/accounts/[^/]+/statuses$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(toots))
+            .mount(&self.mastodon)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(GET_AUTHOR_FEED))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "feed": feed })))
+            .mount(&self.bluesky)
+            .await;
+    }
+
+    async fn mount_mastodon(&self) {
+        let server = &self.mastodon;
+        let status = read_fixture("tests/mastodon_long_url.json");
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&status["account"]))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(UPLOAD_MEDIA))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "media-1",
+                "type": "image",
+                "url": format!("{}/media/uploaded.png", server.uri()),
+                "remote_url": null,
+                "preview_url": null,
+                "text_url": null,
+                "meta": null,
+                "description": null,
+                "blurhash": null,
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(POST_STATUS))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&status))
+            .mount(server)
+            .await;
+        // Attachments and link previews are served by the Mastodon server.
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/media/.+\.png$"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(PNG, "image/png"))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/article"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"<html><head>
+                <meta property="og:title" content="An article">
+                <meta property="og:description" content="About things">
+                <meta property="og:image" content="/media/preview.png">
+                </head></html>"#,
+                "text/html",
+            ))
+            .mount(server)
+            .await;
+    }
+
+    async fn mount_bluesky(&self) {
+        let server = &self.bluesky;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.server.createSession"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "accessJwt": "access-jwt",
+                "refreshJwt": "refresh-jwt",
+                "handle": "alice.test",
+                "did": DID,
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.server.getSession"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "handle": "alice.test", "did": DID })),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.server.getServiceAuth"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "token": "service-token" })),
+            )
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(LIST_RECORDS))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "records": [] })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(UPLOAD_BLOB))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "blob": blob(BLOB_CID, "image/png", PNG.len()),
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(CREATE_RECORD))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": format!("at://{DID}/app.bsky.feed.post/3kpost"),
+                "cid": RECORD_CID,
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .mount(server)
+            .await;
+        // Images of Bluesky posts are served by the Bluesky server.
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/img/.+\.png$"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(PNG, "image/png"))
+            .mount(server)
+            .await;
+    }
+
+    async fn mount_video(&self) {
+        let job = |state: &str| {
+            json!({
+                "jobId": "job-1",
+                "did": DID,
+                "state": state,
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path(GET_UPLOAD_LIMITS))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "canUpload": true,
+                "remainingDailyVideos": 25,
+                "remainingDailyBytes": 10_000_000_000u64,
+            })))
+            .mount(&self.video)
+            .await;
+        // The real service returns the job status without wrapper object.
+        Mock::given(method("POST"))
+            .and(path(UPLOAD_VIDEO))
+            .respond_with(ResponseTemplate::new(200).set_body_json(job("JOB_STATE_CREATED")))
+            .mount(&self.video)
+            .await;
+        let mut completed = job("JOB_STATE_COMPLETED");
+        completed["blob"] = blob(VIDEO_CID, "video/mp4", 1000);
+        Mock::given(method("GET"))
+            .and(path(GET_JOB_STATUS))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "jobStatus": completed })),
+            )
+            .mount(&self.video)
+            .await;
+    }
+
+    fn write_config(&self) {
+        let config = format!(
+            r#"
+[mastodon]
+base_url = "{}"
+client_id = "client-id"
+client_secret = "client-secret"
+access_token = "access-token"
+refresh_token = "refresh-token"
+
+[bluesky]
+email = "alice@example.com"
+app_password = "app-password"
+service_url = "{}"
+video_service_url = "{}"
+
+# The mock servers listen on the loopback interface.
+[fetch]
+allow_private_networks = true
+"#,
+            self.mastodon.uri(),
+            self.bluesky.uri(),
+            self.video.uri()
+        );
+        std::fs::write(self.config_file(), config).unwrap();
+    }
+
+    /// Appends sections to the config file.
+    pub fn append_config(&self, sections: &str) {
+        let config = std::fs::read_to_string(self.config_file()).unwrap() + sections;
+        std::fs::write(self.config_file(), config).unwrap();
+    }
+
+    /// Switches the Bluesky login to an OAuth session with the given tokens,
+    /// the Bluesky server acts as authorization server.
+    pub fn use_bluesky_oauth(&self, access_token: &str, expires_at: &str) {
+        let config = std::fs::read_to_string(self.config_file())
+            .unwrap()
+            .replace(
+                "email = \"alice@example.com\"\napp_password = \"app-password\"",
+                "auth = \"oauth\"",
+            );
+        std::fs::write(self.config_file(), config).unwrap();
+        let session = json!({
+            "did": DID,
+            "handle": "alice.test",
+            "pds_url": self.bluesky.uri(),
+            "issuer": self.bluesky.uri(),
+            "token_endpoint": format!("{}/oauth/token", self.bluesky.uri()),
+            "client_id": "http://localhost",
+            "dpop_key": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE",
+            "access_token": access_token,
+            "refresh_token": "refresh-token",
+            "expires_at": expires_at,
+        });
+        std::fs::write(self.oauth_session_file(), session.to_string()).unwrap();
+    }
+
+    pub fn oauth_session_file(&self) -> std::path::PathBuf {
+        self.state_dir().join("bluesky-oauth-session.json")
+    }
+
+    pub fn config_file(&self) -> String {
+        self.dir
+            .path()
+            .join("mastodon-bluesky-sync.toml")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Cache files of the runs are written here.
+    pub fn state_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Runs the program against the servers with the given extra arguments.
+    pub async fn run(&self, extra_args: &[&str]) -> Result<(), Error> {
+        let mut argv = vec![
+            "mastodon-bluesky-sync".to_string(),
+            "--config".to_string(),
+            self.config_file(),
+            "--state-dir".to_string(),
+            self.state_dir().to_string_lossy().into_owned(),
+        ];
+        argv.extend(extra_args.iter().map(|arg| arg.to_string()));
+        mastodon_bluesky_sync::run(Args::parse_from(argv)).await
+    }
+}
+
+/// Recorded requests of a server with the given method and path.
+pub async fn requests(
+    server: &MockServer,
+    request_method: &str,
+    request_path: &str,
+) -> Vec<Request> {
+    server
+        .received_requests()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|request| {
+            request.method.as_str() == request_method && request.url.path() == request_path
+        })
+        .collect()
+}
+
+/// JSON bodies of the recorded requests.
+pub async fn request_bodies(
+    server: &MockServer,
+    request_method: &str,
+    request_path: &str,
+) -> Vec<Value> {
+    requests(server, request_method, request_path)
+        .await
+        .iter()
+        .map(|request| request.body_json().unwrap())
+        .collect()
+}
+
+/// Decodes the claims of a JWT without verifying it.
+pub fn jwt_claims(jwt: &str) -> Value {
+    use base64::Engine;
+    let claims = jwt.split('.').nth(1).unwrap();
+    let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(claims)
+        .unwrap();
+    serde_json::from_slice(&json).unwrap()
+}
+
+pub fn read_fixture(file_name: &str) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(file_name).unwrap()).unwrap()
+}
+
+fn blob(cid: &str, mime_type: &str, size: usize) -> Value {
+    json!({
+        "$type": "blob",
+        "ref": { "$link": cid },
+        "mimeType": mime_type,
+        "size": size,
+    })
+}
+
+/// A public toot with the given HTML content, based on a fixture.
+pub fn toot(id: &str, content: &str, language: &str) -> Value {
+    let mut toot = read_fixture("tests/mastodon_long_url.json");
+    toot["id"] = json!(id);
+    toot["url"] = json!(format!("https://mastodon.example/@alice/{id}"));
+    toot["uri"] = toot["url"].clone();
+    toot["content"] = json!(content);
+    toot["language"] = json!(language);
+    toot["edited_at"] = Value::Null;
+    toot
+}
+
+/// Adds an image attachment to a toot.
+pub fn with_image(mut toot: Value, url: &str, description: &str) -> Value {
+    toot["media_attachments"] = json!([{
+        "id": "1",
+        "type": "image",
+        "url": url,
+        "remote_url": null,
+        "preview_url": url,
+        "text_url": null,
+        "meta": null,
+        "description": description,
+        "blurhash": null,
+    }]);
+    toot
+}
+
+/// A Bluesky feed entry with the given text and images (URL, alt text).
+pub fn bsky_post(rkey: &str, text: &str, images: &[(&str, &str)]) -> Value {
+    let uri = format!("at://{DID}/app.bsky.feed.post/{rkey}");
+    let mut post = json!({
+        "post": {
+            "uri": uri,
+            "cid": RECORD_CID,
+            "author": { "did": DID, "handle": "alice.test" },
+            "record": {
+                "$type": "app.bsky.feed.post",
+                "createdAt": "2024-12-07T14:34:25.674Z",
+                "text": text,
+                "langs": ["en"],
+            },
+            "indexedAt": "2024-12-07T14:34:26.124Z",
+        }
+    });
+    if !images.is_empty() {
+        post["post"]["embed"] = json!({
+            "$type": "app.bsky.embed.images#view",
+            "images": images
+                .iter()
+                .map(|(url, alt)| json!({ "thumb": url, "fullsize": url, "alt": alt }))
+                .collect::<Vec<_>>(),
+        });
+    }
+    post
+}
@@ -0,0 +1,709 @@
+mod harness;
+
+use harness::*;
+use serde_json::json;
+use std::process::Command;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn toot_with_image_is_posted_to_bluesky() {
+    let h = Harness::start().await;
+    let image_url = format!("{}/media/cat.png", h.mastodon.uri());
+    let toot = with_image(
+        toot(
+            "1",
+            r#"<p>Hallo <a href="https://example.com/">https://example.com/</a> <a href="https://mastodon.example/tags/rust" class="mention hashtag">#<span>rust</span></a></p>"#,
+            "de",
+        ),
+        &image_url,
+        "A cat on a keyboard",
+    );
+    h.timelines(vec![toot], Vec::new()).await;
+
+    h.run(&[]).await.unwrap();
+
+    assert_eq!(
+        requests(&h.mastodon, "GET", "/media/cat.png").await.len(),
+        1
+    );
+    let uploads = requests(&h.bluesky, "POST", UPLOAD_BLOB).await;
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(uploads[0].body, PNG);
+
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["repo"], DID);
+    assert_eq!(records[0]["collection"], "app.bsky.feed.post");
+    let record = &records[0]["record"];
+    assert_eq!(record["text"], "Hallo example.com/ #rust");
+    assert_eq!(record["langs"], json!(["de"]));
+    assert_eq!(
+        record["facets"],
+        json!([
+            {
+                "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/" }],
+                "index": { "byteStart": 6, "byteEnd": 18 },
+            },
+            {
+                "features": [{ "$type": "app.bsky.richtext.facet#tag", "tag": "rust" }],
+                "index": { "byteStart": 19, "byteEnd": 24 },
+            },
+        ])
+    );
+    assert_eq!(record["embed"]["$type"], "app.bsky.embed.images");
+    let image = &record["embed"]["images"][0];
+    assert_eq!(image["alt"], "A cat on a keyboard");
+    assert_eq!(image["image"]["ref"]["$link"], BLOB_CID);
+    assert_eq!(image["aspectRatio"], json!({ "width": 1, "height": 1 }));
+}
+
+#[tokio::test]
+async fn toot_with_link_gets_a_link_preview() {
+    let h = Harness::start().await;
+    let article = format!("{}/article", h.mastodon.uri());
+    h.timelines(
+        vec![toot(
+            "2",
+            &format!(r#"<p>Read this <a href="{article}">{article}</a></p>"#),
+            "en",
+        )],
+        Vec::new(),
+    )
+    .await;
+
+    h.run(&[]).await.unwrap();
+
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    let embed = &records[0]["record"]["embed"];
+    assert_eq!(embed["$type"], "app.bsky.embed.external");
+    assert_eq!(embed["external"]["uri"], article);
+    assert_eq!(embed["external"]["title"], "An article");
+    assert_eq!(embed["external"]["description"], "About things");
+    assert_eq!(embed["external"]["thumb"]["ref"]["$link"], BLOB_CID);
+    assert_eq!(
+        requests(&h.mastodon, "GET", "/media/preview.png")
+            .await
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn bluesky_post_with_image_is_posted_to_mastodon() {
+    let h = Harness::start().await;
+    let image_url = format!("{}/img/dog.png", h.bluesky.uri());
+    h.timelines(
+        Vec::new(),
+        vec![bsky_post(
+            "3kdog",
+            "Hello from Bluesky",
+            &[(&image_url, "A dog")],
+        )],
+    )
+    .await;
+
+    h.run(&[]).await.unwrap();
+
+    let uploads = requests(&h.mastodon, "POST", UPLOAD_MEDIA).await;
+    assert_eq!(uploads.len(), 1);
+    let multipart = String::from_utf8_lossy(&uploads[0].body);
+    assert!(multipart.contains("name=\"description\"\r\n\r\nA dog\r\n"));
+
+    let statuses = request_bodies(&h.mastodon, "POST", POST_STATUS).await;
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0]["status"], "Hello from Bluesky");
+    assert_eq!(statuses[0]["media_ids"], json!(["media-1"]));
+    assert_eq!(statuses[0]["language"], "en");
+    assert_eq!(statuses[0]["visibility"], "public");
+    assert!(requests(&h.bluesky, "POST", CREATE_RECORD).await.is_empty());
+}
+
+#[tokio::test]
+async fn bluesky_video_that_cannot_be_converted_links_to_the_post() {
+    let h = Harness::start().await;
+    Mock::given(method("GET"))
+        .and(path("/watch/clip/playlist.m3u8"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000000\n360p/video.m3u8\n"),
+        )
+        .mount(&h.bluesky)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/watch/clip/360p/video.m3u8"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("#EXTM3U\n#EXTINF:2.0,\nvideo0.ts\n#EXT-X-ENDLIST\n"),
+        )
+        .mount(&h.bluesky)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/watch/clip/360p/video0.ts"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(b"not a video".to_vec(), "video/mp2t"),
+        )
+        .mount(&h.bluesky)
+        .await;
+    let mut post = bsky_post("3kclip", "A long video.", &[]);
+    post["post"]["embed"] = json!({
+        "$type": "app.bsky.embed.video#view",
+        "cid": VIDEO_CID,
+        "playlist": format!("{}/watch/clip/playlist.m3u8", h.bluesky.uri()),
+    });
+    h.timelines(Vec::new(), vec![post]).await;
+
+    h.run(&[]).await.unwrap();
+
+    assert!(requests(&h.mastodon, "POST", UPLOAD_MEDIA).await.is_empty());
+    let statuses = request_bodies(&h.mastodon, "POST", POST_STATUS).await;
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(
+        statuses[0]["status"],
+        format!("A long video.\n\nFull video: https://bsky.app/profile/{DID}/post/3kclip")
+    );
+    assert_eq!(statuses[0]["media_ids"], json!([]));
+}
+
+#[tokio::test]
+async fn rate_limited_post_is_retried() {
+    let h = Harness::start().await;
+    h.timelines(vec![toot("4", "<p>Rate limits</p>", "en")], Vec::new())
+        .await;
+    Mock::given(method("POST"))
+        .and(path(CREATE_RECORD))
+        .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0], records[1]);
+}
+
+#[tokio::test]
+async fn server_error_on_post_queues_the_post() {
+    let h = Harness::start().await;
+    h.timelines(vec![toot("5", "<p>Server down</p>", "en")], Vec::new())
+        .await;
+    Mock::given(method("POST"))
+        .and(path(CREATE_RECORD))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+
+    let error = h.run(&[]).await.unwrap_err();
+    assert_eq!(error.exit_code(), 5);
+    // Creating a post is not idempotent, so server errors are not retried.
+    assert_eq!(requests(&h.bluesky, "POST", CREATE_RECORD).await.len(), 1);
+    let queue = std::fs::read_to_string(h.state_dir().join("post_queue.json")).unwrap();
+    assert!(queue.contains("Server down"));
+}
+
+#[tokio::test]
+async fn server_error_on_timeline_fetch_is_retried() {
+    let h = Harness::start().await;
+    h.timelines(Vec::new(), Vec::new()).await;
+    Mock::given(method("GET"))
+        .and(path(GET_AUTHOR_FEED))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    assert_eq!(requests(&h.bluesky, "GET", GET_AUTHOR_FEED).await.len(), 2);
+}
+
+/// A one second test video, None if ffmpeg is not available.
+fn test_video() -> Option<Vec<u8>> {
+    let dir = tempfile::tempdir().unwrap();
+    let video = dir.path().join("clip.mp4");
+    let generated = Command::new("ffmpeg")
+        .args(["-v", "error", "-f", "lavfi", "-i"])
+        .arg("testsrc=duration=1:size=64x64:rate=10")
+        .args(["-pix_fmt", "yuv420p"])
+        .arg(&video)
+        .status();
+    if !matches!(generated, Ok(status) if status.success()) {
+        eprintln!("Skipping video test, ffmpeg is not available");
+        return None;
+    }
+    Some(std::fs::read(&video).unwrap())
+}
+
+/// A toot with the video at /media/clip.mp4 of the Mastodon server.
+async fn toot_with_video(h: &Harness, id: &str, video: Vec<u8>) -> serde_json::Value {
+    Mock::given(method("GET"))
+        .and(path("/media/clip.mp4"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(video, "video/mp4"))
+        .mount(&h.mastodon)
+        .await;
+    let mut toot = with_image(
+        toot(id, "<p>Watch this</p>", "en"),
+        &format!("{}/media/clip.mp4", h.mastodon.uri()),
+        "A test pattern",
+    );
+    toot["media_attachments"][0]["type"] = json!("video");
+    toot
+}
+
+#[tokio::test]
+async fn toot_with_video_is_uploaded_to_the_video_service() {
+    let Some(video) = test_video() else {
+        return;
+    };
+    let h = Harness::start().await;
+    let toot = toot_with_video(&h, "6", video).await;
+    h.timelines(vec![toot], Vec::new()).await;
+
+    h.run(&[]).await.unwrap();
+
+    assert_eq!(requests(&h.video, "GET", GET_UPLOAD_LIMITS).await.len(), 1);
+
+    let uploads = requests(&h.video, "POST", UPLOAD_VIDEO).await;
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(
+        uploads[0].headers.get("authorization").unwrap(),
+        "Bearer service-token"
+    );
+    let query = uploads[0].url.query().unwrap();
+    assert!(query.contains("did=did%3Aplc%3Aalice"));
+    assert!(query.contains("name=clip.mp4"));
+    assert!(!requests(&h.video, "GET", GET_JOB_STATUS).await.is_empty());
+
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    let embed = &records[0]["record"]["embed"];
+    assert_eq!(embed["$type"], "app.bsky.embed.video");
+    assert_eq!(embed["alt"], "A test pattern");
+    assert_eq!(embed["video"]["ref"]["$link"], VIDEO_CID);
+}
+
+#[tokio::test]
+async fn video_over_the_upload_limit_links_to_the_original_post() {
+    let Some(video) = test_video() else {
+        return;
+    };
+    let h = Harness::start().await;
+    Mock::given(method("GET"))
+        .and(path(GET_UPLOAD_LIMITS))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "canUpload": false,
+            "message": "Daily upload limit reached",
+        })))
+        .with_priority(1)
+        .mount(&h.video)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/@alice/8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"<html><head><meta property="og:title" content="A video"></head></html>"#,
+            "text/html",
+        ))
+        .mount(&h.mastodon)
+        .await;
+    let mut toot = toot_with_video(&h, "8", video).await;
+    toot["url"] = json!(format!("{}/@alice/8", h.mastodon.uri()));
+    h.timelines(vec![toot], Vec::new()).await;
+
+    h.run(&[]).await.unwrap();
+
+    assert!(requests(&h.video, "POST", UPLOAD_VIDEO).await.is_empty());
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    let embed = &records[0]["record"]["embed"];
+    assert_eq!(embed["$type"], "app.bsky.embed.external");
+    assert_eq!(embed["external"]["title"], "A video");
+}
+
+#[tokio::test]
+async fn toot_with_audio_links_to_the_original_post() {
+    let h = Harness::start().await;
+    h.append_config("\n[media]\naudio = \"link\"\n");
+    Mock::given(method("GET"))
+        .and(path("/media/voice.mp3"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(b"ID3".to_vec(), "audio/mpeg"))
+        .mount(&h.mastodon)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/@alice/7"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"<html><head><meta property="og:title" content="Voice note"></head></html>"#,
+            "text/html",
+        ))
+        .mount(&h.mastodon)
+        .await;
+    let mut toot = with_image(
+        toot("7", "<p>Listen to this</p>", "en"),
+        &format!("{}/media/voice.mp3", h.mastodon.uri()),
+        "A voice note",
+    );
+    toot["media_attachments"][0]["type"] = json!("audio");
+    toot["url"] = json!(format!("{}/@alice/7", h.mastodon.uri()));
+    h.timelines(vec![toot], Vec::new()).await;
+
+    h.run(&[]).await.unwrap();
+
+    assert!(requests(&h.video, "POST", UPLOAD_VIDEO).await.is_empty());
+    let records = request_bodies(&h.bluesky, "POST", CREATE_RECORD).await;
+    let embed = &records[0]["record"]["embed"];
+    assert_eq!(embed["$type"], "app.bsky.embed.external");
+    assert_eq!(embed["external"]["title"], "Voice note");
+    assert_eq!(
+        embed["external"]["uri"],
+        format!("{}/@alice/7", h.mastodon.uri())
+    );
+}
+
+#[tokio::test]
+async fn revoked_mastodon_token_is_refreshed() {
+    let h = Harness::start().await;
+    h.timelines(Vec::new(), Vec::new()).await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/accounts/verify_credentials"))
+        .and(header("authorization", "Bearer access-token"))
+        .respond_with(ResponseTemplate::new(401))
+        .with_priority(1)
+        .mount(&h.mastodon)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "new-access-token",
+            "token_type": "Bearer",
+            "refresh_token": "new-refresh-token",
+        })))
+        .mount(&h.mastodon)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    let token_requests = requests(&h.mastodon, "POST", "/oauth/token").await;
+    assert_eq!(token_requests.len(), 1);
+    assert_eq!(
+        String::from_utf8_lossy(&token_requests[0].body),
+        "grant_type=refresh_token&refresh_token=refresh-token&client_id=client-id&client_secret=client-secret"
+    );
+    let verifications = requests(&h.mastodon, "GET", "/api/v1/accounts/verify_credentials").await;
+    assert_eq!(
+        verifications[1].headers.get("authorization").unwrap(),
+        "Bearer new-access-token"
+    );
+    let config = std::fs::read_to_string(h.config_file()).unwrap();
+    assert!(config.contains(r#"access_token = "new-access-token""#));
+    assert!(config.contains(r#"refresh_token = "new-refresh-token""#));
+}
+
+#[tokio::test]
+async fn failed_token_refresh_asks_to_log_in_again() {
+    let h = Harness::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/accounts/verify_credentials"))
+        .respond_with(ResponseTemplate::new(401))
+        .with_priority(1)
+        .mount(&h.mastodon)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&h.mastodon)
+        .await;
+
+    let error = h.run(&[]).await.unwrap_err();
+
+    assert_eq!(error.exit_code(), 4);
+    let cause = std::error::Error::source(&error).unwrap().to_string();
+    assert!(cause.contains("login mastodon"), "{cause}");
+    let config = std::fs::read_to_string(h.config_file()).unwrap();
+    assert!(config.contains(r#"access_token = "access-token""#));
+}
+
+#[tokio::test]
+async fn expired_bluesky_oauth_session_is_refreshed() {
+    let h = Harness::start().await;
+    h.use_bluesky_oauth("old-token", "2020-01-01T00:00:00Z");
+    h.timelines(vec![toot("7", "<p>With OAuth</p>", "en")], Vec::new())
+        .await;
+    // The authorization server and the PDS both ask for a DPoP nonce first.
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .respond_with(
+            ResponseTemplate::new(400)
+                .insert_header("dpop-nonce", "server-nonce")
+                .set_body_json(json!({ "error": "use_dpop_nonce" })),
+        )
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "new-token",
+            "token_type": "DPoP",
+            "refresh_token": "rotated-refresh-token",
+            "expires_in": 3600,
+            "scope": "atproto transition:generic",
+            "sub": DID,
+        })))
+        .mount(&h.bluesky)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/xrpc/com.atproto.server.getSession"))
+        .respond_with(
+            ResponseTemplate::new(401)
+                .insert_header("www-authenticate", r#"DPoP error="use_dpop_nonce""#)
+                .insert_header("dpop-nonce", "pds-nonce"),
+        )
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    assert!(
+        requests(&h.bluesky, "POST", "/xrpc/com.atproto.server.createSession")
+            .await
+            .is_empty()
+    );
+    let token_requests = requests(&h.bluesky, "POST", "/oauth/token").await;
+    assert_eq!(token_requests.len(), 2);
+    assert!(
+        String::from_utf8_lossy(&token_requests[1].body)
+            .starts_with("grant_type=refresh_token&refresh_token=refresh-token&")
+    );
+    let proof = token_requests[1].headers.get("dpop").unwrap();
+    assert_eq!(jwt_claims(proof.to_str().unwrap())["nonce"], "server-nonce");
+
+    let sessions = requests(&h.bluesky, "GET", "/xrpc/com.atproto.server.getSession").await;
+    // Resuming the session and the session check of the sync.
+    assert_eq!(sessions.len(), 3);
+    let records = requests(&h.bluesky, "POST", CREATE_RECORD).await;
+    assert_eq!(records.len(), 1);
+    for request in [&sessions[1], &records[0]] {
+        assert_eq!(
+            request.headers.get("authorization").unwrap(),
+            "DPoP new-token"
+        );
+        let claims = jwt_claims(request.headers.get("dpop").unwrap().to_str().unwrap());
+        assert_eq!(claims["nonce"], "pds-nonce");
+        assert_eq!(claims["htm"], request.method.as_str());
+        assert_eq!(
+            claims["htu"],
+            format!("{}{}", h.bluesky.uri(), request.url.path())
+        );
+    }
+
+    let session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(h.oauth_session_file()).unwrap()).unwrap();
+    assert_eq!(session["access_token"], "new-token");
+    assert_eq!(session["refresh_token"], "rotated-refresh-token");
+}
+
+#[tokio::test]
+async fn rate_limited_oauth_request_gets_a_new_proof() {
+    let h = Harness::start().await;
+    h.use_bluesky_oauth("access-token", "2099-01-01T00:00:00Z");
+    h.timelines(vec![toot("9", "<p>Fresh proofs</p>", "en")], Vec::new())
+        .await;
+    Mock::given(method("POST"))
+        .and(path(CREATE_RECORD))
+        .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&h.bluesky)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    let records = requests(&h.bluesky, "POST", CREATE_RECORD).await;
+    assert_eq!(records.len(), 2);
+    let proof_id = |request: &wiremock::Request| {
+        jwt_claims(request.headers.get("dpop").unwrap().to_str().unwrap())["jti"].clone()
+    };
+    assert_ne!(proof_id(&records[0]), proof_id(&records[1]));
+    assert!(
+        requests(&h.bluesky, "POST", "/oauth/token")
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn refreshed_token_is_written_to_the_secret_file() {
+    let h = Harness::start().await;
+    h.timelines(Vec::new(), Vec::new()).await;
+    let token_file = h.state_dir().join("access-token");
+    std::fs::write(&token_file, "access-token\n").unwrap();
+    let config = std::fs::read_to_string(h.config_file()).unwrap().replace(
+        r#"access_token = "access-token""#,
+        &format!(r#"access_token = "file:{}""#, token_file.display()),
+    );
+    std::fs::write(h.config_file(), config).unwrap();
+    Mock::given(method("GET"))
+        .and(path("/api/v1/accounts/verify_credentials"))
+        .and(header("authorization", "Bearer access-token"))
+        .respond_with(ResponseTemplate::new(401))
+        .with_priority(1)
+        .mount(&h.mastodon)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "new-access-token",
+            "token_type": "Bearer",
+        })))
+        .mount(&h.mastodon)
+        .await;
+
+    h.run(&[]).await.unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&token_file).unwrap(),
+        "new-access-token"
+    );
+    // The config still references the file and contains no token.
+    let saved = std::fs::read_to_string(h.config_file()).unwrap();
+    assert!(saved.contains(&format!(
+        r#"access_token = "file:{}""#,
+        token_file.display()
+    )));
+    assert!(!saved.contains("new-access-token"));
+}
+
+#[tokio::test]
+async fn missing_config_asks_to_run_init() {
+    let h = Harness::start().await;
+    std::fs::remove_file(h.config_file()).unwrap();
+
+    let error = h.run(&[]).await.unwrap_err();
+
+    assert_eq!(error.exit_code(), 7);
+    let cause = std::error::Error::source(&error).unwrap().to_string();
+    assert!(cause.contains("mastodon-bluesky-sync init"), "{cause}");
+    assert!(
+        requests(&h.mastodon, "GET", "/api/v1/accounts/verify_credentials")
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn init_creates_the_config_without_prompts() {
+    let h = Harness::start().await;
+    let (mastodon_url, bluesky_url) = (h.mastodon.uri(), h.bluesky.uri());
+
+    // The harness config exists already.
+    let error = h.run(&["init"]).await.unwrap_err();
+    assert_eq!(error.exit_code(), 7);
+
+    h.run(&[
+        "--secret-store",
+        "file",
+        "init",
+        "--force",
+        "--mastodon-url",
+        &mastodon_url,
+        "--mastodon-access-token",
+        "settings-token",
+        "--bluesky-service-url",
+        &bluesky_url,
+        "--bluesky-email",
+        "bob@example.com",
+        "--bluesky-app-password",
+        "bob-password",
+    ])
+    .await
+    .unwrap();
+
+    let verify = requests(&h.mastodon, "GET", "/api/v1/accounts/verify_credentials").await;
+    assert_eq!(
+        verify[0].headers.get("authorization").unwrap(),
+        "Bearer settings-token"
+    );
+    let sessions =
+        request_bodies(&h.bluesky, "POST", "/xrpc/com.atproto.server.createSession").await;
+    assert_eq!(sessions[0]["identifier"], "bob@example.com");
+    assert_eq!(sessions[0]["password"], "bob-password");
+
+    let saved = std::fs::read_to_string(h.config_file()).unwrap();
+    assert!(
+        saved.contains(&format!(r#"base_url = "{mastodon_url}""#)),
+        "{saved}"
+    );
+    assert!(saved.contains(r#"email = "bob@example.com""#));
+    assert!(!saved.contains("settings-token"));
+    assert!(!saved.contains("bob-password"));
+    let secrets = h.state_dir().join("secrets");
+    assert_eq!(
+        std::fs::read_to_string(secrets.join("mastodon-access-token")).unwrap(),
+        "settings-token"
+    );
+    assert_eq!(
+        std::fs::read_to_string(secrets.join("bluesky-app-password")).unwrap(),
+        "bob-password"
+    );
+}
+
+#[tokio::test]
+async fn check_verifies_credentials_without_syncing() {
+    let h = Harness::start().await;
+
+    h.run(&["check"]).await.unwrap();
+
+    assert_eq!(
+        requests(&h.mastodon, "GET", "/api/v1/accounts/verify_credentials")
+            .await
+            .len(),
+        1
+    );
+    assert_eq!(
+        requests(&h.bluesky, "GET", "/xrpc/com.atproto.server.getSession")
+            .await
+            .len(),
+        1
+    );
+    assert!(
+        requests(&h.bluesky, "GET", GET_AUTHOR_FEED)
+            .await
+            .is_empty()
+    );
+
+    // Typos in keys are reported instead of being ignored.
+    let config = std::fs::read_to_string(h.config_file())
+        .unwrap()
+        .replace("[bluesky]", "[bluesky]\ndelete_old_post = true");
+    std::fs::write(h.config_file(), config).unwrap();
+    let error = h.run(&["check"]).await.unwrap_err();
+    assert_eq!(error.exit_code(), 7);
+    let cause = std::error::Error::source(&error).unwrap();
+    let cause = cause.source().unwrap().to_string();
+    assert!(
+        cause.contains("Did you mean `delete_old_posts`?"),
+        "{cause}"
+    );
+}
+
+#[tokio::test]
+async fn overlapping_run_is_skipped() {
+    let h = Harness::start().await;
+    h.timelines(vec![toot("1", "Hallo", "de")], Vec::new())
+        .await;
+    let lock_file =
+        std::fs::File::create(h.state_dir().join("mastodon-bluesky-sync.lock")).unwrap();
+    lock_file.lock().unwrap();
+
+    h.run(&[]).await.unwrap();
+    assert!(requests(&h.bluesky, "POST", CREATE_RECORD).await.is_empty());
+
+    lock_file.unlock().unwrap();
+    h.run(&[]).await.unwrap();
+    assert_eq!(requests(&h.bluesky, "POST", CREATE_RECORD).await.len(), 1);
+}